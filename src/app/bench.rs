@@ -1,83 +1,364 @@
-use std::fs;
-use std::process::exit;
-
-use crate::utils::index_zip::*;
-
-macro_rules! call_index_statistic {
-    ($func:ident, ($($args:expr),*), $update_func:ident, |$time:ident, $count:ident, $size:ident| $code:block) => {
-		{
-			let count = std::sync::Arc::new(std::sync::Mutex::new(std::pin::Pin::new(Box::new(0u64))));
-			let size = std::sync::Arc::new(std::sync::Mutex::new(std::pin::Pin::new(Box::new(0u64))));
-
-			let count_capture = std::sync::Arc::downgrade(&count);
-			let size_capture = std::sync::Arc::downgrade(&size);
-
-			let func_ret = $func($($args),*, crate::utils::index_callback::ZipCallback::new(move |x, _, _| {
-				if let Some(count) = count_capture.upgrade() {
-					**count.lock().unwrap() += 1;
-				};
-				if let Some(size) = size_capture.upgrade() {
-					**size.lock().unwrap() += x.size();
-				};
-			}));
-
-			let $update_func = |from: std::sync::Arc<std::sync::Mutex<std::pin::Pin<Box<u64>>>>| -> u64 {
-				**from.lock().unwrap()
-			};
-
-			{
-				let $count = count;
-				let $size = size;
-				let $time = func_ret;
-				$code
-			}
-		}
-    };
-}
-
-pub async fn read_dir(dir: &str, core_num: usize, depth: isize) {
-	if let Err(file_err) = fs::read_dir(dir) {
-		println!("[ERROR] Directory invalid: {}", file_err);
-		exit(1);
-	}
-
-	call_index_statistic!(index_zip_dir, (dir, core_num, depth), update, |time_future, count, size| {
-		match time_future.await {
-			Ok(time) => {
-				let count = update(count);
-				let size = update(size);
-				println!("[INFO] DIR iteration done.\n Time: {}ms\n File count: {}\n File size: {}", time, count, size);
-			},
-			Err(err) => { println!("[ERROR] {}", err); exit(1); }
-		}
-	});
-}
-
-pub async fn read_file(file: &str, core_num: usize) {
-	if let Err(file_err) = fs::read(file) {
-		println!("[ERROR] File invalid: {}", file_err);
-		exit(1);
-	}
-
-	call_index_statistic!(index_zip_single_thread, (file), update, |time_result, count, size| {
-		match time_result {
-			Ok(time) => {
-				let count = update(count);
-				let size = update(size);
-				println!("[INFO] ST iteration done.\n Time: {}ms\n File count: {}\n File size: {}", time, count, size);
-			},
-			Err(err) => { println!("[ERROR] {}", err); exit(1); }
-		}
-	});
-
-	call_index_statistic!(index_zip_multi_thread, (file, core_num), update, |time_future, count, size| {
-		match time_future.await {
-			Ok(time) => {
-				let count = update(count);
-				let size = update(size);
-				println!("[INFO] MT ({} threads) iteration done.\n Time: {}ms\n File count: {}\n File size: {}", core_num, time, count, size);
-			},
-			Err(err) => { println!("[ERROR] {}", err); exit(1); }
-		}
-	});
-}
\ No newline at end of file
+use std::fs;
+
+use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
+
+use super::serve;
+use crate::utils::index_zip::*;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+	Text,
+	Json,
+	Csv
+}
+
+impl OutputFormat {
+	pub fn parse(format: &str) -> Option<Self> {
+		match format {
+			"text" => Some(Self::Text),
+			"json" => Some(Self::Json),
+			"csv" => Some(Self::Csv),
+			_ => None
+		}
+	}
+}
+
+pub struct BenchOptions {
+	pub core_num: usize,
+	pub format: OutputFormat,
+	pub warmup: usize,
+	pub iterations: usize
+}
+
+#[derive(Serialize)]
+struct ScenarioResult {
+	threads: usize,
+	iterations: usize,
+	// Mean of all iterations
+	time_ms: f64,
+	min_ms: u128,
+	max_ms: u128,
+	stddev_ms: f64,
+	file_count: u64,
+	file_size: u64,
+	// Decompressed bytes per second
+	throughput: f64,
+	entries_per_sec: f64
+}
+
+// Collects the result of every scenario, printing them right away in text mode
+// or all together at the end in the machine-readable modes
+struct BenchReport {
+	format: OutputFormat,
+	// Print a single table at the end instead of one block per scenario
+	table: bool,
+	scenarios: Vec<(String, ScenarioResult)>
+}
+
+impl BenchReport {
+	fn new(format: OutputFormat) -> Self {
+		Self { format, table: false, scenarios: Vec::new() }
+	}
+
+	fn add(&mut self, key: &str, label: &str, threads: usize, samples: &[u128], file_count: u64, file_size: u64) {
+		let iterations = samples.len();
+		let time_ms = samples.iter().sum::<u128>() as f64 / iterations as f64;
+		let min_ms = samples.iter().copied().min().unwrap_or(0);
+		let max_ms = samples.iter().copied().max().unwrap_or(0);
+		let stddev_ms = (samples.iter().map(|x| (*x as f64 - time_ms).powi(2)).sum::<f64>() / iterations as f64).sqrt();
+
+		let throughput = file_size as f64 * 1000.0 / time_ms.max(1.0);
+		let entries_per_sec = file_count as f64 * 1000.0 / time_ms.max(1.0);
+
+		if self.format == OutputFormat::Text && !self.table {
+			if iterations == 1 {
+				println!("[INFO] {} iteration done.\n Time: {}ms\n File count: {}\n File size: {}", label, min_ms, file_count, file_size);
+			}
+			else {
+				println!("[INFO] {} iteration done ({} runs).\n Time: {:.2}ms (min {}ms, max {}ms, stddev {:.2}ms)\n File count: {}\n File size: {}", label, iterations, time_ms, min_ms, max_ms, stddev_ms, file_count, file_size);
+			}
+			println!(" Throughput: {:.2} MB/s, {:.2} entries/s", throughput / 1_000_000.0, entries_per_sec);
+		}
+		self.scenarios.push((key.to_string(), ScenarioResult { threads, iterations, time_ms, min_ms, max_ms, stddev_ms, file_count, file_size, throughput, entries_per_sec }));
+	}
+
+	fn finish(&self) {
+		match self.format {
+			OutputFormat::Text if self.table => {
+				let baseline = self.scenarios.first().map(|(_, result)| result.time_ms.max(1.0)).unwrap_or(1.0);
+				println!("[INFO] Thread sweep done.\n {:>8} {:>12} {:>8}", "Threads", "Time (ms)", "Speedup");
+				for (_, result) in self.scenarios.iter() {
+					println!(" {:>8} {:>12.2} {:>7.2}x", result.threads, result.time_ms, baseline / result.time_ms.max(1.0));
+				}
+				if let Some((_, best)) = self.scenarios.iter().min_by(|(_, a), (_, b)| a.time_ms.total_cmp(&b.time_ms)) {
+					println!("[INFO] Fastest with {} threads.", best.threads);
+				}
+			},
+			OutputFormat::Text => {
+				let st = self.scenarios.iter().find(|(key, _)| key == "st");
+				let mt = self.scenarios.iter().find(|(key, _)| key == "mt");
+				if let (Some((_, st)), Some((_, mt))) = (st, mt) {
+					println!("[INFO] MT speedup over ST: {:.2}x", st.time_ms.max(1.0) / mt.time_ms.max(1.0));
+				}
+			},
+			OutputFormat::Json => {
+				let map: serde_json::Map<String, serde_json::Value> = self.scenarios.iter()
+					.map(|(key, result)| (key.clone(), serde_json::to_value(result).unwrap()))
+					.collect();
+				println!("{}", serde_json::Value::Object(map));
+			},
+			OutputFormat::Csv => {
+				println!("scenario,threads,iterations,time_ms,min_ms,max_ms,stddev_ms,file_count,file_size,throughput,entries_per_sec");
+				for (key, result) in self.scenarios.iter() {
+					println!("{},{},{},{:.2},{},{},{:.2},{},{},{:.2},{:.2}", key, result.threads, result.iterations, result.time_ms, result.min_ms, result.max_ms, result.stddev_ms, result.file_count, result.file_size, result.throughput, result.entries_per_sec);
+				}
+			}
+		}
+	}
+}
+
+#[derive(Serialize)]
+struct CacheReadResult {
+	entries: usize,
+	cache_size: u64,
+	// Measured passes over every entry once the cache is filled
+	warm_passes: usize,
+	cold_mean_us: f64,
+	cold_p95_us: u128,
+	warm_mean_us: f64,
+	warm_p95_us: u128,
+	// Share of the warm reads answered by the entry cache
+	hit_ratio: f64
+}
+
+#[derive(Serialize)]
+struct ListResult {
+	entries: usize,
+	directories: usize,
+	// Children found by range lookups, as listings do
+	range_mean_ns: f64,
+	range_p95_ns: u128,
+	// Children found by scanning the whole index, for comparison
+	scan_mean_ns: f64,
+	scan_p95_ns: u128
+}
+
+fn mean_and_p95(samples: &mut [u128]) -> (f64, u128) {
+	samples.sort_unstable();
+	let mean = samples.iter().sum::<u128>() as f64 / samples.len().max(1) as f64;
+	let p95 = samples.get(samples.len().saturating_sub(1) * 95 / 100).copied().unwrap_or(0);
+	(mean, p95)
+}
+
+macro_rules! call_index_statistic {
+    ($func:ident, ($($args:expr),*) $(.$await:tt)?, $runs:expr, |$samples:ident, $count:ident, $size:ident| $code:block) => {
+		{
+			let (warmup, iterations, show_progress) = $runs;
+			let mut samples = Vec::<u128>::with_capacity(iterations);
+			let mut last_count = 0u64;
+			let mut last_size = 0u64;
+
+			for run in 0..(warmup + iterations) {
+				let count = std::sync::Arc::new(std::sync::Mutex::new(std::pin::Pin::new(Box::new(0u64))));
+				let size = std::sync::Arc::new(std::sync::Mutex::new(std::pin::Pin::new(Box::new(0u64))));
+
+				let count_capture = std::sync::Arc::downgrade(&count);
+				let size_capture = std::sync::Arc::downgrade(&size);
+
+				let callback = crate::utils::index_callback::ZipCallback::new(move |x, _, _| {
+					if let Some(count) = count_capture.upgrade() {
+						**count.lock().unwrap() += 1;
+					};
+					if let Some(size) = size_capture.upgrade() {
+						**size.lock().unwrap() += x.size();
+					};
+					Ok(())
+				});
+				if show_progress {
+					callback.lock().unwrap().set_progress(crate::utils::progress::print_progress_bar);
+				}
+
+				let func_ret = $func($($args),*, callback) $(.$await)?;
+
+				match func_ret {
+					Ok(time) => {
+						// Warmup runs are not counted
+						if run >= warmup { samples.push(time); }
+						last_count = **count.lock().unwrap();
+						last_size = **size.lock().unwrap();
+					},
+					Err(err) => return Err(err)
+				}
+			}
+
+			{
+				let $samples = samples;
+				let $count = last_count;
+				let $size = last_size;
+				$code
+			}
+		}
+    };
+}
+
+pub async fn read_dir(dir: &str, depth: isize, options: &BenchOptions) -> Result<()> {
+	fs::read_dir(dir).map_err(|err| anyhow!("Directory invalid: {}", err))?;
+
+	let BenchOptions { core_num, format, warmup, iterations } = *options;
+	let mut report = BenchReport::new(format);
+
+	call_index_statistic!(index_zip_dir_single_thread, (dir, depth), (warmup, iterations, format == OutputFormat::Text), |samples, count, size| {
+		report.add("st", "DIR ST", 1, &samples, count, size);
+	});
+
+	call_index_statistic!(index_zip_dir, (dir, core_num, depth).await, (warmup, iterations, format == OutputFormat::Text), |samples, count, size| {
+		report.add("mt", &format!("DIR MT ({} threads)", core_num), core_num, &samples, count, size);
+	});
+
+	report.finish();
+	Ok(())
+}
+
+pub async fn read_file(file: &str, options: &BenchOptions) -> Result<()> {
+	fs::read(file).map_err(|err| anyhow!("File invalid: {}", err))?;
+
+	let BenchOptions { core_num, format, warmup, iterations } = *options;
+	let mut report = BenchReport::new(format);
+
+	call_index_statistic!(index_zip_single_thread, (file), (warmup, iterations, format == OutputFormat::Text), |samples, count, size| {
+		report.add("st", "ST", 1, &samples, count, size);
+	});
+
+	call_index_statistic!(index_zip_multi_thread, (file, core_num).await, (warmup, iterations, format == OutputFormat::Text), |samples, count, size| {
+		report.add("mt", &format!("MT ({} threads)", core_num), core_num, &samples, count, size);
+	});
+
+	#[cfg(feature = "rayon")]
+	call_index_statistic!(index_zip_rayon, (file, core_num), (warmup, iterations, format == OutputFormat::Text), |samples, count, size| {
+		report.add("rayon", &format!("Rayon ({} threads)", core_num), core_num, &samples, count, size);
+	});
+
+	report.finish();
+	Ok(())
+}
+
+pub async fn sweep_file(file: &str, max_threads: usize, options: &BenchOptions) -> Result<()> {
+	fs::read(file).map_err(|err| anyhow!("File invalid: {}", err))?;
+
+	let BenchOptions { format, warmup, iterations, .. } = *options;
+	let mut report = BenchReport::new(format);
+	report.table = true;
+
+	// 1, 2, 4, ... and finally max_threads itself if it is not a power of two
+	let mut thread_counts = vec![];
+	let mut threads = 1;
+	while threads < max_threads {
+		thread_counts.push(threads);
+		threads *= 2;
+	}
+	thread_counts.push(max_threads);
+
+	for core_num in thread_counts {
+		call_index_statistic!(index_zip_multi_thread, (file, core_num).await, (warmup, iterations, format == OutputFormat::Text), |samples, count, size| {
+			report.add(&format!("mt-{}", core_num), &format!("MT ({} threads)", core_num), core_num, &samples, count, size);
+		});
+	}
+
+	report.finish();
+	Ok(())
+}
+
+// Reads every entry of the zip the way serve does: once with an empty entry cache, then again after
+// filling the cache as --preload would with cache_size. Warmup passes only apply to the warm reads.
+pub async fn serve_read(file: &str, cache_size: u64, options: &BenchOptions) -> Result<()> {
+	fs::metadata(file).map_err(|err| anyhow!("File invalid: {}", err))?;
+
+	let BenchOptions { format, warmup, iterations, .. } = *options;
+	let entries = serve::load_bench_archive(file, cache_size).await?;
+	if entries.is_empty() { bail!("{} has no file entries to read", file); }
+
+	let mut cold = Vec::with_capacity(entries.len());
+	for (zip_path, zip_index) in &entries {
+		cold.push(serve::bench_read(zip_path, *zip_index).await?.0.as_micros());
+	}
+
+	serve::preload_bench_archive().await?;
+	let mut warm = Vec::with_capacity(entries.len() * iterations);
+	let mut hits = 0usize;
+	for pass in 0..(warmup + iterations) {
+		for (zip_path, zip_index) in &entries {
+			let (time, cached) = serve::bench_read(zip_path, *zip_index).await?;
+			if pass < warmup { continue; }
+			warm.push(time.as_micros());
+			if cached { hits += 1; }
+		}
+	}
+
+	let (cold_mean_us, cold_p95_us) = mean_and_p95(&mut cold);
+	let (warm_mean_us, warm_p95_us) = mean_and_p95(&mut warm);
+	let result = CacheReadResult {
+		entries: entries.len(), cache_size, warm_passes: iterations,
+		cold_mean_us, cold_p95_us, warm_mean_us, warm_p95_us,
+		hit_ratio: hits as f64 / warm.len() as f64
+	};
+
+	match format {
+		OutputFormat::Text => {
+			println!("[INFO] Serve read done ({} entries, cache size {}).", result.entries, result.cache_size);
+			println!(" Cold: {:.2}us mean, {}us p95\n Warm: {:.2}us mean, {}us p95 ({} passes)", result.cold_mean_us, result.cold_p95_us, result.warm_mean_us, result.warm_p95_us, result.warm_passes);
+			println!(" Cache hit ratio: {:.1}%", result.hit_ratio * 100.0);
+		},
+		OutputFormat::Json => {
+			let mut map = serde_json::Map::new();
+			map.insert(String::from("serve-read"), serde_json::to_value(&result)?);
+			println!("{}", serde_json::Value::Object(map));
+		},
+		OutputFormat::Csv => {
+			println!("scenario,entries,cache_size,warm_passes,cold_mean_us,cold_p95_us,warm_mean_us,warm_p95_us,hit_ratio");
+			println!("serve-read,{},{},{},{:.2},{},{:.2},{},{:.4}", result.entries, result.cache_size, result.warm_passes, result.cold_mean_us, result.cold_p95_us, result.warm_mean_us, result.warm_p95_us, result.hit_ratio);
+		}
+	}
+	Ok(())
+}
+
+// Times gathering the children of every directory of the archive, once per iteration
+pub async fn serve_list(file: &str, options: &BenchOptions) -> Result<()> {
+	fs::metadata(file).map_err(|err| anyhow!("File invalid: {}", err))?;
+
+	let BenchOptions { format, warmup, iterations, .. } = *options;
+	serve::load_bench_archive(file, 0).await?;
+	let (mut range, mut scan) = (vec![], vec![]);
+	let mut entries = 0;
+	let mut directories = 0;
+	for pass in 0..(warmup + iterations) {
+		let (index_size, times) = serve::bench_list().await;
+		(entries, directories) = (index_size, times.len());
+		if pass < warmup { continue; }
+		for (range_ns, scan_ns) in times {
+			range.push(range_ns);
+			scan.push(scan_ns);
+		}
+	}
+
+	let (range_mean_ns, range_p95_ns) = mean_and_p95(&mut range);
+	let (scan_mean_ns, scan_p95_ns) = mean_and_p95(&mut scan);
+	let result = ListResult { entries, directories, range_mean_ns, range_p95_ns, scan_mean_ns, scan_p95_ns };
+
+	match format {
+		OutputFormat::Text => {
+			println!("[INFO] Serve list done ({} entries, {} directories).", result.entries, result.directories);
+			println!(" Range: {:.0}ns mean, {}ns p95\n Scan: {:.0}ns mean, {}ns p95", result.range_mean_ns, result.range_p95_ns, result.scan_mean_ns, result.scan_p95_ns);
+		},
+		OutputFormat::Json => {
+			let mut map = serde_json::Map::new();
+			map.insert(String::from("serve-list"), serde_json::to_value(&result)?);
+			println!("{}", serde_json::Value::Object(map));
+		},
+		OutputFormat::Csv => {
+			println!("scenario,entries,directories,range_mean_ns,range_p95_ns,scan_mean_ns,scan_p95_ns");
+			println!("serve-list,{},{},{:.0},{},{:.0},{}", result.entries, result.directories, result.range_mean_ns, result.range_p95_ns, result.scan_mean_ns, result.scan_p95_ns);
+		}
+	}
+	Ok(())
+}
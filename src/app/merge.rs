@@ -0,0 +1,99 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::{anyhow, bail, Result};
+use tracing::{debug, info, warn};
+use zip::{ZipArchive, ZipWriter, result::ZipError};
+
+use super::split::{clear_target, Manifest};
+use super::verify::collect_manifests;
+
+// Finds the parts written by split, which are named "{stem}-{NNN}.zip", ordered by their number
+fn collect_parts(input: &str) -> Result<(String, BTreeMap<usize, PathBuf>)> {
+	let mut parts = BTreeMap::new();
+	let mut stems = vec![];
+	for entry in fs::read_dir(input).map_err(|err| anyhow!("Cannot open directory {}: {}", input, err))? {
+		let path = entry?.path();
+		if path.extension().and_then(|x| x.to_str()) != Some("zip") { continue; }
+		let Some(file_stem) = path.file_stem().and_then(|x| x.to_str()) else { continue; };
+		let Some((stem, number)) = file_stem.rsplit_once('-') else { continue; };
+		if number.len() < 3 || !number.bytes().all(|x| x.is_ascii_digit()) { continue; }
+		if !stems.iter().any(|x| x == stem) { stems.push(stem.to_string()); }
+		parts.insert(number.parse::<usize>()?, path.clone());
+	}
+	match stems.len() {
+		0 => bail!("No split archives (*-NNN.zip) found under {}", input),
+		1 => Ok((stems.remove(0), parts)),
+		_ => bail!("Split archives of several sources found under {}: {}", input, stems.join(", "))
+	}
+}
+
+// The parts listed by the manifest when there is exactly one, which also covers parts named by a template.
+// Otherwise the parts found by their names, including the gaps between them.
+fn expected_parts(input: &str) -> Result<(String, BTreeMap<usize, PathBuf>)> {
+	if let Ok([manifest_path]) = collect_manifests(input).as_deref() {
+		let manifest = Manifest::read(manifest_path)?;
+		let parts = (0..manifest.parts).map(|x| (x, Path::new(input).join(manifest.part_name(x)))).collect();
+		return Ok((manifest.source, parts));
+	}
+	let (stem, mut parts) = collect_parts(input)?;
+	let last = *parts.keys().next_back().unwrap();
+	for x in 0..=last {
+		parts.entry(x).or_insert_with(|| Path::new(input).join(format!("{}-{:03}.zip", stem, x)));
+	}
+	Ok((stem, parts))
+}
+
+pub async fn merge_archive_files(input: &str, output: &str, quiet: bool, force: bool) -> Result<()> {
+	let (stem, mut parts) = expected_parts(input)?;
+
+	let gaps: Vec<String> = parts.values().filter(|x| !x.is_file()).map(|x| x.display().to_string()).collect();
+	parts.retain(|_, x| x.is_file());
+	if parts.is_empty() { bail!("None of the parts of {} found under {}", stem, input); }
+	if !gaps.is_empty() {
+		warn!("Missing {} part(s): {}", gaps.len(), gaps.join(", "));
+	}
+
+	if !clear_target(output, quiet, force)? { return Ok(()); }
+	if let Some(parent) = Path::new(output).parent().filter(|x| !x.as_os_str().is_empty()) {
+		fs::create_dir_all(parent).map_err(|err| anyhow!("Cannot create directory {}: {}", parent.display(), err))?;
+	}
+
+	let begin = Instant::now();
+	info!("Merging {} part(s) of {}...", parts.len(), stem);
+
+	// entry name -> part it was taken from
+	let mut seen = HashMap::<String, usize>::new();
+	let mut collisions = 0usize;
+	let mut archive_file = ZipWriter::new(BufWriter::new(File::create(output)?));
+	for (number, path) in &parts {
+		debug!("Merging {}...", path.display());
+		let mut part = ZipArchive::new(BufReader::new(File::open(path)?))
+			.map_err(|err| anyhow!("Cannot open {}: {}", path.display(), err))?;
+		for i in 0..part.len() {
+			// Raw copies drop the encryption flag, which would leave the entry unreadable
+			if let Err(ZipError::UnsupportedArchive(ZipError::PASSWORD_REQUIRED)) = part.by_index(i) {
+				bail!("{} holds encrypted entries, which cannot be merged", path.display());
+			}
+			let entry = part.by_index_raw(i)?;
+			// The first part holding a name wins
+			if let Some(first) = seen.get(entry.name()) {
+				warn!("{} is in both part {:03} and part {:03}. Keeping the one from part {:03}.", entry.name(), first, number, first);
+				collisions += 1;
+				continue;
+			}
+			seen.insert(entry.name().to_string(), *number);
+			archive_file.raw_copy_file(entry)?;
+		}
+	}
+	archive_file.finish()?;
+
+	info!("Merge completed! {} entries written. Time: {}ms.", seen.len(), (Instant::now() - begin).as_millis());
+	if collisions > 0 { warn!("Skipped {} colliding entries.", collisions); }
+	if !gaps.is_empty() { warn!("The result is incomplete, as {} part(s) are missing.", gaps.len()); }
+
+	Ok(())
+}
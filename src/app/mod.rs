@@ -1,75 +1,366 @@
-use clap::ArgMatches;
-
-mod bench;
-mod split;
-mod serve;
-
-pub async fn app_bench(arguments: &ArgMatches) {
-	let depth = arguments.get_one::<String>("depth").unwrap().trim().parse::<isize>().unwrap();
-	let core_num = arguments.get_one::<String>("jobs").unwrap().trim().parse::<usize>().unwrap();
-
-	if let Ok(Some(file)) = arguments.try_get_one::<String>("file") {
-		println!("[INFO] Running benchmark on file {} under both ST and MT ({} threads) scenario.", file, core_num);
-		bench::read_file(file, core_num).await;
-	}
-
-	if let Ok(Some(dir)) = arguments.try_get_one::<String>("dir") {
-		println!("[INFO] Running benchmark on directory {} with {} threads.", dir, core_num);
-		bench::read_dir(dir, core_num, depth).await;
-	}
-}
-
-pub async fn app_split(arguments: &ArgMatches) {
-	let input_zip = arguments.get_one::<String>("input").unwrap();
-	let output_zip = arguments.get_one::<String>("output").unwrap();
-	let core_num = arguments.get_one::<String>("jobs").unwrap().trim().parse::<usize>().unwrap();
-	let channel_size = arguments.get_one::<String>("channel_size").unwrap().trim().parse::<usize>().unwrap();
-	let thread_delay = arguments.get_one::<String>("thread_delay").unwrap().trim().parse::<usize>().unwrap();
-	let sort_by = arguments.get_one::<String>("sort_by").unwrap();
-	let quiet = arguments.get_flag("quiet");
-	let verbose = arguments.get_flag("verbose");
-
-	println!("[INFO] Split file {} to {} into {} parts.", input_zip, output_zip, core_num);
-
-	split::split_archive_files(input_zip, output_zip, split::SplitOptions { core_num, channel_size, thread_delay, quiet, verbose, sort_by }).await;
-}
-
-pub async fn app_serve(arguments: &ArgMatches) {
-	let dir = arguments.get_one::<String>("dir").unwrap();
-	let depth = arguments.get_one::<String>("depth").unwrap().trim().parse::<isize>().unwrap();
-	let core_num = arguments.get_one::<String>("jobs").unwrap().trim().parse::<usize>().unwrap();
-	let host = arguments.get_one::<String>("listen").unwrap();
-	let port = arguments.get_one::<String>("port").unwrap().trim().parse::<u16>().unwrap();
-
-	let mut use_ssl = false;
-	let ssl_cert;
-	let ssl_key;
-
-	if let Some(cert_path) = arguments.get_one::<String>("ssl_cert") {
-		use_ssl = true;
-		ssl_cert = Some(String::from(cert_path));
-		ssl_key = Some(String::from(arguments.get_one::<String>("ssl_key").unwrap()));
-	}
-	else {
-		ssl_cert = None;
-		ssl_key = None;
-	}
-
-	let landing = arguments.get_one::<String>("landing").map(|x| x.clone());
-	let land_with_path = arguments.get_flag("land_with_path");
-
-	println!("[INFO] Serving file under {}. Listening http{}://{}:{}.", if dir == "." { "current directory" } else { dir }, if use_ssl { "s" } else { "" }, host, port);
-	// println!("[INFO] Indexing subdirectories with a depth of {} and a thread number of {}.", depth, core_num);
-
-	let index_options = serve::IndexOptions {
-		depth, core_num
-	};
-
-	let serve_options = serve::ServeOptions {
-		host, port, use_ssl, ssl_cert, ssl_key, landing, land_with_path
-	};
-
-	if let Err(err) = serve::launch(dir, &index_options, &serve_options).await {
-		println!("[ERROR] File server error: {}", err);
-	}
-}
+use std::fmt::Debug;
+use std::process::exit;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use clap::ArgMatches;
+use clap::parser::ValueSource;
+use tracing::{error, info, warn};
+use glob::Pattern;
+
+use crate::utils::archive;
+use crate::utils::tls::check_tls_files;
+
+pub mod bench;
+pub mod merge;
+pub mod selftest;
+pub mod split;
+pub mod serve;
+pub mod verify;
+
+// Errors of every command end up here, which is the only place deciding the exit code
+fn exit_on_error(result: Result<()>) {
+	if let Err(err) = result {
+		error!("{}", err);
+		exit(1);
+	}
+}
+
+pub async fn app_bench(arguments: &ArgMatches) {
+	let depth = arguments.get_one::<String>("depth").unwrap().trim().parse::<isize>().unwrap();
+	let core_num = arguments.get_one::<String>("jobs").unwrap().trim().parse::<usize>().unwrap();
+	let format = match bench::OutputFormat::parse(arguments.get_one::<String>("format").unwrap()) {
+		Some(format) => format,
+		None => {
+			error!("Output format must be \"text\" or \"json\" or \"csv\"");
+			exit(1);
+		}
+	};
+	let warmup = arguments.get_one::<String>("warmup").unwrap().trim().parse::<usize>().unwrap();
+	let iterations = arguments.get_one::<String>("iterations").unwrap().trim().parse::<usize>().unwrap().max(1);
+	let options = bench::BenchOptions { core_num, format, warmup, iterations };
+	let read_buffer = arguments.get_one::<String>("read_buffer").unwrap().trim().parse::<usize>().unwrap();
+	if let Err(err) = set_read_buffer(read_buffer) { return exit_on_error(Err(err)); }
+
+	if let Ok(Some(file)) = arguments.try_get_one::<String>("file") {
+		if arguments.get_flag("serve_read") {
+			let cache_size = arguments.get_one::<String>("cache_size").unwrap().trim().parse::<u64>().unwrap();
+			info!("Running serve read benchmark on file {} with a cache size of {}.", file, cache_size);
+			exit_on_error(bench::serve_read(file, cache_size, &options).await);
+			return;
+		}
+		if arguments.get_flag("serve_list") {
+			info!("Running serve listing benchmark on file {}.", file);
+			exit_on_error(bench::serve_list(file, &options).await);
+			return;
+		}
+		if let Some(max_threads) = arguments.get_one::<String>("sweep") {
+			let max_threads = max_threads.trim().parse::<usize>().unwrap().max(1);
+			info!("Running thread sweep on file {} up to {} threads.", file, max_threads);
+			exit_on_error(bench::sweep_file(file, max_threads, &options).await);
+			return;
+		}
+		info!("Running benchmark on file {} under both ST and MT ({} threads) scenario.", file, core_num);
+		exit_on_error(bench::read_file(file, &options).await);
+	}
+
+	if let Ok(Some(dir)) = arguments.try_get_one::<String>("dir") {
+		info!("Running benchmark on directory {} under both ST and MT ({} threads) scenario.", dir, core_num);
+		exit_on_error(bench::read_dir(dir, depth, &options).await);
+	}
+}
+
+pub async fn app_split(arguments: &ArgMatches) {
+	let inputs: Vec<String> = arguments.get_many::<String>("input").unwrap().cloned().collect();
+	let output_zip = arguments.get_one::<String>("output").unwrap();
+	let core_num = arguments.get_one::<String>("jobs").unwrap().trim().parse::<usize>().unwrap();
+	let reader_threads = arguments.get_one::<String>("reader_threads").unwrap().trim().parse::<usize>().unwrap();
+	let channel_size = arguments.get_one::<String>("channel_size").unwrap().trim().parse::<usize>().unwrap();
+	let max_memory = arguments.get_one::<String>("max_memory").map(|x| x.trim().parse::<u64>().unwrap());
+	let thread_delay = arguments.get_one::<String>("thread_delay").unwrap().trim().parse::<usize>().unwrap();
+	let sort_by = arguments.get_one::<String>("sort_by").unwrap();
+	let order_file = arguments.get_one::<String>("order_file").cloned();
+	let quiet = arguments.get_flag("quiet");
+	let force = arguments.get_flag("force");
+	let include = parse_patterns(arguments, "include");
+	let exclude = parse_patterns(arguments, "exclude");
+	let strip_prefix = arguments.get_one::<String>("strip_prefix").cloned();
+	let strip_prefix_optional = arguments.get_flag("strip_prefix_optional");
+	let password = arguments.get_one::<String>("encrypt").cloned();
+	let checksum = arguments.get_flag("checksum");
+	let progress = arguments.get_flag("progress");
+	let name_template = arguments.get_one::<String>("name_template").cloned();
+	let per_top_level = arguments.get_flag("per_top_level");
+	let read_buffer = arguments.get_one::<String>("read_buffer").unwrap().trim().parse::<usize>().unwrap();
+	if let Err(err) = set_read_buffer(read_buffer) { return exit_on_error(Err(err)); }
+	if password.is_some() {
+		warn!("Encrypting with ZipCrypto, which is weak. Some tools cannot open the output, and merge refuses it.");
+	}
+
+	if per_top_level { info!("Split file {} to {}, one part per top-level entry.", inputs.join(", "), output_zip); }
+	else { info!("Split file {} to {} into {} parts.", inputs.join(", "), output_zip, core_num); }
+
+	exit_on_error(split::split_archive_files(&inputs, output_zip, split::SplitOptions { core_num, reader_threads, channel_size, max_memory, thread_delay, quiet, force, sort_by, order_file, include, exclude, strip_prefix, strip_prefix_optional, password, checksum, progress, name_template, per_top_level }).await);
+}
+
+pub async fn app_merge(arguments: &ArgMatches) {
+	let input_dir = arguments.get_one::<String>("input").unwrap();
+	let output_zip = arguments.get_one::<String>("output").unwrap();
+	let quiet = arguments.get_flag("quiet");
+	let force = arguments.get_flag("force");
+
+	info!("Merge split files under {} into {}.", input_dir, output_zip);
+
+	exit_on_error(merge::merge_archive_files(input_dir, output_zip, quiet, force).await);
+}
+
+pub async fn app_verify(arguments: &ArgMatches) {
+	let input_dir = arguments.get_one::<String>("input").unwrap();
+	let password = arguments.get_one::<String>("password");
+
+	info!("Verify split files under {}.", input_dir);
+
+	exit_on_error(verify::verify_split_files(input_dir, password.map(|x| &x[..])).await);
+}
+
+pub async fn app_selftest(arguments: &ArgMatches) {
+	let input = arguments.get_one::<String>("input").unwrap();
+	let core_num = arguments.get_one::<String>("jobs").unwrap().trim().parse::<usize>().unwrap();
+	let work_dir = arguments.get_one::<String>("work_dir");
+
+	info!("Self-test of split and merge on {}.", input);
+
+	exit_on_error(selftest::self_test(input, core_num, work_dir.map(|x| &x[..])).await);
+}
+
+fn parse_patterns(arguments: &ArgMatches, id: &str) -> Vec<Pattern> {
+	compile_patterns(arguments.get_many::<String>(id).unwrap_or_default())
+}
+
+fn compile_patterns<'a>(patterns: impl Iterator<Item = &'a String>) -> Vec<Pattern> {
+	patterns.map(|x| {
+		match Pattern::new(x) {
+			Ok(pattern) => pattern,
+			Err(err) => {
+				error!("Invalid pattern {}: {}", x, err);
+				exit(1);
+			}
+		}
+	}).collect()
+}
+
+pub async fn app_serve(arguments: &ArgMatches) {
+	let config = match arguments.get_one::<String>("config") {
+		Some(path) => match serve::ServeConfig::load(path) {
+			Ok(config) => config,
+			Err(err) => return exit_on_error(Err(err))
+		},
+		None => serve::ServeConfig::default()
+	};
+
+	let dir = serve_value(arguments, "dir", config.dir);
+	let depth = serve_value(arguments, "depth", config.depth);
+	let core_num = serve_value(arguments, "jobs", config.jobs);
+	let host = serve_value(arguments, "listen", config.listen);
+	let port = serve_value(arguments, "port", config.port);
+	// Further ports only come from the command line, the first one taking the place of the config's
+	let mut extra_ports = vec![];
+	if arguments.value_source("port") == Some(ValueSource::CommandLine) {
+		for extra_port in arguments.get_many::<String>("port").unwrap().skip(1) {
+			let extra_port = extra_port.trim().parse::<u16>().unwrap();
+			if extra_port == port || extra_ports.contains(&extra_port) {
+				return exit_on_error(Err(anyhow!("Port {} is given more than once", extra_port)));
+			}
+			extra_ports.push(extra_port);
+		}
+	}
+
+	let ssl_cert = arguments.get_one::<String>("ssl_cert").cloned().or(config.ssl_cert);
+	let ssl_key = arguments.get_one::<String>("ssl_key").cloned().or(config.ssl_key);
+	if ssl_cert.is_some() != ssl_key.is_some() {
+		return exit_on_error(Err(anyhow!("ssl-cert and ssl-key must be set together")));
+	}
+	let use_ssl = ssl_cert.is_some();
+	if let (Some(ssl_cert), Some(ssl_key)) = (&ssl_cert, &ssl_key) {
+		if let Err(err) = check_tls_files(ssl_cert, ssl_key) {
+			return exit_on_error(Err(err));
+		}
+	}
+
+	let landing = arguments.get_one::<String>("landing").cloned().or(config.landing_page);
+	let land_with_path = arguments.get_flag("land_with_path") || config.land_with_path.unwrap_or(false);
+	if land_with_path && landing.is_none() {
+		return exit_on_error(Err(anyhow!("land-with-path requires landing-page to be set")));
+	}
+	let landing_redirect = match arguments.get_one::<String>("landing_redirect") {
+		Some(redirect) => match serve::LandingRedirect::parse(redirect) {
+			Some(redirect) => redirect,
+			None => return exit_on_error(Err(anyhow!("Landing redirect must be \"temporary\" or \"permanent\"")))
+		},
+		None => config.landing_redirect.unwrap_or(serve::LandingRedirect::Temporary)
+	};
+	let max_entry_size = arguments.get_one::<String>("max_entry_size").map(|x| x.trim().parse::<u64>().unwrap()).or(config.max_entry_size);
+	let max_request_bytes = serve_value(arguments, "max_request_bytes", config.max_request_bytes);
+	if max_request_bytes == 0 {
+		return exit_on_error(Err(anyhow!("max-request-bytes must be at least 1")));
+	}
+	let ignore_case = arguments.get_flag("ignore_case") || config.ignore_case.unwrap_or(false);
+	let render_markdown = arguments.get_flag("render_markdown") || config.render_markdown.unwrap_or(false);
+	let index_files = match arguments.get_many::<String>("index_file") {
+		Some(names) => names.cloned().collect(),
+		None => config.index_file.unwrap_or_else(|| vec![String::from("index.html")])
+	};
+	let auto_index = !(arguments.get_flag("no_auto_index") || config.no_auto_index.unwrap_or(false));
+	let dirs_first = arguments.get_flag("dirs_first") || config.dirs_first.unwrap_or(false);
+	let per_page = arguments.get_one::<String>("per_page").map(|x| x.trim().parse::<usize>().unwrap()).or(config.per_page);
+	if per_page == Some(0) {
+		return exit_on_error(Err(anyhow!("per-page must be at least 1")));
+	}
+	let collapse_dirs = arguments.get_flag("collapse_dirs") || config.collapse_dirs.unwrap_or(false);
+	let hide_source_archives = arguments.get_flag("hide_source_archives") || config.hide_source_archives.unwrap_or(false);
+	let allow_ext = match arguments.get_many::<String>("allow_ext") {
+		Some(exts) => exts.cloned().collect(),
+		None => config.allow_ext.unwrap_or_default()
+	};
+	let hide_listing = match arguments.get_many::<String>("hide_listing") {
+		Some(patterns) => compile_patterns(patterns),
+		None => compile_patterns(config.hide_listing.unwrap_or_default().iter())
+	};
+	let spa = arguments.get_one::<String>("spa").cloned().or(config.spa);
+	let redirect_http = arguments.get_one::<String>("redirect_http").map(|x| x.trim().parse::<u16>().unwrap()).or(config.redirect_http);
+	if redirect_http.is_some_and(|x| x == port || extra_ports.contains(&x)) {
+		return exit_on_error(Err(anyhow!("redirect-http must not be one of the listen ports")));
+	}
+	let open = arguments.get_flag("open") || config.open.unwrap_or(false);
+	let metrics = arguments.get_flag("metrics") || config.metrics.unwrap_or(false);
+	let workers = arguments.get_one::<String>("workers").map(|x| x.trim().parse::<usize>().unwrap()).or(config.workers);
+	if workers.is_some_and(|x| !(1..=1024).contains(&x)) {
+		return exit_on_error(Err(anyhow!("workers must be between 1 and 1024")));
+	}
+	// 0 turns keep-alive off
+	let keep_alive = arguments.get_one::<String>("keep_alive").map(|x| x.trim().parse::<u32>().unwrap()).or(config.keep_alive);
+	if keep_alive.is_some_and(|x| x > 3600) {
+		return exit_on_error(Err(anyhow!("keep-alive must be at most 3600 seconds")));
+	}
+	let secure_headers = arguments.get_flag("secure_headers") || config.secure_headers.unwrap_or(false);
+	let nosniff = secure_headers || arguments.get_flag("nosniff") || config.nosniff.unwrap_or(false);
+	let csp = arguments.get_one::<String>("csp").cloned().or(config.csp)
+		.or(secure_headers.then(|| String::from(serve::DEFAULT_CSP)));
+	let api_path = arguments.get_one::<String>("api_path").cloned().or(config.api_path);
+	let request_timeout = arguments.get_one::<String>("request_timeout").map(|x| x.trim().parse::<u64>().unwrap()).or(config.request_timeout);
+	if request_timeout == Some(0) {
+		return exit_on_error(Err(anyhow!("request-timeout must be at least 1 second")));
+	}
+	let request_timeout = request_timeout.map(Duration::from_secs);
+	let max_concurrent_reads = arguments.get_one::<String>("max_concurrent_reads").map(|x| x.trim().parse::<usize>().unwrap()).or(config.max_concurrent_reads);
+	if max_concurrent_reads == Some(0) {
+		return exit_on_error(Err(anyhow!("max-concurrent-reads must be at least 1")));
+	}
+
+	let file = arguments.get_one::<String>("file").cloned().or(config.file);
+	let archive_list = arguments.get_one::<String>("archive_list").cloned().or(config.archive_list);
+	if file.is_some() && archive_list.is_some() {
+		return exit_on_error(Err(anyhow!("file and archive-list cannot be used together")));
+	}
+	let flatten = arguments.get_flag("flatten") || config.flatten.unwrap_or(false);
+	if flatten && (file.is_some() || archive_list.is_some()) {
+		return exit_on_error(Err(anyhow!("flatten only applies to the archives under the directory, not to file or archive-list")));
+	}
+
+	let dry_run = arguments.get_flag("dry_run");
+	let dump_index = arguments.get_one::<String>("dump_index");
+	if dump_index.is_some() && !dry_run {
+		return exit_on_error(Err(anyhow!("dump-index requires dry-run")));
+	}
+
+	let served = match (&file, &archive_list) {
+		(Some(file), _) => file.as_str(),
+		(None, Some(list)) => list.as_str(),
+		(None, None) if dir == "." => "current directory",
+		(None, None) => &dir
+	};
+	if dry_run { info!("Indexing {} without serving.", served); }
+	else {
+		let addresses: Vec<String> = std::iter::once(port).chain(extra_ports.iter().copied())
+			.map(|x| format!("http{}://{}:{}", if use_ssl { "s" } else { "" }, host, x))
+			.collect();
+		info!("Serving file under {}. Listening {}.", served, addresses.join(", "));
+	}
+	// info!("Indexing subdirectories with a depth of {} and a thread number of {}.", depth, core_num);
+
+	let index_cache = arguments.get_one::<String>("index_cache").cloned().or(config.index_cache);
+	let recurse_zips = arguments.get_flag("recurse_zips") || config.recurse_zips.unwrap_or(false);
+	let find_duplicates = arguments.get_flag("find_duplicates") || config.find_duplicates.unwrap_or(false);
+	let remote = match arguments.get_many::<String>("remote") {
+		Some(urls) => urls.cloned().collect(),
+		None => config.remote.unwrap_or_default()
+	};
+
+	let strict = arguments.get_flag("strict") || config.strict.unwrap_or(false);
+	let follow_symlinks = serve_value(arguments, "follow_symlinks", config.follow_symlinks);
+	let preload = arguments.get_flag("preload") || config.preload.unwrap_or(false);
+	let cache_size = serve_value(arguments, "cache_size", config.cache_size);
+	let prefetch = arguments.get_flag("prefetch") || config.prefetch.unwrap_or(false);
+	let open_retries = serve_value(arguments, "open_retries", config.open_retries);
+	let index_timeout = arguments.get_one::<String>("index_timeout").map(|x| x.trim().parse::<u64>().unwrap()).or(config.index_timeout);
+	if index_timeout == Some(0) {
+		return exit_on_error(Err(anyhow!("index-timeout must be at least 1 second")));
+	}
+	let index_timeout = index_timeout.map(Duration::from_secs);
+	let partial_index = arguments.get_flag("partial_index") || config.partial_index.unwrap_or(false);
+	if partial_index && index_timeout.is_none() {
+		return exit_on_error(Err(anyhow!("partial-index requires index-timeout")));
+	}
+	let index_open_files = arguments.get_one::<String>("index_open_files").map(|x| x.trim().parse::<usize>().unwrap()).or(config.index_open_files);
+	if index_open_files == Some(0) {
+		return exit_on_error(Err(anyhow!("index-open-files must be at least 1")));
+	}
+	let json_logs = arguments.get_one::<String>("log_format").is_some_and(|x| x == "json");
+	let lazy_open = arguments.get_flag("lazy_open") || config.lazy_open.unwrap_or(false);
+	let max_open_files = serve_value(arguments, "max_open_files", config.max_open_files);
+	if let Err(err) = set_read_buffer(serve_value(arguments, "read_buffer", config.read_buffer)) { return exit_on_error(Err(err)); }
+	archive::set_verify_crc(arguments.get_flag("verify_crc") || config.verify_crc.unwrap_or(false));
+	if max_open_files == 0 {
+		return exit_on_error(Err(anyhow!("max-open-files must be at least 1")));
+	}
+	// Like --remote, patterns on the command line replace those in the config file
+	let include = match arguments.get_many::<String>("include") {
+		Some(patterns) => compile_patterns(patterns),
+		None => compile_patterns(config.include.unwrap_or_default().iter())
+	};
+	let exclude = match arguments.get_many::<String>("exclude") {
+		Some(patterns) => compile_patterns(patterns),
+		None => compile_patterns(config.exclude.unwrap_or_default().iter())
+	};
+
+	let index_options = serve::IndexOptions {
+		depth, core_num, index_cache, recurse_zips, find_duplicates, remote, include, exclude, strict, preload, cache_size, follow_symlinks, file, lazy_open, max_open_files, open_retries, index_timeout, partial_index, index_open_files, archive_list, flatten
+	};
+
+	if dry_run {
+		return exit_on_error(serve::dry_run(&dir, &index_options, dump_index.map(|x| &x[..])).await);
+	}
+
+	let serve_options = serve::ServeOptions {
+		host: &host, port, use_ssl, ssl_cert, ssl_key, landing, land_with_path, landing_redirect, max_entry_size, max_request_bytes, ignore_case, render_markdown, index_files, auto_index, hide_listing, dirs_first, per_page, collapse_dirs, hide_source_archives, allow_ext, spa, extra_ports, redirect_http, open, metrics, workers, keep_alive, nosniff, csp, api_path, request_timeout, max_concurrent_reads, prefetch, json_logs
+	};
+
+	exit_on_error(serve::launch(&dir, &index_options, &serve_options).await);
+}
+
+// Every command reading archives takes --read-buffer, which applies to the whole process
+fn set_read_buffer(size: usize) -> Result<()> {
+	if size == 0 { return Err(anyhow!("read-buffer must be at least 1 byte")); }
+	archive::set_read_buffer_size(size);
+	Ok(())
+}
+
+// A flag given on the command line wins over the config file, which wins over the flag's default value
+fn serve_value<T: FromStr>(arguments: &ArgMatches, id: &str, config: Option<T>) -> T where T::Err: Debug {
+	let value = arguments.get_one::<String>(id).unwrap();
+	match config {
+		Some(config) if arguments.value_source(id) != Some(ValueSource::CommandLine) => config,
+		_ => value.trim().parse::<T>().unwrap()
+	}
+}
@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::{anyhow, bail, Result};
+use tracing::{debug, info, warn};
+use zip::ZipArchive;
+
+use super::merge::merge_archive_files;
+use super::split::{split_archive_files, SplitOptions};
+use super::verify::verify_split_files;
+use crate::utils::archive::open_buffered;
+
+// Split parts and the merged archive, removed once the test passes
+struct WorkDir(PathBuf);
+
+impl WorkDir {
+	fn create(path: Option<&str>) -> Result<Self> {
+		let dir = match path {
+			Some(path) => PathBuf::from(path),
+			None => std::env::temp_dir().join(format!("zip_handler-selftest-{}", std::process::id()))
+		};
+		if dir.exists() { bail!("Work directory {} already exists", dir.display()); }
+		fs::create_dir_all(&dir).map_err(|err| anyhow!("Cannot create directory {}: {}", dir.display(), err))?;
+		Ok(Self(dir))
+	}
+
+	fn path(&self, name: &str) -> String {
+		self.0.join(name).to_string_lossy().into_owned()
+	}
+
+	fn remove(self) {
+		if let Err(err) = fs::remove_dir_all(&self.0) {
+			warn!("Cannot remove {}: {}", self.0.display(), err);
+		}
+	}
+}
+
+// (size, CRC32 of the data as read), by entry name
+fn read_checksums(path: &str) -> Result<BTreeMap<String, (u64, u32)>> {
+	let mut archive_file = ZipArchive::new(open_buffered(path)?).map_err(|err| anyhow!("Cannot open {}: {}", path, err))?;
+	let mut checksums = BTreeMap::new();
+	let mut buffer = vec![0u8; 64 * 1024];
+	for i in 0..archive_file.len() {
+		let mut zip_file = archive_file.by_index(i)?;
+		let mut hasher = crc32fast::Hasher::new();
+		let mut size = 0u64;
+		loop {
+			let read = match zip_file.read(&mut buffer) {
+				Ok(0) => break,
+				Ok(read) => read,
+				Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+				Err(err) => bail!("Cannot read {} in {}: {}", zip_file.name(), path, err)
+			};
+			hasher.update(&buffer[..read]);
+			size += read as u64;
+		}
+		// The first entry holding a name is the one split keeps
+		checksums.entry(zip_file.name().to_string()).or_insert((size, hasher.finalize()));
+	}
+	Ok(checksums)
+}
+
+// Splits input, verifies the parts, merges them back and compares every entry of the result
+// against the original. The work directory is kept when anything fails, for inspection.
+pub async fn self_test(input: &str, core_num: usize, work_dir: Option<&str>) -> Result<()> {
+	if !Path::new(input).is_file() { bail!("{} is not a file", input); }
+	let begin = Instant::now();
+	let original = read_checksums(input)?;
+	let work_dir = WorkDir::create(work_dir)?;
+	let (parts, merged) = (work_dir.path("parts"), work_dir.path("merged.zip"));
+
+	let result = async {
+		info!("Splitting {} into {} parts...", input, core_num);
+		split_archive_files(&[input.to_string()], &parts, SplitOptions {
+			core_num, reader_threads: 1, channel_size: 512, max_memory: None, thread_delay: 0, quiet: true, force: true,
+			sort_by: "name", order_file: None, include: vec![], exclude: vec![], strip_prefix: None, strip_prefix_optional: false,
+			password: None, checksum: true, progress: false, name_template: None, per_top_level: false
+		}).await?;
+		info!("Verifying the parts...");
+		verify_split_files(&parts, None).await?;
+		info!("Merging the parts...");
+		merge_archive_files(&parts, &merged, true, true).await?;
+		read_checksums(&merged)
+	}.await;
+	let result = result.and_then(|merged| {
+		let mut problems = 0usize;
+		for (name, (size, crc32)) in &original {
+			match merged.get(name) {
+				None => { warn!("{} is missing from the merged archive", name); problems += 1; },
+				Some((merged_size, _)) if merged_size != size => {
+					warn!("{} has {} bytes instead of {}", name, merged_size, size);
+					problems += 1;
+				},
+				Some((_, merged_crc32)) if merged_crc32 != crc32 => {
+					warn!("{} has CRC32 {:08x} instead of {:08x}", name, merged_crc32, crc32);
+					problems += 1;
+				},
+				_ => debug!("{} OK.", name)
+			}
+		}
+		for name in merged.keys().filter(|x| !original.contains_key(*x)) {
+			warn!("{} is in the merged archive only", name);
+			problems += 1;
+		}
+		if problems > 0 { bail!("{} problem(s) in the merged archive", problems); }
+		Ok(())
+	});
+
+	match result {
+		Ok(()) => {
+			work_dir.remove();
+			info!("Self-test passed: {} entries came back identical. Time: {}ms.", original.len(), (Instant::now() - begin).as_millis());
+			Ok(())
+		},
+		Err(err) => {
+			warn!("Keeping {} for inspection.", work_dir.0.display());
+			bail!("Self-test failed: {}", err)
+		}
+	}
+}
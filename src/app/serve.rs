@@ -1,397 +1,2990 @@
-use std::collections::BTreeMap;
-use std::future::Future;
-use std::path::{Path, PathBuf};
-use std::fs::{self, File};
-use std::io::{self, BufReader};
-use std::process::exit;
-use std::sync::OnceLock;
-use std::net::{IpAddr, Ipv4Addr};
-use std::time::Instant;
-
-use anyhow::Result;
-use zip::ZipArchive;
-use rocket::{self, uri, Config, Response, Request};
-use rocket::shield::{Shield, NoSniff};
-use rocket::config::{LogLevel, TlsConfig, SecretKey};
-use rocket::fs::NamedFile;
-use rocket::response::{self, Redirect, Responder};
-use rocket::http::{ContentType, Status};
-use async_recursion::async_recursion;
-
-use crate::{async_ptr_create, arc_pinned_ptr_create};
-use crate::utils::{AsyncPtr, ArcPinnedPtr};
-use crate::utils::{index_zip::index_zip_dir, index_callback::ZipCallback};
-
-type ArcFileMapPtr = ArcPinnedPtr<BTreeMap<String, FileIndex>>;
-type ArcZipHandleMapPtr = ArcPinnedPtr<BTreeMap<String, ZipArchive<BufReader<File>>>>;
-
-struct GlobalControl {
-	pub file_db: ArcFileMapPtr,
-	pub zip_handles: ArcZipHandleMapPtr,
-	pub landing_page: String,
-	pub land_with_path: bool
-}
-
-static GLOBAL_CTRL: OnceLock<AsyncPtr<GlobalControl>> = OnceLock::new();
-fn global() -> &'static AsyncPtr<GlobalControl> {
-	GLOBAL_CTRL.get_or_init(|| async_ptr_create!(GlobalControl {
-		file_db: arc_pinned_ptr_create!(BTreeMap::new()),
-		zip_handles: arc_pinned_ptr_create!(BTreeMap::new()),
-		landing_page: String::new(),
-		land_with_path: false
-	}))
-}
-
-// static FILE_DB: OnceLock<ArcFileMapPtr> = OnceLock::new();
-// fn file_db() -> &'static ArcFileMapPtr {
-// 	FILE_DB.get_or_init(|| arc_pinned_ptr_create!(BTreeMap::new()))
-// }
-
-// static ZIP_HANDLES: OnceLock<ArcZipHandleMapPtr> = OnceLock::new();
-// fn global().zip_handles -> &'static ArcZipHandleMapPtr {
-// 	ZIP_HANDLES.get_or_init(|| arc_pinned_ptr_create!(BTreeMap::new()))
-// }
-
-// static LANDING_PAGE: OnceLock<String> = OnceLock::new();
-// fn landing_page(initial: &str) -> &'static String {
-// 	LANDING_PAGE.get_or_init(|| String::from(initial))
-// }
-
-pub struct ServeOptions<'a> {
-	pub host: &'a str,
-	pub port: u16,
-	pub use_ssl: bool,
-	pub ssl_cert: Option<String>,
-	pub ssl_key: Option<String>,
-	pub landing: Option<String>,
-	pub land_with_path: bool
-}
-
-pub struct IndexOptions {
-	pub depth: isize,
-	pub core_num: usize,
-}
-
-// (file_type, zip_file_path, zip_index)
-//				^ This is the path to the zip file
-//								^ This is the internal index of the file in the zip file
-#[derive(Clone)]
-struct FileIndex(u16, Option<String>, Option<usize>);
-
-#[allow(unused)]
-impl FileIndex {
-	pub fn new(is_inside_zip: bool, is_dir: bool, zip_path: Option<String>, in_zip_path: Option<usize>) -> Self {
-		Self(if is_inside_zip { 0x01 } else { 0 } | if is_dir { 0x10 } else { 0 }, zip_path, in_zip_path)
-	}
-	pub fn is_inside_zip(&self) -> bool { self.0 & 0x01 > 0 }
-	pub fn is_dir(&self) -> bool { self.0 & 0x10 > 0 }
-	pub fn is_file(&self) -> bool { self.0 & 0x10 == 0 }
-}
-
-#[async_recursion]
-async fn iter_dir<F, R, 'a>(dir: &Path, depth: isize, cb: &mut F) -> Result<()>
-where
-	F: FnMut(PathBuf, PathBuf) -> R + Send,
-	R: Future<Output = Result<()>> + Send, {
-	if dir.is_dir() {
-		for i in fs::read_dir(dir)? {
-			let entry = i?;
-			let entry_path = entry.path();
-			cb(dir.to_path_buf(), entry_path.clone()).await?;
-			if (depth > 0 || depth == -1) && entry_path.is_dir() {
-				iter_dir(&entry_path, if depth == -1 { depth } else { depth - 1 }, cb).await?;
-			}
-		}
-	}
-	Ok(())
-}
-
-#[async_recursion]
-async fn iter_dir_cb(dir: PathBuf, x: PathBuf) -> Result<()> {
-	let zip_map;
-	{
-		let ctrl = global().lock().await;
-		zip_map = ctrl.zip_handles.clone();
-	}
-	if let Some(ext) = x.extension() {
-		if let Some(str) = ext.to_str() {
-			if str == "zip" {
-				zip_map.lock().unwrap().insert(
-					x.to_str().unwrap().to_string(),
-					ZipArchive::new(BufReader::new(File::open(x).unwrap())).unwrap());
-				return Ok(());
-			}
-		}
-	}
-	let key = x.strip_prefix(dir).unwrap().to_str().unwrap().to_string();
-	let key = key.replace('\\', "/");
-	{
-		let ctrl = global().lock().await;
-		ctrl.file_db.lock().unwrap().entry(key).or_insert_with(|| FileIndex::new(false, x.is_dir(), None, None));
-	}
-	Ok(())
-}
-
-async fn create_file_db(dir: &str, index_options: &IndexOptions, file_db: ArcFileMapPtr) -> Result<()> {
-	println!("[INFO] Creating file database...");
-
-	let begin_time = Instant::now();
-	let index_join_handle;
-	{
-		let file_db_clone = file_db.clone();
-		let parent_dir = dir.to_string();
-		index_join_handle = index_zip_dir(dir, index_options.core_num, index_options.depth, ZipCallback::new(move |x, i, f| {
-			// Whoever comes first gets inserted first
-			let xname = x.name();
-			let parent_dir = Path::new(&parent_dir);
-			let zip_file_dir = Path::new(&f);
-			let mut relative_path_buf = zip_file_dir.strip_prefix(parent_dir).unwrap().to_path_buf();
-			relative_path_buf.pop();
-			relative_path_buf.push(if x.is_dir() { &xname[..xname.len() - 1] } else { xname });
-
-			let file_path_str = relative_path_buf.to_string_lossy().replace('\\', "/");
-			file_db_clone.lock().unwrap().insert(file_path_str, FileIndex::new(true, x.is_dir(), Some(f.to_string()), Some(i)));
-		}));
-	}
-
-	iter_dir(Path::new(dir), index_options.depth, &mut iter_dir_cb).await?;
-	// iter_dir(Path::new(dir), index_options.depth, || {}).await;
-
-	index_join_handle.await?;
-	println!("[INFO] File database created. Time: {}ms.", (Instant::now() - begin_time).as_millis());
-
-	Ok(())
-}
-
-#[allow(dead_code)]
-enum GetResponse {
-    StringContent(ContentType, String),
-    Bytes(ContentType, Vec<u8>),
-    File(Option<NamedFile>),
-	Error(Status)
-}
-
-macro_rules! response_build {
-	($content_type:expr, $body:expr) => {
-		rocket::Response::build()
-			.header($content_type)
-			.sized_body($body.len(), std::io::Cursor::new($body))
-			.ok()
-	};
-}
-
-impl<'a> Responder<'a, 'static> for GetResponse {
-	fn respond_to(self, request: &'a Request<'_>) -> response::Result<'static> {
-		match self {
-			GetResponse::StringContent(content_type, body) => response_build!(content_type, body),
-			GetResponse::Bytes(content_type, body) => response_build!(content_type, body),
-			GetResponse::File(file_option) => {
-				match file_option {
-					Some(file) => file.respond_to(request),
-					None => Response::build().status(Status::NotFound).ok()
-				}
-			},
-			GetResponse::Error(status) => Response::build().status(status).ok()
-		}
-	}
-}
-
-async fn read_file_from_zip(zip_path: &String, zip_index: usize) -> Vec<u8> {
-	let zip_handles;
-	{
-		let ctrl = global().lock().await;
-		zip_handles = ctrl.zip_handles.clone();
-	}
-	let mut zip_handles = zip_handles.lock().unwrap();
-	let zip_handle = zip_handles.get_mut(zip_path).unwrap();
-	let mut zip_file = zip_handle.by_index(zip_index).unwrap();
-	let mut vec = Vec::<u8>::with_capacity(zip_file.size() as usize);
-	io::copy(&mut zip_file, &mut vec).unwrap();
-	vec
-}
-
-fn count_occurrences(s: &str, c: char) -> usize {
-    s.chars().filter(|&ch| ch == c).count()
-}
-
-fn insert_base_tag(data: &mut Vec<u8>, base_href: &str) {
-	let mut content = String::from_utf8(data.clone()).unwrap();
-    let base_tag = format!("<base href=\"{}\">", base_href);
-    if let Some(head_end) = content.find("</head>") {
-        content.insert_str(head_end, &base_tag);
-        *data = content.into_bytes();
-    }
-}
-
-macro_rules! response_file_index {
-	($file_index_opt:expr, $file_ext:expr, $cur_path:expr, $auto_index:expr) => {
-		if let Some(file_index) = $file_index_opt {
-			match file_index.0 {
-				0x00 => return GetResponse::File(NamedFile::open($cur_path).await.ok()),
-				0x01 => {
-					let zip_path = file_index.1.clone().unwrap();
-					let zip_index = file_index.2.clone().unwrap();
-					let ctype;
-					if let Some(ext) = $file_ext {
-						ctype = match ext.to_str().unwrap() {
-							"html" => ContentType::HTML,
-							"js" => ContentType::JavaScript,
-							"css" => ContentType::CSS,
-							_ => ContentType::Bytes
-						}
-					}
-					else {
-						ctype = ContentType::Bytes;
-					}
-					let mut data = read_file_from_zip(&zip_path, zip_index).await;
-					if ctype == ContentType::HTML && $auto_index {
-						insert_base_tag(&mut data, $cur_path);
-					}
-					return GetResponse::Bytes(ctype, data);
-				},
-				_ => {}
-			}
-		}
-	};
-}
-
-#[allow(unused)]
-#[derive(Responder)]
-enum RouteResult {
-	GetResponse(GetResponse),
-	Redirect(Redirect),
-	String(String)
-}
-
-#[allow(unused)]
-fn html_redirect_str(uri: String) -> String {
-	String::from(format!(r#"<html><head><meta http-equiv="refresh" content="0;url={}" /><title></title></head><body></body></html>"#, uri))
-}
-
-#[rocket::get("/")]
-async fn landing_route() -> RouteResult {
-	let landing_page;
-	let land_with_path;
-	{
-		let ctrl = global().lock().await;
-		landing_page = ctrl.landing_page.clone();
-		land_with_path = ctrl.land_with_path;
-	}
-	if landing_page.is_empty() {
-		return RouteResult::GetResponse(file_route(PathBuf::new()).await);
-	}
-	else {
-		let a = landing_page.clone();
-		if land_with_path {
-			return RouteResult::Redirect(Redirect::temporary(uri!(file_route(PathBuf::from(a)))));
-			// return RouteResult::String(html_redirect_str(uri!(file_route(PathBuf::from(a))).to_string()));
-		}
-		else {
-			return RouteResult::GetResponse(file_route(PathBuf::from(&a[..])).await);
-		}
-	}
-}
-
-#[rocket::post("/<_path..>")]
-async fn post_route(_path: PathBuf) -> &'static str {
-	// Maybe some meilisearch integration in the future?
-	"{}"
-}
-
-#[rocket::get("/<path..>")]
-async fn file_route(path: PathBuf) -> GetResponse {
-	let file_ext = path.extension();
-	let cur_path = path.to_str().unwrap().replace('\\', "/");
-
-	println!("[INFO] GET Request: {}", if cur_path.is_empty() { "current path" } else { &cur_path });
-
-	let file_db;
-	{
-		let ctrl = global().lock().await;
-		file_db = ctrl.file_db.clone();
-	}
-	let file_index_opt;
-	let index_html_opt;
-	{
-		let file_db_lock = file_db.lock().unwrap();
-		file_index_opt = file_db_lock.get(&cur_path).map(|f| f.clone());
-		index_html_opt = file_db_lock.get(&format!("{}/index.html", cur_path)).map(|f| f.clone());
-	}
-	response_file_index!(index_html_opt, Some(&std::ffi::OsString::from("html")), &format!("{}/index.html", cur_path), true);
-	response_file_index!(file_index_opt, file_ext, &cur_path, false);
-
-	let mut file_list = vec![];
-	for (k, _) in file_db.lock().unwrap().iter() {
-		if k != &cur_path &&
-		   k.starts_with(&cur_path) &&
-		   count_occurrences(k.strip_prefix(&format!("{}/", cur_path)).unwrap_or(k), '/') == 0 {
-			file_list.push(format!("<a href=\"\\{}\">{}</a>", k, k));
-		}
-	}
-	GetResponse::StringContent(ContentType::HTML, format!("<pre>Files under {}:<br>  {}</pre>", if cur_path.is_empty() { "current path" } else { &cur_path }, file_list.join("<br>  ")))
-}
-
-pub async fn launch(dir: &str, index_options: &IndexOptions, serve_options: &ServeOptions<'_>) -> Result<()> {
-	let current_path = PathBuf::from(dir);
-	if !current_path.is_dir() {
-		println!("[ERROR] Target path is not a directory!");
-		exit(1);
-	}
-
-	let address = match serve_options.host.parse::<Ipv4Addr>() {
-		Ok(x) => { IpAddr::V4(x) },
-		Err(err) => {
-			println!("[ERROR] Invalid IP address: {}, error: {}", serve_options.host, err);
-			exit(1);
-		}
-	};
-
-	let mut server_config = Config {
-		log_level: LogLevel::Normal,
-		cli_colors: false,
-		address,
-		port: serve_options.port,
-		secret_key: SecretKey::generate().unwrap_or(SecretKey::from(&[0; 64])),
-		..Default::default()
-	};
-
-	let file_db;
-	{
-		let mut ctrl = global().lock().await;
-		file_db = ctrl.file_db.clone();
-
-		if let Some(landing) = &serve_options.landing {
-			ctrl.landing_page.clone_from(&landing);
-			ctrl.land_with_path = serve_options.land_with_path;
-			println!("[INFO] Serving default page: {}", landing);
-		}
-	}
-
-	create_file_db(current_path.to_str().unwrap(), index_options, file_db).await?;
-
-	if serve_options.use_ssl {
-		server_config.tls = Some(TlsConfig::from_paths(
-			serve_options.ssl_cert.clone().unwrap(),
-			serve_options.ssl_key.clone().unwrap()
-		));
-	}
-	
-	#[cfg(not(debug_assertions))]
-	set_log_level_critical(&mut server_config);
-
-	let _ = rocket::custom(server_config)
-		.attach(Shield::default().disable::<NoSniff>())
-		.mount("/", rocket::routes![file_route])
-		.mount("/", rocket::routes![post_route])
-		.mount("/", rocket::routes![landing_route])
-		.launch()
-		.await?;
-
-	Ok(())
-}
-
-#[inline]
-#[allow(unused)]
-fn set_log_level_critical(config: &mut Config) {
-	config.log_level = LogLevel::Critical;
-}
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::iter;
+use std::ops::Bound;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::net::{IpAddr, Ipv4Addr};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use tracing::{debug, error, info, warn};
+use tracing::level_filters::LevelFilter;
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use zip::ZipArchive;
+use rocket::{self, uri, Build, Config, Data, Ignite, Response, Request, Rocket, Shutdown, State};
+use rocket::fairing::{AdHoc, Fairing, Info, Kind};
+use rocket::shield::{Shield, NoSniff};
+use rocket::config::{LogLevel, TlsConfig, SecretKey};
+use rocket::fs::NamedFile;
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Redirect, Responder};
+use rocket::http::{ContentType, Method, Status};
+use rocket::serde::json::Json;
+use rocket::http::uri::{Host, Origin};
+use async_recursion::async_recursion;
+use pulldown_cmark::{Options as MdOptions, Parser as MdParser};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::runtime::Handle;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+
+use crate::{async_ptr_create, arc_ptr_create, arc_pinned_ptr_create};
+use crate::utils::{AsyncPtr, ArcPinnedPtr, ArcPtr};
+use crate::utils::{index_zip::{index_zip_dir_filtered, DirWalk, OpenLimits}, index_callback::ZipCallback};
+use crate::utils::archive::{retry_open, unsupported_zip, ArchiveEntry, ArchiveHandle, ArchiveKind, EntryCorrupt, EntryTooLarge, OpenZips, SharedFile};
+use crate::utils::progress::{human_size, print_progress_bar};
+use crate::utils::remote::HttpRangeReader;
+use crate::utils::zip_stream::StreamZipWriter;
+
+type ArcFileMapPtr = ArcPinnedPtr<BTreeMap<String, FileIndex>>;
+type ArcZipHandleMapPtr = ArcPinnedPtr<BTreeMap<String, ArchiveHandle>>;
+type ArcArchiveStatsMapPtr = ArcPinnedPtr<BTreeMap<String, ArchiveStats>>;
+// "{size}-{crc32}" -> [(archive path, entry name)]
+type ArcContentHashMapPtr = ArcPinnedPtr<BTreeMap<String, Vec<(String, String)>>>;
+
+struct GlobalControl {
+	pub file_db: ArcFileMapPtr,
+	pub zip_handles: ArcZipHandleMapPtr,
+	// Shared by the zips in zip_handles with lazy_open
+	pub open_zips: Option<ArcPtr<OpenZips>>,
+	// Same keys as zip_handles, gathered while indexing
+	pub archive_stats: ArcArchiveStatsMapPtr,
+	// Only filled with find_duplicates
+	pub content_hashes: ArcContentHashMapPtr,
+	pub landing_page: String,
+	pub land_with_path: bool,
+	pub landing_redirect: LandingRedirect,
+	pub max_entry_size: Option<u64>,
+	// Bytes one request may decompress from archives, however many entries it reads
+	pub max_request_bytes: u64,
+	pub ignore_case: bool,
+	pub render_markdown: bool,
+	pub index_files: Vec<String>,
+	pub auto_index: bool,
+	// Directories refused with 403 instead of listed, their files are still served
+	pub hide_listing: Vec<Pattern>,
+	pub dirs_first: bool,
+	// Listings are split into pages of this many entries, unless the query asks otherwise
+	pub per_page: Option<usize>,
+	pub collapse_dirs: bool,
+	// Archives under the directory are neither listed nor downloadable, only their content is served
+	pub hide_source_archives: bool,
+	// Archive entries are indexed by base name, which listings point out
+	pub flatten: bool,
+	// Extensions served, lowercase and without the dot. Empty serves every file.
+	pub allow_ext: Vec<String>,
+	pub spa_index: Option<String>,
+	pub root_dir: PathBuf,
+	// lowercased path -> path in file_db, only filled with ignore_case
+	pub file_db_lowercase: ArcPinnedPtr<BTreeMap<String, String>>,
+	pub metrics: Arc<ServeMetrics>,
+	pub request_timeout: Option<Duration>,
+	// Entries being read from their archives at once for requests, unlimited when None
+	pub read_permits: Option<Arc<Semaphore>>,
+	// Filled by preload and prefetch
+	pub entry_cache: ArcPinnedPtr<EntryCache>,
+	pub prefetch: bool
+}
+
+// Decompressed archive entries kept in memory, up to a byte budget. Nothing is evicted, so once the
+// budget is used up further entries are read from their archive on every request.
+#[derive(Default)]
+pub struct EntryCache {
+	// (archive path, entry index) -> decompressed entry
+	entries: BTreeMap<(String, usize), Vec<u8>>,
+	bytes: u64,
+	budget: u64
+}
+
+impl EntryCache {
+	fn get(&self, zip_path: &str, zip_index: usize) -> Option<&Vec<u8>> {
+		self.entries.get(&(zip_path.to_string(), zip_index))
+	}
+	fn fits(&self, size: u64) -> bool { self.bytes + size <= self.budget }
+	// Returns false, dropping data, if it does not fit
+	fn insert(&mut self, zip_path: String, zip_index: usize, data: Vec<u8>) -> bool {
+		if !self.fits(data.len() as u64) { return false; }
+		self.bytes += data.len() as u64;
+		self.entries.insert((zip_path, zip_index), data);
+		true
+	}
+}
+
+// Counters served by /metrics, kept up to date by RequestLogger
+#[derive(Default)]
+struct ServeMetrics {
+	requests: AtomicU64,
+	// Indexed by the first digit of the status, 1xx to 5xx
+	responses: [AtomicU64; 5],
+	// Streamed bodies are not counted, as their size is unknown
+	bytes: AtomicU64
+}
+
+impl ServeMetrics {
+	// Statuses outside 1xx to 5xx have no counter and are left out
+	fn count_response(&self, code: u16) {
+		if let Some(count) = (code as usize / 100).checked_sub(1).and_then(|x| self.responses.get(x)) {
+			count.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+}
+
+static GLOBAL_CTRL: OnceLock<AsyncPtr<GlobalControl>> = OnceLock::new();
+fn global() -> &'static AsyncPtr<GlobalControl> {
+	GLOBAL_CTRL.get_or_init(|| async_ptr_create!(GlobalControl {
+		file_db: arc_pinned_ptr_create!(BTreeMap::new()),
+		zip_handles: arc_pinned_ptr_create!(BTreeMap::new()),
+		open_zips: None,
+		archive_stats: arc_pinned_ptr_create!(BTreeMap::new()),
+		content_hashes: arc_pinned_ptr_create!(BTreeMap::new()),
+		landing_page: String::new(),
+		land_with_path: false,
+		landing_redirect: LandingRedirect::Temporary,
+		max_entry_size: None,
+		max_request_bytes: u64::MAX,
+		ignore_case: false,
+		render_markdown: false,
+		index_files: vec![String::from("index.html")],
+		auto_index: true,
+		hide_listing: vec![],
+		dirs_first: false,
+		per_page: None,
+		collapse_dirs: false,
+		hide_source_archives: false,
+		flatten: false,
+		allow_ext: vec![],
+		spa_index: None,
+		root_dir: PathBuf::new(),
+		file_db_lowercase: arc_pinned_ptr_create!(BTreeMap::new()),
+		metrics: Arc::new(ServeMetrics::default()),
+		request_timeout: None,
+		read_permits: None,
+		entry_cache: arc_pinned_ptr_create!(EntryCache::default()),
+		prefetch: false
+	}))
+}
+
+// The maps indexing fills: those of the global state, or fresh ones that a reindex swaps in at once
+// when it is done, so that requests meanwhile are answered from the previous index
+#[derive(Clone)]
+struct IndexTarget {
+	file_db: ArcFileMapPtr,
+	zip_handles: ArcZipHandleMapPtr,
+	archive_stats: ArcArchiveStatsMapPtr,
+	content_hashes: ArcContentHashMapPtr
+}
+
+impl IndexTarget {
+	async fn global() -> Self {
+		let ctrl = global().lock().await;
+		Self {
+			file_db: ctrl.file_db.clone(),
+			zip_handles: ctrl.zip_handles.clone(),
+			archive_stats: ctrl.archive_stats.clone(),
+			content_hashes: ctrl.content_hashes.clone()
+		}
+	}
+
+	fn fresh() -> Self {
+		Self {
+			file_db: arc_pinned_ptr_create!(BTreeMap::new()),
+			zip_handles: arc_pinned_ptr_create!(BTreeMap::new()),
+			archive_stats: arc_pinned_ptr_create!(BTreeMap::new()),
+			content_hashes: arc_pinned_ptr_create!(BTreeMap::new())
+		}
+	}
+}
+
+// static FILE_DB: OnceLock<ArcFileMapPtr> = OnceLock::new();
+// fn file_db() -> &'static ArcFileMapPtr {
+// 	FILE_DB.get_or_init(|| arc_pinned_ptr_create!(BTreeMap::new()))
+// }
+
+// static ZIP_HANDLES: OnceLock<ArcZipHandleMapPtr> = OnceLock::new();
+// fn global().zip_handles -> &'static ArcZipHandleMapPtr {
+// 	ZIP_HANDLES.get_or_init(|| arc_pinned_ptr_create!(BTreeMap::new()))
+// }
+
+// static LANDING_PAGE: OnceLock<String> = OnceLock::new();
+// fn landing_page(initial: &str) -> &'static String {
+// 	LANDING_PAGE.get_or_init(|| String::from(initial))
+// }
+
+// Status used when --land-with-path redirects to the landing page
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LandingRedirect {
+	// 307, the landing page may change
+	Temporary,
+	// 301, browsers and proxies may cache it
+	Permanent
+}
+
+impl LandingRedirect {
+	pub fn parse(redirect: &str) -> Option<Self> {
+		match redirect {
+			"temporary" => Some(Self::Temporary),
+			"permanent" => Some(Self::Permanent),
+			_ => None
+		}
+	}
+}
+
+pub struct ServeOptions<'a> {
+	pub host: &'a str,
+	pub port: u16,
+	pub use_ssl: bool,
+	pub ssl_cert: Option<String>,
+	pub ssl_key: Option<String>,
+	pub landing: Option<String>,
+	pub land_with_path: bool,
+	pub landing_redirect: LandingRedirect,
+	pub max_entry_size: Option<u64>,
+	// Stops downloads decompressing more than this, and caps single entries like max_entry_size
+	pub max_request_bytes: u64,
+	pub ignore_case: bool,
+	pub render_markdown: bool,
+	// Names tried in order when a directory is requested
+	pub index_files: Vec<String>,
+	// List directories without an index file, refuse them with 403 otherwise
+	pub auto_index: bool,
+	// Matched against directory paths, whose listing is then refused like without auto_index
+	pub hide_listing: Vec<Pattern>,
+	// List subdirectories before files, which "?dirs_first=0" or "=1" overrides per request
+	pub dirs_first: bool,
+	pub per_page: Option<usize>,
+	// List a chain of directories holding nothing but the next one as a single "a/b/c/" entry
+	pub collapse_dirs: bool,
+	// Neither list nor serve the archive files themselves, only their content
+	pub hide_source_archives: bool,
+	// Refuse files with other extensions with 403, directories are still listed
+	pub allow_ext: Vec<String>,
+	// Page served for unknown routes without an extension, for single-page apps
+	pub spa: Option<String>,
+	// Further ports serving the same content as port
+	pub extra_ports: Vec<u16>,
+	// Port of a plain HTTP listener redirecting to the TLS one
+	pub redirect_http: Option<u16>,
+	// Open the default browser once the server listens
+	pub open: bool,
+	// Serve request counters at /metrics
+	pub metrics: bool,
+	// Rocket's worker threads and keep-alive seconds, left to its defaults when unset
+	pub workers: Option<usize>,
+	pub keep_alive: Option<u32>,
+	// Send X-Content-Type-Options: nosniff
+	pub nosniff: bool,
+	// Content-Security-Policy sent with every response
+	pub csp: Option<String>,
+	// Base path accepting POST requests, which are refused everywhere when unset
+	pub api_path: Option<String>,
+	// Longest time spent building a response, and waiting on a client reading a streamed download
+	pub request_timeout: Option<Duration>,
+	// Answer 503 instead of reading another entry while this many are being read
+	pub max_concurrent_reads: Option<usize>,
+	// Cache the stylesheets and scripts next to a served HTML entry, within the cache size
+	pub prefetch: bool,
+	// Whether logs are JSON, which the request log then fills with fields
+	pub json_logs: bool
+}
+
+// Used by --secure-headers when no --csp is given. Archive content may only load from this server.
+pub const DEFAULT_CSP: &str = "default-src 'self'; object-src 'none'; base-uri 'self'; frame-ancestors 'self'";
+
+// Settings read from --config, keyed like the long command line flags. Flags given on the
+// command line take precedence over these.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ServeConfig {
+	pub dir: Option<String>,
+	pub depth: Option<isize>,
+	pub jobs: Option<usize>,
+	pub listen: Option<String>,
+	pub port: Option<u16>,
+	pub ssl_cert: Option<String>,
+	pub ssl_key: Option<String>,
+	pub landing_page: Option<String>,
+	pub land_with_path: Option<bool>,
+	pub landing_redirect: Option<LandingRedirect>,
+	pub index_cache: Option<String>,
+	pub recurse_zips: Option<bool>,
+	pub find_duplicates: Option<bool>,
+	pub remote: Option<Vec<String>>,
+	pub max_entry_size: Option<u64>,
+	pub max_request_bytes: Option<u64>,
+	pub ignore_case: Option<bool>,
+	pub render_markdown: Option<bool>,
+	pub index_file: Option<Vec<String>>,
+	pub no_auto_index: Option<bool>,
+	pub hide_listing: Option<Vec<String>>,
+	pub dirs_first: Option<bool>,
+	pub per_page: Option<usize>,
+	pub collapse_dirs: Option<bool>,
+	pub allow_ext: Option<Vec<String>>,
+	pub spa: Option<String>,
+	pub redirect_http: Option<u16>,
+	pub open: Option<bool>,
+	pub metrics: Option<bool>,
+	pub workers: Option<usize>,
+	pub keep_alive: Option<u32>,
+	pub nosniff: Option<bool>,
+	pub csp: Option<String>,
+	pub secure_headers: Option<bool>,
+	pub api_path: Option<String>,
+	pub include: Option<Vec<String>>,
+	pub exclude: Option<Vec<String>>,
+	pub request_timeout: Option<u64>,
+	pub max_concurrent_reads: Option<usize>,
+	pub strict: Option<bool>,
+	pub preload: Option<bool>,
+	pub cache_size: Option<u64>,
+	pub prefetch: Option<bool>,
+	pub follow_symlinks: Option<bool>,
+	pub file: Option<String>,
+	pub lazy_open: Option<bool>,
+	pub max_open_files: Option<usize>,
+	pub open_retries: Option<usize>,
+	pub index_timeout: Option<u64>,
+	pub partial_index: Option<bool>,
+	pub read_buffer: Option<usize>,
+	pub index_open_files: Option<usize>,
+	pub archive_list: Option<String>,
+	pub flatten: Option<bool>,
+	pub verify_crc: Option<bool>,
+	pub hide_source_archives: Option<bool>
+}
+
+impl ServeConfig {
+	pub fn load(path: &str) -> Result<Self> {
+		let content = fs::read_to_string(path).map_err(|err| anyhow!("Cannot read config file {}: {}", path, err))?;
+		toml::from_str(&content).map_err(|err| anyhow!("Invalid config file {}: {}", path, err))
+	}
+}
+
+#[derive(Clone)]
+pub struct IndexOptions {
+	pub depth: isize,
+	pub core_num: usize,
+	pub index_cache: Option<String>,
+	pub recurse_zips: bool,
+	// Group identical zip entries by size and CRC32 while indexing
+	pub find_duplicates: bool,
+	// URLs of zip files served over HTTP, mounted at the root
+	pub remote: Vec<String>,
+	// Matched against archive paths relative to the served directory
+	pub include: Vec<Pattern>,
+	pub exclude: Vec<Pattern>,
+	// Abort indexing on absolute or traversal entry names instead of cleaning them up
+	pub strict: bool,
+	// Decompress archive entries into memory after indexing, up to cache_size bytes in total
+	pub preload: bool,
+	pub cache_size: u64,
+	// Index symlinked files and directories too, which may lead outside the served directory
+	pub follow_symlinks: bool,
+	// Serve this zip alone at the root instead of the directory, reindexed whenever it changes
+	pub file: Option<String>,
+	// Open the zips under the directory when reading from them, keeping at most max_open_files open,
+	// instead of holding one file descriptor per zip for as long as the server runs
+	pub lazy_open: bool,
+	pub max_open_files: usize,
+	// Times opening an archive is retried on transient I/O errors before it is skipped
+	pub open_retries: usize,
+	// Give up indexing after this long, or with partial_index serve whatever it reached by then
+	pub index_timeout: Option<Duration>,
+	pub partial_index: bool,
+	// Archives opened at once while indexing, whatever core_num is. Unset, every worker may hold one.
+	pub index_open_files: Option<usize>,
+	// Serve the zips listed in this file, one "PATH" or "PREFIX=PATH" per line, instead of scanning the directory
+	pub archive_list: Option<String>,
+	// Index the entries of the archives under the directory by their base name alone, dropping their
+	// directories. Clashing names are handled like any other path collision.
+	pub flatten: bool
+}
+
+// Entry names come from the archives and cannot be trusted. Absolute names are made relative and
+// names leaving the archive ("..", drive prefixes) are left out, so that they never become keys.
+struct EntryNameCheck {
+	strict: bool,
+	renamed: AtomicUsize,
+	refused: AtomicUsize,
+	// Archive files indexed under a path another archive file already took
+	collided: AtomicUsize
+}
+
+impl EntryNameCheck {
+	fn new(strict: bool) -> Self {
+		Self { strict, renamed: AtomicUsize::new(0), refused: AtomicUsize::new(0), collided: AtomicUsize::new(0) }
+	}
+
+	// The name to index the entry under, None if it is left out
+	fn check(&self, archive: &str, name: &str) -> Result<Option<String>> {
+		let trimmed = name.trim_end_matches('/');
+		let clean = normalize_request_path(std::iter::once(trimmed.trim_start_matches(['/', '\\']))).filter(|x| !x.is_empty());
+		if clean.as_deref() == Some(trimmed) { return Ok(clean); }
+		if self.strict { bail!("Unsafe entry name {:?} in {}", name, archive); }
+		match clean {
+			Some(_) => self.renamed.fetch_add(1, Ordering::Relaxed),
+			None => self.refused.fetch_add(1, Ordering::Relaxed)
+		};
+		Ok(clean)
+	}
+
+	fn report(&self) {
+		let renamed = self.renamed.load(Ordering::Relaxed);
+		let refused = self.refused.load(Ordering::Relaxed);
+		if renamed + refused > 0 {
+			warn!("Found {} absolute or traversal entry name(s): {} made relative, {} left out.", renamed + refused, renamed, refused);
+		}
+		let collided = self.collided.load(Ordering::Relaxed);
+		if collided > 0 { info!("{} archive entries share their path with another one.", collided); }
+	}
+
+	// Counts an entry indexed at a path that was already taken, unless both are directories
+	fn record(&self, previous: Option<&FileIndex>, is_dir: bool) {
+		if previous.is_some_and(|x| x.is_file() || !is_dir) {
+			self.collided.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+}
+
+// Decides which archives under the served directory are opened and indexed
+struct ArchiveFilter {
+	root: PathBuf,
+	include: Vec<Pattern>,
+	exclude: Vec<Pattern>,
+	skipped: AtomicUsize
+}
+
+impl ArchiveFilter {
+	fn allows(&self, path: &Path) -> bool {
+		let relative = path.strip_prefix(&self.root).unwrap_or(path).to_string_lossy().replace('\\', "/");
+		(self.include.is_empty() || self.include.iter().any(|p| p.matches(&relative))) &&
+			!self.exclude.iter().any(|p| p.matches(&relative))
+	}
+}
+
+// (file_type, zip_file_path, zip_index)
+//				^ This is the path to the zip file
+//								^ This is the internal index of the file in the zip file
+#[derive(Clone, Serialize, Deserialize)]
+struct FileIndex(u16, Option<String>, Option<usize>, u64);
+
+#[allow(unused)]
+impl FileIndex {
+	pub fn new(is_inside_zip: bool, is_dir: bool, zip_path: Option<String>, in_zip_path: Option<usize>, size: u64) -> Self {
+		Self(if is_inside_zip { 0x01 } else { 0 } | if is_dir { 0x10 } else { 0 }, zip_path, in_zip_path, size)
+	}
+	// Uncompressed size, 0 for directories
+	pub fn size(&self) -> u64 { self.3 }
+	pub fn is_inside_zip(&self) -> bool { self.0 & 0x01 > 0 }
+	pub fn is_dir(&self) -> bool { self.0 & 0x10 > 0 }
+	pub fn is_file(&self) -> bool { self.0 & 0x10 == 0 }
+}
+
+#[async_recursion]
+async fn iter_dir<F, R, 'a>(dir: &Path, depth: isize, cb: &mut F, walk: &mut DirWalk) -> Result<()>
+where
+	F: FnMut(PathBuf, PathBuf) -> R + Send,
+	R: Future<Output = Result<()>> + Send, {
+	if dir.is_dir() {
+		if !walk.enter(dir) { return Ok(()); }
+		for i in fs::read_dir(dir)? {
+			let entry = i?;
+			if walk.skips(&entry) { continue; }
+			let entry_path = entry.path();
+			cb(dir.to_path_buf(), entry_path.clone()).await?;
+			if depth != 0 && entry_path.is_dir() {
+				iter_dir(&entry_path, if depth < 0 { depth } else { depth - 1 }, cb, walk).await?;
+			}
+		}
+		walk.leave();
+	}
+	Ok(())
+}
+
+#[async_recursion]
+async fn iter_dir_cb(x: PathBuf, filter: Arc<ArchiveFilter>, open_retries: usize, target: IndexTarget) -> Result<()> {
+	let zip_map = target.zip_handles;
+	let open_zips;
+	let hide_source_archives;
+	{
+		let ctrl = global().lock().await;
+		open_zips = ctrl.open_zips.clone();
+		hide_source_archives = ctrl.hide_source_archives;
+	}
+	// Keyed from the served directory, as iter_dir only passes the directory holding x
+	let Some(key) = x.strip_prefix(&filter.root).ok().and_then(|x| x.to_str()) else {
+		warn!("Skipping {}, its path is not valid UTF-8", x.display());
+		return Ok(());
+	};
+	let key = key.replace('\\', "/");
+	let size = fs::metadata(&x).map(|x| x.len()).unwrap_or(0);
+	if ArchiveKind::from_path(&x).is_some() {
+		// Filtered archives are left out completely, index_zip_dir_filtered skips them too
+		if !filter.allows(&x) {
+			filter.skipped.fetch_add(1, Ordering::Relaxed);
+			return Ok(());
+		}
+		// The archive itself stays downloadable next to its content
+		if !hide_source_archives {
+			target.file_db.lock().unwrap().entry(key).or_insert_with(|| FileIndex::new(false, false, None, None, size));
+		}
+		// Unreadable and unsupported archives are reported and skipped by index_zip_dir
+		let Some(path) = x.to_str() else { return Ok(()); };
+		if unsupported_zip(&x).is_some() { return Ok(()); }
+		// Opening parses the archive and retrying sleeps between attempts, neither of which may stall the runtime
+		let path_clone = path.to_string();
+		let zip_handle = tokio::task::spawn_blocking(move || retry_open(&path_clone, open_retries, || match &open_zips {
+			Some(open_zips) => ArchiveHandle::open_lazy(&path_clone, open_zips),
+			None => ArchiveHandle::open(&path_clone)
+		})).await?;
+		if let Ok(zip_handle) = zip_handle {
+			zip_map.lock().unwrap().insert(path.to_string(), zip_handle);
+		}
+		return Ok(());
+	}
+	// Still served as a plain file, only its content is not browsable
+	if let Some(reason) = unsupported_zip(&x) { warn!("Not browsing {}: {}", key, reason); }
+	target.file_db.lock().unwrap().entry(key).or_insert_with(|| FileIndex::new(false, x.is_dir(), None, None, if x.is_dir() { 0 } else { size }));
+	Ok(())
+}
+
+// Archive entries of the file database, valid as long as the archives themselves are unchanged
+#[derive(Serialize, Deserialize, PartialEq)]
+struct IndexCacheStamp {
+	dir: String,
+	depth: isize,
+	recurse_zips: bool,
+	find_duplicates: bool,
+	flatten: bool,
+	// path -> (size, mtime in nanoseconds)
+	archives: BTreeMap<String, (u64, u128)>
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexCache {
+	stamp: IndexCacheStamp,
+	entries: BTreeMap<String, FileIndex>,
+	#[serde(default)]
+	archive_stats: BTreeMap<String, ArchiveStats>,
+	#[serde(default)]
+	content_hashes: BTreeMap<String, Vec<(String, String)>>
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
+struct ArchiveStats {
+	entries: usize,
+	compressed_size: u64,
+	uncompressed_size: u64
+}
+
+impl ArchiveStats {
+	fn add(&mut self, entry: &dyn ArchiveEntry) {
+		self.entries += 1;
+		self.compressed_size += entry.compressed_size();
+		self.uncompressed_size += entry.size();
+	}
+}
+
+fn index_cache_stamp(dir: &str, index_options: &IndexOptions, target: &IndexTarget) -> Result<IndexCacheStamp> {
+	let mut archives = BTreeMap::new();
+	for path in target.zip_handles.lock().unwrap().keys() {
+		let meta = fs::metadata(path)?;
+		let mtime = meta.modified()?.duration_since(UNIX_EPOCH)?.as_nanos();
+		archives.insert(path.clone(), (meta.len(), mtime));
+	}
+	Ok(IndexCacheStamp { dir: dir.to_string(), depth: index_options.depth, recurse_zips: index_options.recurse_zips, find_duplicates: index_options.find_duplicates, flatten: index_options.flatten, archives })
+}
+
+fn load_index_cache(cache_path: &str, stamp: &IndexCacheStamp) -> Option<IndexCache> {
+	let cache: IndexCache = serde_json::from_reader(BufReader::new(File::open(cache_path).ok()?)).ok()?;
+	if &cache.stamp == stamp { Some(cache) } else { None }
+}
+
+fn save_index_cache(cache_path: &str, stamp: IndexCacheStamp, file_db: &ArcFileMapPtr, archive_stats: &ArcArchiveStatsMapPtr, content_hashes: &ArcContentHashMapPtr) -> Result<()> {
+	let entries = file_db.lock().unwrap().iter()
+		.filter(|(_, v)| v.is_inside_zip())
+		.map(|(k, v)| (k.clone(), v.clone()))
+		.collect();
+	let archive_stats = (**archive_stats.lock().unwrap()).clone();
+	let content_hashes = (**content_hashes.lock().unwrap()).clone();
+	serde_json::to_writer(BufWriter::new(File::create(cache_path)?), &IndexCache { stamp, entries, archive_stats, content_hashes })?;
+	Ok(())
+}
+
+// Reads the central directory of each remote zip with range requests. Local files take
+// precedence over remote entries with the same path.
+async fn index_remote_archives(urls: &[String], target: &IndexTarget, names: &EntryNameCheck) -> Result<()> {
+	let IndexTarget { file_db, zip_handles, archive_stats, .. } = target;
+	for url in urls {
+		let begin_time = Instant::now();
+		let url_clone = url.clone();
+		let (zip_handle, entries, stats) = tokio::task::spawn_blocking(move || -> Result<_> {
+			let mut zip_handle = ZipArchive::new(HttpRangeReader::open(&url_clone)?)?;
+			let mut entries = Vec::with_capacity(zip_handle.len());
+			let mut stats = ArchiveStats::default();
+			for i in 0..zip_handle.len() {
+				// Raw access avoids setting up a decompressor for every entry
+				let entry = zip_handle.by_index_raw(i)?;
+				stats.add(&entry);
+				entries.push((entry.name().to_string(), entry.is_dir(), entry.size()));
+			}
+			Ok((zip_handle, entries, stats))
+		}).await?.map_err(|err| anyhow!("Cannot open remote archive {}: {}", url, err))?;
+		archive_stats.lock().unwrap().insert(url.clone(), stats);
+
+		let mut file_db_lock = file_db.lock().unwrap();
+		for (i, (name, is_dir, size)) in entries.into_iter().enumerate() {
+			let Some(name) = names.check(url, &name)? else { continue; };
+			names.record(file_db_lock.get(&name), is_dir);
+			file_db_lock.entry(name).or_insert_with(|| FileIndex::new(true, is_dir, Some(url.clone()), Some(i), size));
+		}
+		zip_handles.lock().unwrap().insert(url.clone(), ArchiveHandle::Remote(zip_handle));
+		info!("Remote archive {} indexed. Time: {}ms.", url, (Instant::now() - begin_time).as_millis());
+	}
+	Ok(())
+}
+
+// Opens the zip served by --file and indexes its entries at the root, apart from the global state
+async fn read_archive_file(path: &str, names: &EntryNameCheck) -> Result<(ArchiveHandle, BTreeMap<String, FileIndex>, ArchiveStats)> {
+	if ArchiveKind::from_path(Path::new(path)) != Some(ArchiveKind::Zip) {
+		bail!("{} is not a zip file", path);
+	}
+	let path_clone = path.to_string();
+	let (zip_handle, entries, stats) = tokio::task::spawn_blocking(move || -> Result<_> {
+		let mut zip_handle = ZipArchive::new(SharedFile::open(&path_clone)?)?;
+		let mut entries = Vec::with_capacity(zip_handle.len());
+		let mut stats = ArchiveStats::default();
+		for i in 0..zip_handle.len() {
+			let entry = zip_handle.by_index_raw(i)?;
+			stats.add(&entry);
+			entries.push((entry.name().to_string(), entry.is_dir(), entry.size()));
+		}
+		Ok((zip_handle, entries, stats))
+	}).await?.map_err(|err| anyhow!("Cannot open {}: {}", path, err))?;
+
+	let mut file_db = BTreeMap::new();
+	for (i, (name, is_dir, size)) in entries.into_iter().enumerate() {
+		let Some(name) = names.check(path, &name)? else { continue; };
+		let previous = file_db.insert(name, FileIndex::new(true, is_dir, Some(path.to_string()), Some(i), size));
+		names.record(previous.as_ref(), is_dir);
+	}
+	Ok((ArchiveHandle::Zip(zip_handle), file_db, stats))
+}
+
+async fn index_archive_file(path: &str, target: &IndexTarget, names: &EntryNameCheck) -> Result<()> {
+	let IndexTarget { file_db, zip_handles, archive_stats, .. } = target;
+	let begin_time = Instant::now();
+	let (zip_handle, entries, stats) = read_archive_file(path, names).await?;
+	file_db.lock().unwrap().extend(entries);
+	archive_stats.lock().unwrap().insert(path.to_string(), stats);
+	zip_handles.lock().unwrap().insert(path.to_string(), zip_handle);
+	info!("Archive {} indexed. Time: {}ms.", path, (Instant::now() - begin_time).as_millis());
+	Ok(())
+}
+
+// Lines of an archive list: blank lines and those starting with '#' are skipped, the others are a zip
+// path, mounted at the root, or PREFIX=PATH to mount it under PREFIX
+fn parse_archive_list(content: &str) -> Vec<(String, String)> {
+	content.lines()
+		.map(str::trim)
+		.filter(|x| !x.is_empty() && !x.starts_with('#'))
+		.map(|x| match x.split_once('=') {
+			Some((prefix, path)) => (prefix.trim().trim_matches('/').to_string(), path.trim().to_string()),
+			None => (String::new(), x.to_string())
+		})
+		.collect()
+}
+
+// Indexes exactly the zips of --archive-list. Missing and unreadable ones are reported and skipped,
+// so that one stale line does not keep the others from being served.
+async fn index_archive_list(list: &str, target: &IndexTarget, names: &EntryNameCheck) -> Result<()> {
+	let content = fs::read_to_string(list).map_err(|err| anyhow!("Cannot read archive list {}: {}", list, err))?;
+	let IndexTarget { file_db, zip_handles, archive_stats, .. } = target;
+	let (mut indexed, mut skipped) = (0usize, 0usize);
+	for (prefix, path) in parse_archive_list(&content) {
+		if !Path::new(&path).is_file() {
+			warn!("Skipping {} from {}, it is not a file", path, list);
+			skipped += 1;
+			continue;
+		}
+		if zip_handles.lock().unwrap().contains_key(&path) {
+			warn!("Skipping {} from {}, it is listed more than once", path, list);
+			skipped += 1;
+			continue;
+		}
+		let begin_time = Instant::now();
+		let (zip_handle, entries, stats) = match read_archive_file(&path, names).await {
+			Ok(archive) => archive,
+			// Under --strict an unsafe entry name has to stop the server, so any failure does
+			Err(err) if names.strict => return Err(err),
+			Err(err) => {
+				warn!("Skipping {} from {}: {}", path, list, err);
+				skipped += 1;
+				continue;
+			}
+		};
+		{
+			let mut file_db_lock = file_db.lock().unwrap();
+			// The prefix directories exist nowhere else, they are listed like those of the served directory
+			let mut dir = String::new();
+			for component in prefix.split('/').filter(|x| !x.is_empty()) {
+				if !dir.is_empty() { dir.push('/'); }
+				dir.push_str(component);
+				file_db_lock.entry(dir.clone()).or_insert_with(|| FileIndex::new(false, true, None, None, 0));
+			}
+			for (name, index) in entries {
+				let key = if dir.is_empty() { name } else { format!("{}/{}", dir, name) };
+				names.record(file_db_lock.get(&key), index.is_dir());
+				file_db_lock.entry(key).or_insert(index);
+			}
+		}
+		archive_stats.lock().unwrap().insert(path.clone(), stats);
+		zip_handles.lock().unwrap().insert(path.clone(), zip_handle);
+		indexed += 1;
+		info!("Archive {} indexed. Time: {}ms.", path, (Instant::now() - begin_time).as_millis());
+	}
+	if skipped > 0 { warn!("{} of the archives listed in {} were skipped.", skipped, list); }
+	if indexed == 0 { warn!("No archive of {} could be indexed.", list); }
+	Ok(())
+}
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Polls the archive served by --file. Once it has changed and then kept its size and mtime for a whole
+// interval, it is reindexed aside and swapped in, so the old content is served until then.
+async fn watch_archive_file(path: String, strict: bool, preload: bool) {
+	let stamp = |path: &str| fs::metadata(path).and_then(|x| Ok((x.len(), x.modified()?))).ok();
+	let mut current = stamp(&path);
+	loop {
+		tokio::time::sleep(WATCH_INTERVAL).await;
+		let changed = stamp(&path);
+		// A missing file is likely being replaced, the old content is kept meanwhile
+		if changed.is_none() || changed == current { continue; }
+		tokio::time::sleep(WATCH_INTERVAL).await;
+		if stamp(&path) != changed { continue; }
+		// Not retried until the next change, as a broken archive stays broken
+		current = changed;
+		if let Err(err) = reload_archive_file(&path, strict, preload).await {
+			warn!("Cannot reindex {}, still serving its previous content: {}", path, err);
+		}
+	}
+}
+
+async fn reload_archive_file(path: &str, strict: bool, preload: bool) -> Result<()> {
+	let begin_time = Instant::now();
+	let names = EntryNameCheck::new(strict);
+	let (zip_handle, entries, stats) = read_archive_file(path, &names).await?;
+	names.report();
+
+	let file_db;
+	let zip_handles;
+	let archive_stats;
+	let entry_cache;
+	let ignore_case;
+	let file_db_lowercase;
+	{
+		let ctrl = global().lock().await;
+		file_db = ctrl.file_db.clone();
+		zip_handles = ctrl.zip_handles.clone();
+		archive_stats = ctrl.archive_stats.clone();
+		entry_cache = ctrl.entry_cache.clone();
+		ignore_case = ctrl.ignore_case;
+		file_db_lowercase = ctrl.file_db_lowercase.clone();
+	}
+	let count = entries.len();
+	{
+		// Swapped together, so that indices are never looked up in the other archive
+		let mut zip_handles = zip_handles.lock().unwrap();
+		**file_db.lock().unwrap() = entries;
+		zip_handles.insert(path.to_string(), zip_handle);
+	}
+	archive_stats.lock().unwrap().insert(path.to_string(), stats);
+	{
+		let mut entry_cache = entry_cache.lock().unwrap();
+		let budget = entry_cache.budget;
+		**entry_cache = EntryCache { budget, ..Default::default() };
+	}
+	if ignore_case {
+		file_db_lowercase.lock().unwrap().clear();
+		create_lowercase_db(&file_db, &file_db_lowercase);
+	}
+	info!("{} changed, reindexed {} entries. Time: {}ms.", path, count, (Instant::now() - begin_time).as_millis());
+	if preload { preload_entries(&file_db).await?; }
+	Ok(())
+}
+
+// (size, mtime) of whatever a reindex could pick up: the directories walked, whose mtime changes when
+// a file is added, removed or renamed in them, and the local archives indexed, or the list naming them
+async fn content_stamp(dir: &Path, index_options: &IndexOptions) -> Result<BTreeMap<String, (u64, u128)>> {
+	let stamp_of = |path: &Path| -> Option<(u64, u128)> {
+		let meta = fs::metadata(path).ok()?;
+		Some((meta.len(), meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_nanos()))
+	};
+	let stamps = arc_ptr_create!(BTreeMap::new());
+	let served = index_options.file.as_ref().or(index_options.archive_list.as_ref());
+	match served {
+		Some(path) => { stamps.lock().unwrap().insert(path.clone(), stamp_of(Path::new(path))); },
+		None => {
+			stamps.lock().unwrap().insert(dir.to_string_lossy().into_owned(), stamp_of(dir));
+			iter_dir(dir, index_options.depth, &mut |_, x| {
+				let stamps = stamps.clone();
+				async move {
+					if x.is_dir() { stamps.lock().unwrap().insert(x.to_string_lossy().into_owned(), stamp_of(&x)); }
+					Ok(())
+				}
+			}, &mut DirWalk::new(index_options.follow_symlinks)).await?;
+		}
+	}
+	let zip_handles = global().lock().await.zip_handles.clone();
+	// Nested archives change with their parent, remote ones are not stamped
+	let archives: Vec<String> = zip_handles.lock().unwrap().keys().filter(|x| Path::new(x).is_file()).cloned().collect();
+	let mut stamps = stamps.lock().unwrap();
+	for path in archives {
+		let stamp = stamp_of(Path::new(&path));
+		stamps.insert(path, stamp);
+	}
+	Ok(stamps.iter().filter_map(|(k, v)| Some((k.clone(), (*v)?))).collect())
+}
+
+// Indexes everything again into a fresh file database and swaps it in once it is complete, so that
+// requests meanwhile are answered from the previous one
+async fn reindex(dir: &Path, index_options: &IndexOptions) -> Result<()> {
+	let begin_time = Instant::now();
+	let fresh = IndexTarget::fresh();
+	index_files(dir, index_options, &fresh).await?;
+
+	let target = IndexTarget::global().await;
+	let entry_cache;
+	let ignore_case;
+	let file_db_lowercase;
+	{
+		let ctrl = global().lock().await;
+		entry_cache = ctrl.entry_cache.clone();
+		ignore_case = ctrl.ignore_case;
+		file_db_lowercase = ctrl.file_db_lowercase.clone();
+	}
+	let (before, after);
+	{
+		// Swapped together, so that indices are never looked up in the other archive
+		let mut zip_handles = target.zip_handles.lock().unwrap();
+		let mut file_db = target.file_db.lock().unwrap();
+		before = file_db.len();
+		**file_db = std::mem::take(&mut **fresh.file_db.lock().unwrap());
+		**zip_handles = std::mem::take(&mut **fresh.zip_handles.lock().unwrap());
+		after = file_db.len();
+	}
+	**target.archive_stats.lock().unwrap() = std::mem::take(&mut **fresh.archive_stats.lock().unwrap());
+	**target.content_hashes.lock().unwrap() = std::mem::take(&mut **fresh.content_hashes.lock().unwrap());
+	{
+		let mut entry_cache = entry_cache.lock().unwrap();
+		let budget = entry_cache.budget;
+		**entry_cache = EntryCache { budget, ..Default::default() };
+	}
+	if ignore_case {
+		file_db_lowercase.lock().unwrap().clear();
+		create_lowercase_db(&target.file_db, &file_db_lowercase);
+	}
+	info!("Reindexed {} paths, {} before. Time: {}ms.", after, before, (Instant::now() - begin_time).as_millis());
+	if index_options.preload { preload_entries(&target.file_db).await?; }
+	Ok(())
+}
+
+// Reindexes on SIGHUP, unless no directory or archive changed since the last index. Remote archives
+// cannot be checked, so with any of them every SIGHUP reindexes.
+#[cfg(unix)]
+async fn reindex_on_hangup(dir: PathBuf, index_options: IndexOptions) {
+	use tokio::signal::unix::{signal, SignalKind};
+	let mut hangups = match signal(SignalKind::hangup()) {
+		Ok(hangups) => hangups,
+		Err(err) => {
+			warn!("Cannot listen for SIGHUP, reindexing on it is disabled: {}", err);
+			return;
+		}
+	};
+	let mut current = content_stamp(&dir, &index_options).await.ok();
+	while hangups.recv().await.is_some() {
+		let changed = content_stamp(&dir, &index_options).await.ok();
+		if changed.is_some() && changed == current && index_options.remote.is_empty() {
+			info!("SIGHUP received, nothing changed since the last index.");
+			continue;
+		}
+		info!("SIGHUP received, reindexing...");
+		match reindex(&dir, &index_options).await {
+			// Stamped before reindexing, so that changes made meanwhile are picked up by the next SIGHUP
+			Ok(()) => current = changed,
+			Err(err) => warn!("Reindexing failed, still serving the previous index: {}", err)
+		}
+	}
+}
+
+const MAX_NESTED_ZIP_DEPTH: usize = 4;
+// Inner archives are held in memory whole, so without --max-entry-size larger ones are not browsed
+const MAX_NESTED_ZIP_SIZE: u64 = 256 * 1024 * 1024;
+
+// Opens the zip files found inside archives and indexes their content under the entry path,
+// which then becomes a directory. Inner archives are kept in memory.
+async fn index_nested_zips(target: &IndexTarget, names: &EntryNameCheck) -> Result<()> {
+	let IndexTarget { file_db, zip_handles, archive_stats, .. } = target;
+	let max_nested_size = global().lock().await.max_entry_size.unwrap_or(MAX_NESTED_ZIP_SIZE);
+
+	let mut pending: Vec<String> = file_db.lock().unwrap().iter()
+		.filter(|(k, v)| v.is_inside_zip() && k.to_lowercase().ends_with(".zip"))
+		.map(|(k, _)| k.clone())
+		.collect();
+
+	for _ in 0..MAX_NESTED_ZIP_DEPTH {
+		let mut next = vec![];
+		for key in pending {
+			let Some(FileIndex(_, Some(zip_path), Some(zip_index), _)) = file_db.lock().unwrap().get(&key).cloned() else { continue; };
+			let nested_path = format!("{}#{}", zip_path, zip_index);
+			if zip_handles.lock().unwrap().contains_key(&nested_path) { continue; }
+
+			let Some(mut zip_handle) = zip_handles.lock().unwrap().get(&zip_path).cloned() else { continue; };
+			// Decompressing the inner archive and reading its entries block
+			let opened = tokio::task::spawn_blocking(move || -> Result<Option<_>> {
+				let bytes = zip_handle.read_entry(zip_index, Some(max_nested_size))?;
+				if bytes.is_empty() { return Ok(None); }
+				let mut nested_handle = ZipArchive::new(Cursor::new(Arc::<[u8]>::from(bytes)))?;
+				let mut entries = Vec::with_capacity(nested_handle.len());
+				let mut stats = ArchiveStats::default();
+				for i in 0..nested_handle.len() {
+					let Ok(entry) = nested_handle.by_index(i) else { continue; };
+					stats.add(&entry);
+					entries.push((i, entry.name().to_string(), entry.is_dir(), entry.size()));
+				}
+				Ok(Some((nested_handle, entries, stats)))
+			}).await?;
+			let (nested_handle, entries, stats) = match opened {
+				Ok(Some(opened)) => opened,
+				Ok(None) => continue,
+				Err(err) => {
+					warn!("Cannot open nested archive {}: {}", key, err);
+					continue;
+				}
+			};
+
+			let mut file_db_lock = file_db.lock().unwrap();
+			for (i, name, is_dir, size) in entries {
+				let Some(entry_name) = names.check(&key, &name)? else { continue; };
+				let entry_key = format!("{}/{}", key, entry_name);
+				if !is_dir && entry_key.to_lowercase().ends_with(".zip") {
+					next.push(entry_key.clone());
+				}
+				file_db_lock.insert(entry_key, FileIndex::new(true, is_dir, Some(nested_path.clone()), Some(i), size));
+			}
+			file_db_lock.insert(key, FileIndex::new(true, true, Some(zip_path), Some(zip_index), 0));
+			archive_stats.lock().unwrap().insert(nested_path.clone(), stats);
+			zip_handles.lock().unwrap().insert(nested_path, ArchiveHandle::NestedZip(nested_handle));
+		}
+		pending = next;
+	}
+	Ok(())
+}
+
+// Decompresses archive entries into memory until the budget runs out. Entries that do not fit
+// are still read from their archive on every request.
+async fn preload_entries(file_db: &ArcFileMapPtr) -> Result<()> {
+	let zip_handles;
+	let max_entry_size;
+	let entry_cache;
+	{
+		let ctrl = global().lock().await;
+		zip_handles = ctrl.zip_handles.clone();
+		max_entry_size = ctrl.max_entry_size;
+		entry_cache = ctrl.entry_cache.clone();
+	}
+	let entries: Vec<(String, usize, u64)> = file_db.lock().unwrap().values()
+		.filter(|x| x.is_inside_zip() && x.is_file())
+		.filter_map(|x| Some((x.1.clone()?, x.2?, x.size())))
+		.collect();
+
+	let begin_time = Instant::now();
+	let (loaded, loaded_bytes, skipped) = tokio::task::spawn_blocking(move || {
+		let (mut loaded, mut loaded_bytes, mut skipped) = (0usize, 0u64, 0usize);
+		for (zip_path, zip_index, size) in entries {
+			if !entry_cache.lock().unwrap().fits(size) {
+				skipped += 1;
+				continue;
+			}
+			let zip_handle = zip_handles.lock().unwrap().get(&zip_path).cloned();
+			let data = zip_handle.map(|mut x| x.read_entry(zip_index, max_entry_size));
+			match data {
+				Some(Ok(data)) => {
+					let len = data.len() as u64;
+					if entry_cache.lock().unwrap().insert(zip_path, zip_index, data) {
+						loaded += 1;
+						loaded_bytes += len;
+					}
+					else { skipped += 1; }
+				},
+				// Reported here rather than counted as not fitting, the requests for it fail anyway
+				Some(Err(err)) if err.is::<EntryCorrupt>() => error!("{}: {}", zip_path, err),
+				_ => skipped += 1
+			}
+		}
+		(loaded, loaded_bytes, skipped)
+	}).await?;
+
+	info!("Preloaded {} entries ({}) into memory. Time: {}ms.", loaded, human_size(loaded_bytes), (Instant::now() - begin_time).as_millis());
+	if skipped > 0 { info!("{} entries did not fit in the cache size and are read on demand.", skipped); }
+	Ok(())
+}
+
+fn report_duplicates(content_hashes: &ArcContentHashMapPtr) {
+	let mut groups = 0usize;
+	let mut copies = 0usize;
+	let mut redundant_size = 0u64;
+	for (key, entries) in content_hashes.lock().unwrap().iter().filter(|(_, v)| v.len() > 1) {
+		let size = key.split('-').next().and_then(|x| x.parse::<u64>().ok()).unwrap_or(0);
+		groups += 1;
+		copies += entries.len() - 1;
+		redundant_size += size * (entries.len() - 1) as u64;
+	}
+	info!("Found {} redundant copies of {} distinct entries, {} bytes of duplicated content.", copies, groups, redundant_size);
+}
+
+// Setting cancelled stops the indexing threads at their next entry, once the caller gave up waiting on them
+async fn create_file_db(dir: &str, index_options: &IndexOptions, target: &IndexTarget, names: Arc<EntryNameCheck>, cancelled: Arc<AtomicBool>) -> Result<()> {
+	info!("Creating file database...");
+
+	let begin_time = Instant::now();
+
+	let filter = Arc::new(ArchiveFilter {
+		root: PathBuf::from(dir),
+		include: index_options.include.clone(),
+		exclude: index_options.exclude.clone(),
+		skipped: AtomicUsize::new(0)
+	});
+	iter_dir(Path::new(dir), index_options.depth, &mut |_, x| iter_dir_cb(x, filter.clone(), index_options.open_retries, target.clone()), &mut DirWalk::new(index_options.follow_symlinks)).await?;
+	let skipped = filter.skipped.load(Ordering::Relaxed);
+	if skipped > 0 { info!("Skipped {} archive(s) excluded by the filters.", skipped); }
+
+	let IndexTarget { file_db, archive_stats, content_hashes, .. } = target;
+
+	let mut cache_stamp = None;
+	if let Some(cache_path) = &index_options.index_cache {
+		let stamp = index_cache_stamp(dir, index_options, target)?;
+		if let Some(cache) = load_index_cache(cache_path, &stamp) {
+			file_db.lock().unwrap().extend(cache.entries);
+			archive_stats.lock().unwrap().extend(cache.archive_stats);
+			content_hashes.lock().unwrap().extend(cache.content_hashes);
+			if index_options.find_duplicates { report_duplicates(content_hashes); }
+			// Nested archives live in memory only, so they have to be opened again
+			if index_options.recurse_zips { index_nested_zips(target, &names).await?; }
+			info!("File database loaded from {}. Time: {}ms.", cache_path, (Instant::now() - begin_time).as_millis());
+			return Ok(());
+		}
+		info!("Index cache {} is missing or stale, reindexing.", cache_path);
+		cache_stamp = Some(stamp);
+	}
+
+	let allows = |x: &Path| filter.allows(x);
+	let open_limits = OpenLimits {
+		retries: index_options.open_retries,
+		max_open: index_options.index_open_files.unwrap_or(index_options.core_num)
+	};
+	let index_join_handle;
+	{
+		let file_db_clone = file_db.clone();
+		let archive_stats = archive_stats.clone();
+		let content_hashes = content_hashes.clone();
+		let find_duplicates = index_options.find_duplicates;
+		let flatten = index_options.flatten;
+		let parent_dir = dir.to_string();
+		let names = names.clone();
+		index_join_handle = index_zip_dir_filtered(dir, index_options.core_num, index_options.depth, DirWalk::new(index_options.follow_symlinks), &allows, open_limits, ZipCallback::with_progress(move |x, i, f| {
+			if cancelled.load(Ordering::Relaxed) { bail!("Indexing cancelled"); }
+			archive_stats.lock().unwrap().entry(f.to_string()).or_default().add(x);
+			// Matching size and CRC32 is taken as identical content, which is cheap but not proof
+			if let Some(crc) = x.crc32().filter(|_| find_duplicates && !x.is_dir() && x.size() > 0) {
+				content_hashes.lock().unwrap().entry(format!("{}-{:08x}", x.size(), crc)).or_default().push((f.to_string(), x.name().to_string()));
+			}
+			// Whoever comes first gets inserted first
+			let Some(xname) = names.check(f, x.name())? else { return Ok(()); };
+			if flatten {
+				// Directories have nothing left to hold
+				if x.is_dir() { return Ok(()); }
+				let base_name = xname.rsplit('/').next().unwrap_or(&xname).to_string();
+				let previous = file_db_clone.lock().unwrap().insert(base_name, FileIndex::new(true, false, Some(f.to_string()), Some(i), x.size()));
+				names.record(previous.as_ref(), false);
+				return Ok(());
+			}
+			let parent_dir = Path::new(&parent_dir);
+			let zip_file_dir = Path::new(&f);
+			let mut relative_path_buf = zip_file_dir.strip_prefix(parent_dir)?.to_path_buf();
+			relative_path_buf.pop();
+			relative_path_buf.push(xname);
+
+			let file_path_str = relative_path_buf.to_string_lossy().replace('\\', "/");
+			let previous = file_db_clone.lock().unwrap().insert(file_path_str, FileIndex::new(true, x.is_dir(), Some(f.to_string()), Some(i), if x.is_dir() { 0 } else { x.size() }));
+			names.record(previous.as_ref(), x.is_dir());
+			Ok(())
+		}, print_progress_bar));
+	}
+
+	index_join_handle.await?;
+	if index_options.recurse_zips { index_nested_zips(target, &names).await?; }
+	info!("File database created. Time: {}ms.", (Instant::now() - begin_time).as_millis());
+	if index_options.find_duplicates { report_duplicates(content_hashes); }
+
+	if let (Some(cache_path), Some(stamp)) = (&index_options.index_cache, cache_stamp) {
+		if let Err(err) = save_index_cache(cache_path, stamp, file_db, archive_stats, content_hashes) {
+			warn!("Cannot write index cache {}: {}", cache_path, err);
+		}
+	}
+
+	Ok(())
+}
+
+// Every body but Download's has a known size, which Rocket sends as Content-Length, and the wrapping
+// variants only add headers. Download is built while it is sent, so it goes out chunked.
+#[allow(dead_code)]
+enum GetResponse {
+    StringContent(ContentType, String),
+    Bytes(ContentType, Vec<u8>),
+    File(Option<NamedFile>),
+	// A precompressed sibling: (Content-Encoding, content type of the original file, response)
+	Encoded(&'static str, ContentType, Box<GetResponse>),
+	// An archive built while it is sent: (content type, file name, body)
+	Download(ContentType, String, DuplexStream),
+	// Served as octet-stream whatever the extension says
+	Raw(Box<GetResponse>),
+	// A file whose ETag and Last-Modified are known, which also makes byte ranges of it available
+	Validated(Validators, Box<GetResponse>),
+	// Names the methods served, answering OPTIONS (204) or refusing another method (405)
+	Allow(Status),
+	Error(Status)
+}
+
+macro_rules! response_build {
+	($content_type:expr, $body:expr) => {
+		rocket::Response::build()
+			.header($content_type)
+			.sized_body($body.len(), std::io::Cursor::new($body))
+			.ok()
+	};
+}
+
+impl<'a> Responder<'a, 'static> for GetResponse {
+	fn respond_to(self, request: &'a Request<'_>) -> response::Result<'static> {
+		match self {
+			GetResponse::StringContent(content_type, body) => response_build!(content_type, body),
+			GetResponse::Bytes(content_type, body) => response_build!(content_type, body),
+			GetResponse::File(file_option) => {
+				match file_option {
+					Some(file) => file.respond_to(request),
+					None => error_response(Status::NotFound, request)
+				}
+			},
+			GetResponse::Encoded(encoding, content_type, response) => {
+				let mut response = response.respond_to(request)?;
+				if response.status() == Status::Ok {
+					response.set_header(content_type);
+					response.set_raw_header("Content-Encoding", encoding);
+					response.set_raw_header("Vary", "Accept-Encoding");
+				}
+				Ok(response)
+			},
+			GetResponse::Raw(response) => {
+				let mut response = response.respond_to(request)?;
+				if response.status() == Status::Ok {
+					response.set_header(ContentType::Bytes);
+				}
+				Ok(response)
+			},
+			GetResponse::Validated(validators, response) => {
+				let path = match response.as_ref() {
+					GetResponse::File(Some(file)) => Some(file.path().to_path_buf()),
+					_ => None
+				};
+				let mut response = response.respond_to(request)?;
+				if response.status() == Status::Ok {
+					response.set_raw_header("ETag", validators.etag);
+					response.set_raw_header("Last-Modified", httpdate::fmt_http_date(validators.last_modified));
+					response.set_raw_header("Accept-Ranges", "bytes");
+					if let Some(path) = path { serve_file_range(request, &mut response, &path); }
+				}
+				Ok(response)
+			},
+			GetResponse::Download(content_type, file_name, body) => {
+				// No size, hence Transfer-Encoding: chunked
+				Response::build()
+					.header(content_type)
+					.raw_header("Content-Disposition", format!("attachment; filename=\"{}\"", file_name.replace('"', "_")))
+					.streamed_body(body)
+					.ok()
+			},
+			GetResponse::Allow(status) => {
+				let mut response = if status == Status::NoContent { Response::build().status(status).finalize() }
+					else { error_response(status, request)? };
+				response.set_raw_header("Allow", "GET, HEAD, OPTIONS");
+				Ok(response)
+			},
+			GetResponse::Error(status) => error_response(status, request)
+		}
+	}
+}
+
+// A JSON object for clients asking for JSON, a minimal HTML page for everyone else
+fn error_response(status: Status, request: &Request<'_>) -> response::Result<'static> {
+	let path = request.uri().path().percent_decode_lossy().into_owned();
+	let reason = status.reason_lossy();
+	let (content_type, body) = if request.accept().is_some_and(|x| x.media_types().any(|x| x.is_json())) {
+		(ContentType::JSON, serde_json::json!({ "error": reason, "status": status.code, "path": path }).to_string())
+	} else {
+		(ContentType::HTML, format!(r#"<!DOCTYPE html><html><head><meta charset="utf-8"><title>{} {}</title></head><body><h1>{} {}</h1><p>{}</p></body></html>"#,
+			status.code, reason, status.code, reason, html_escape(&path)))
+	};
+	Response::build()
+		.status(status)
+		.header(content_type)
+		.sized_body(body.len(), Cursor::new(body))
+		.ok()
+}
+
+// Returned by read_file_from_zip when max_concurrent_reads entries are already being read
+#[derive(Debug)]
+struct ReadsBusy;
+
+impl fmt::Display for ReadsBusy {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Too many entries being read at once")
+	}
+}
+
+impl std::error::Error for ReadsBusy {}
+
+async fn read_file_from_zip(zip_path: &String, zip_index: usize) -> Result<Vec<u8>> {
+	let zip_handles;
+	let max_entry_size;
+	let entry_cache;
+	let read_permits;
+	{
+		let ctrl = global().lock().await;
+		zip_handles = ctrl.zip_handles.clone();
+		max_entry_size = Some(ctrl.max_entry_size.unwrap_or(u64::MAX).min(ctrl.max_request_bytes));
+		entry_cache = ctrl.entry_cache.clone();
+		read_permits = ctrl.read_permits.clone();
+	}
+	if let Some(data) = entry_cache.lock().unwrap().get(zip_path, zip_index) {
+		return Ok(data.clone());
+	}
+	// Cached entries cost nothing to serve, so only reads from the archives take a permit
+	let _permit = match read_permits {
+		Some(read_permits) => Some(read_permits.try_acquire_owned().map_err(|_| ReadsBusy)?),
+		None => None
+	};
+	// A stale index may still point to an archive a reindex dropped. The read gets a handle of its
+	// own, so that it does not hold zip_handles while decompressing.
+	let mut zip_handle = zip_handles.lock().unwrap().get(zip_path).cloned().ok_or_else(|| anyhow!("{} is not an indexed archive", zip_path))?;
+	tokio::task::spawn_blocking(move || zip_handle.read_entry(zip_index, max_entry_size)).await?
+		.with_context(|| format!("Cannot read entry {} of {}", zip_index, zip_path))
+}
+
+// Indexes the zip alone like --file does and gives the entry cache its budget, for bench. Returns
+// every file entry as (archive path, entry index).
+pub(super) async fn load_bench_archive(file: &str, cache_size: u64) -> Result<Vec<(String, usize)>> {
+	global().lock().await.entry_cache.lock().unwrap().budget = cache_size;
+	let target = IndexTarget::global().await;
+	index_archive_file(file, &target, &EntryNameCheck::new(false)).await?;
+	let entries = target.file_db.lock().unwrap().values()
+		.filter(|x| x.is_file())
+		.filter_map(|x| Some((x.1.clone()?, x.2?)))
+		.collect();
+	Ok(entries)
+}
+
+// Gathers the children of every directory loaded by load_bench_archive the way listings do, and by
+// scanning the whole index as they used to, for bench. Returns the index size and, per directory,
+// the nanoseconds taken by either way.
+pub(super) async fn bench_list() -> (usize, Vec<(u128, u128)>) {
+	let file_db = global().lock().await.file_db.clone();
+	let file_db = file_db.lock().unwrap();
+	let dirs: Vec<&str> = iter::once("").chain(file_db.iter().filter(|(_, v)| v.is_dir()).map(|(k, _)| &k[..])).collect();
+	let times = dirs.into_iter().map(|dir| {
+		let begin = Instant::now();
+		let listed = children(&file_db, dir).count();
+		let range = begin.elapsed().as_nanos();
+		let begin = Instant::now();
+		let prefix = if dir.is_empty() { String::new() } else { format!("{}/", dir) };
+		let scanned = file_db.keys().filter(|k| k.strip_prefix(&prefix).is_some_and(|x| !x.is_empty() && !x.contains('/'))).count();
+		let scan = begin.elapsed().as_nanos();
+		debug_assert_eq!(listed, scanned);
+		(range, scan)
+	}).collect();
+	(file_db.len(), times)
+}
+
+pub(super) async fn preload_bench_archive() -> Result<()> {
+	let file_db = global().lock().await.file_db.clone();
+	preload_entries(&file_db).await
+}
+
+// Reads an entry the way a request does, returning how long it took and whether the entry cache had it
+pub(super) async fn bench_read(zip_path: &String, zip_index: usize) -> Result<(Duration, bool)> {
+	let entry_cache = global().lock().await.entry_cache.clone();
+	let cached = entry_cache.lock().unwrap().get(zip_path, zip_index).is_some();
+	let begin = Instant::now();
+	read_file_from_zip(zip_path, zip_index).await?;
+	Ok((begin.elapsed(), cached))
+}
+
+fn insert_base_tag(data: &mut Vec<u8>, base_href: &str) {
+	let mut content = String::from_utf8(data.clone()).unwrap();
+    let base_tag = format!("<base href=\"{}\">", href_path(base_href));
+    if let Some(head_end) = content.find("</head>") {
+        content.insert_str(head_end, &base_tag);
+        *data = content.into_bytes();
+    }
+}
+
+struct Validators {
+	etag: String,
+	last_modified: SystemTime
+}
+
+// Derived from the size and modification time of the file on disk, plus the entry index for
+// archive entries. Remote and nested archives have no such file, so they get none.
+async fn file_validators(path: &Path, zip_index: Option<usize>) -> Option<Validators> {
+	let meta = tokio::fs::metadata(path).await.ok().filter(|x| x.is_file())?;
+	let last_modified = meta.modified().ok()?;
+	let mtime = last_modified.duration_since(UNIX_EPOCH).ok()?.as_secs();
+	let etag = match zip_index {
+		Some(zip_index) => format!("\"{:x}-{:x}-{:x}\"", mtime, meta.len(), zip_index),
+		None => format!("\"{:x}-{:x}\"", mtime, meta.len())
+	};
+	Some(Validators { etag, last_modified })
+}
+
+fn validated(response: GetResponse, validators: Option<Validators>) -> GetResponse {
+	match validators {
+		Some(validators) => GetResponse::Validated(validators, Box::new(response)),
+		None => response
+	}
+}
+
+// Parses a Range header against a body of len bytes. None means the header is ignored (malformed
+// or asking for several ranges), Some(None) that the range lies beyond the body.
+fn parse_byte_range(header: &str, len: u64) -> Option<Option<(u64, u64)>> {
+	let spec = header.trim().strip_prefix("bytes=")?.trim();
+	if spec.contains(',') { return None; }
+	let (first, last) = spec.split_once('-')?;
+	let (first, last) = (first.trim(), last.trim());
+	if first.is_empty() {
+		let suffix = last.parse::<u64>().ok()?;
+		if suffix == 0 || len == 0 { return Some(None); }
+		return Some(Some((len - suffix.min(len), len - 1)));
+	}
+	let start = first.parse::<u64>().ok()?;
+	let end = if last.is_empty() { u64::MAX } else { last.parse::<u64>().ok()? };
+	if end < start { return None; }
+	if start >= len { return Some(None); }
+	Some(Some((start, end.min(len - 1))))
+}
+
+// If-Range holds either an ETag, which has to match strongly, or the date the client last saw
+fn if_range_matches(if_range: &str, response: &Response<'_>) -> bool {
+	let if_range = if_range.trim();
+	if if_range.starts_with('"') {
+		return response.headers().get_one("ETag") == Some(if_range);
+	}
+	if if_range.starts_with("W/") { return false; }
+	match (httpdate::parse_http_date(if_range), response.headers().get_one("Last-Modified").map(httpdate::parse_http_date)) {
+		(Ok(date), Some(Ok(last_modified))) => date == last_modified,
+		_ => false
+	}
+}
+
+// Answers a single byte range of responses offering them. A stale If-Range leaves the full body.
+async fn serve_byte_range<'r>(request: &'r Request<'_>, response: &mut Response<'r>) {
+	if request.method() != Method::Get || response.status() != Status::Ok { return; }
+	if response.headers().get_one("Accept-Ranges") != Some("bytes") { return; }
+	let Some(range) = request.headers().get_one("Range") else { return; };
+	if let Some(if_range) = request.headers().get_one("If-Range") {
+		if !if_range_matches(if_range, response) { return; }
+	}
+	let Some(len) = response.body_mut().size().await else { return; };
+	let Some(range) = parse_byte_range(range, len as u64) else { return; };
+	let Some((start, end)) = range else { return range_not_satisfiable(response, len as u64); };
+	let body = match response.body_mut().to_bytes().await {
+		Ok(body) => body,
+		Err(err) => {
+			warn!("Cannot read the body for a range of {}: {}", request.uri(), err);
+			response.set_status(Status::InternalServerError);
+			response.set_sized_body(0, Cursor::new(vec![]));
+			return;
+		}
+	};
+	let part = body[start as usize..=end as usize].to_vec();
+	response.set_status(Status::PartialContent);
+	response.set_raw_header("Content-Range", format!("bytes {}-{}/{}", start, end, len));
+	response.set_sized_body(part.len(), Cursor::new(part));
+}
+
+// The rest of a range read from a file. Rocket only seeks bodies to find their size, which is
+// always given for it.
+struct FileRange(tokio::io::Take<tokio::fs::File>);
+
+impl AsyncRead for FileRange {
+	fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.0).poll_read(cx, buf)
+	}
+}
+
+impl AsyncSeek for FileRange {
+	fn start_seek(self: Pin<&mut Self>, _: SeekFrom) -> io::Result<()> {
+		Err(io::Error::new(io::ErrorKind::Unsupported, "A file range cannot seek"))
+	}
+	fn poll_complete(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<u64>> {
+		Poll::Ready(Err(io::Error::new(io::ErrorKind::Unsupported, "A file range cannot seek")))
+	}
+}
+
+fn range_not_satisfiable(response: &mut Response<'_>, len: u64) {
+	response.set_status(Status::RangeNotSatisfiable);
+	response.set_raw_header("Content-Range", format!("bytes */{}", len));
+	response.set_sized_body(0, Cursor::new(vec![]));
+}
+
+// Answers a byte range of a file on disk by seeking into it. serve_byte_range would read the whole
+// file into memory first, and skips the response once it is no longer 200.
+fn serve_file_range(request: &Request<'_>, response: &mut Response<'_>, path: &Path) {
+	if request.method() != Method::Get { return; }
+	let Some(range) = request.headers().get_one("Range") else { return; };
+	if let Some(if_range) = request.headers().get_one("If-Range") {
+		if !if_range_matches(if_range, response) { return; }
+	}
+	let Ok(len) = fs::metadata(path).map(|x| x.len()) else { return; };
+	let Some(range) = parse_byte_range(range, len) else { return; };
+	let Some((start, end)) = range else { return range_not_satisfiable(response, len); };
+	let file = File::open(path).and_then(|mut file| file.seek(SeekFrom::Start(start)).map(|_| file));
+	match file {
+		Ok(file) => {
+			let size = end - start + 1;
+			response.set_status(Status::PartialContent);
+			response.set_raw_header("Content-Range", format!("bytes {}-{}/{}", start, end, len));
+			response.set_sized_body(size as usize, FileRange(tokio::fs::File::from_std(file).take(size)));
+		},
+		Err(err) => {
+			warn!("Cannot read {} for a range: {}", path.display(), err);
+			response.set_status(Status::InternalServerError);
+			response.set_sized_body(0, Cursor::new(vec![]));
+		}
+	}
+}
+
+// Reads the stylesheets and scripts next to an HTML entry into the entry cache in the background,
+// as the browser asks for them right after the page
+async fn prefetch_siblings(cur_path: &str) {
+	let file_db;
+	let zip_handles;
+	let max_entry_size;
+	let entry_cache;
+	{
+		let ctrl = global().lock().await;
+		if !ctrl.prefetch { return; }
+		file_db = ctrl.file_db.clone();
+		zip_handles = ctrl.zip_handles.clone();
+		max_entry_size = ctrl.max_entry_size;
+		entry_cache = ctrl.entry_cache.clone();
+	}
+	let prefix = match cur_path.rsplit_once('/') {
+		Some((dir, _)) => format!("{}/", dir),
+		None => String::new()
+	};
+	let siblings: Vec<(String, usize, u64)> = {
+		let file_db = file_db.lock().unwrap();
+		let entry_cache = entry_cache.lock().unwrap();
+		file_db.range(prefix.clone()..)
+			.take_while(|(k, _)| k.starts_with(&prefix))
+			.filter(|(k, v)| !k[prefix.len()..].contains('/') && v.is_inside_zip() && v.is_file())
+			.filter(|(k, _)| Path::new(k).extension().is_some_and(|x| x.eq_ignore_ascii_case("css") || x.eq_ignore_ascii_case("js")))
+			.filter_map(|(_, v)| Some((v.1.clone()?, v.2?, v.size())))
+			.filter(|(zip_path, zip_index, _)| entry_cache.get(zip_path, *zip_index).is_none())
+			.collect()
+	};
+	if siblings.is_empty() { return; }
+	let cur_path = cur_path.to_string();
+	tokio::task::spawn_blocking(move || {
+		let mut cached = 0usize;
+		for (zip_path, zip_index, size) in siblings {
+			if !entry_cache.lock().unwrap().fits(size) { continue; }
+			let zip_handle = zip_handles.lock().unwrap().get(&zip_path).cloned();
+			let data = zip_handle.map(|mut x| x.read_entry(zip_index, max_entry_size));
+			if let Some(Ok(data)) = data {
+				if entry_cache.lock().unwrap().insert(zip_path, zip_index, data) { cached += 1; }
+			}
+		}
+		debug!("Prefetched {} entries next to {}.", cached, cur_path);
+	});
+}
+
+macro_rules! response_file_index {
+	($file_index_opt:expr, $file_ext:expr, $cur_path:expr, $auto_index:expr) => {
+		response_file_index!($file_index_opt, $file_ext, $cur_path, $auto_index, |x| x)
+	};
+	// $wrap is applied to every response before returning it
+	($file_index_opt:expr, $file_ext:expr, $cur_path:expr, $auto_index:expr, $wrap:expr) => {
+		if let Some(file_index) = $file_index_opt {
+			match file_index.0 {
+				0x00 => {
+					let root_dir;
+					{
+						let ctrl = global().lock().await;
+						root_dir = ctrl.root_dir.clone();
+					}
+					let path = root_dir.join($cur_path);
+					let validators = file_validators(&path, None).await;
+					return $wrap(validated(GetResponse::File(NamedFile::open(path).await.ok()), validators));
+				},
+				0x01 => {
+					let zip_path = file_index.1.clone().unwrap();
+					let zip_index = file_index.2.clone().unwrap();
+					let ctype;
+					if let Some(ext) = $file_ext {
+						ctype = match ext.to_str().unwrap() {
+							"html" => ContentType::HTML,
+							"js" => ContentType::JavaScript,
+							"css" => ContentType::CSS,
+							_ => ContentType::Bytes
+						}
+					}
+					else {
+						ctype = ContentType::Bytes;
+					}
+					let mut data = match read_file_from_zip(&zip_path, zip_index).await {
+						Ok(data) => data,
+						Err(err) if err.is::<EntryTooLarge>() => return GetResponse::Error(Status::PayloadTooLarge),
+						Err(err) if err.is::<ReadsBusy>() => return GetResponse::Error(Status::ServiceUnavailable),
+						Err(err) => {
+							error!("Cannot serve {}: {:#}", $cur_path, err);
+							return GetResponse::Error(Status::InternalServerError);
+						}
+					};
+					if ctype == ContentType::HTML { prefetch_siblings($cur_path).await; }
+					// With a base tag the body is no longer the entry itself, so it gets no validators
+					let validators = if ctype == ContentType::HTML && $auto_index {
+						insert_base_tag(&mut data, $cur_path);
+						None
+					} else { file_validators(Path::new(&zip_path), Some(zip_index)).await };
+					return $wrap(validated(GetResponse::Bytes(ctype, data), validators));
+				},
+				_ => {}
+			}
+		}
+	};
+}
+
+#[allow(unused)]
+#[derive(Responder)]
+enum RouteResult {
+	GetResponse(GetResponse),
+	Redirect(Redirect),
+	String(String)
+}
+
+#[allow(unused)]
+fn html_redirect_str(uri: String) -> String {
+	format!(r#"<html><head><meta http-equiv="refresh" content="0;url={}" /><title></title></head><body></body></html>"#, uri)
+}
+
+fn html_escape(text: &str) -> String {
+	text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
+
+// Percent-encodes every segment of a path for an href, keeping the separators
+fn href_path(path: &str) -> String {
+	path.split('/').map(|segment| segment.bytes().map(|x| match x {
+		b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => char::from(x).to_string(),
+		_ => format!("%{:02X}", x)
+	}).collect::<String>()).collect::<Vec<_>>().join("/")
+}
+
+fn markdown_to_html(title: &str, markdown: &str) -> String {
+	let options = MdOptions::ENABLE_TABLES | MdOptions::ENABLE_STRIKETHROUGH | MdOptions::ENABLE_TASKLISTS | MdOptions::ENABLE_FOOTNOTES;
+	let mut body = String::new();
+	pulldown_cmark::html::push_html(&mut body, MdParser::new_ext(markdown, options));
+	let title = html_escape(title);
+	format!(r#"<!DOCTYPE html><html><head><meta charset="utf-8"><meta name="viewport" content="width=device-width, initial-scale=1"><title>{}</title><style>body{{max-width:48em;margin:2em auto;padding:0 1em;font-family:sans-serif;line-height:1.6}}pre{{background:#f4f4f4;padding:1em;overflow:auto}}code{{background:#f4f4f4}}table{{border-collapse:collapse}}th,td{{border:1px solid #ccc;padding:.3em .6em}}img{{max-width:100%}}</style></head><body>{}</body></html>"#, title, body)
+}
+
+// Which precompressed variants the client accepts, from the Accept-Encoding header
+#[derive(Default, Clone, Copy)]
+struct AcceptEncoding {
+	gzip: bool,
+	br: bool
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AcceptEncoding {
+	type Error = ();
+
+	async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+		let mut accept = AcceptEncoding::default();
+		for header in request.headers().get("Accept-Encoding") {
+			for coding in header.split(',') {
+				let mut params = coding.split(';').map(|x| x.trim());
+				let name = params.next().unwrap_or_default().to_lowercase();
+				// "q=0" means the coding is explicitly refused
+				let refused = params.any(|x| x.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0));
+				match &name[..] {
+					"gzip" => accept.gzip = !refused,
+					"br" => accept.br = !refused,
+					_ => {}
+				}
+			}
+		}
+		request::Outcome::Success(accept)
+	}
+}
+
+// Finds a "{path}.br" or "{path}.gz" entry the client accepts, preferring brotli
+fn find_precompressed(file_db: &ArcFileMapPtr, cur_path: &str, accept: AcceptEncoding) -> Option<(&'static str, String, FileIndex)> {
+	let file_db = file_db.lock().unwrap();
+	[("br", "br", accept.br), ("gzip", "gz", accept.gzip)].into_iter()
+		.filter(|(_, _, accepted)| *accepted)
+		.find_map(|(encoding, ext, _)| {
+			let path = format!("{}.{}", cur_path, ext);
+			file_db.get(&path).filter(|x| x.0 & 0x10 == 0).map(|x| (encoding, path, x.clone()))
+		})
+}
+
+#[rocket::get("/")]
+async fn landing_route(accept: AcceptEncoding, origin: &Origin<'_>) -> RouteResult {
+	if let Some(archive) = archive_query(origin) {
+		return RouteResult::GetResponse(match archive {
+			Some(format) => with_request_timeout(download_dir(String::new(), format), origin).await,
+			None => GetResponse::Error(Status::BadRequest)
+		});
+	}
+
+	let landing_page;
+	let land_with_path;
+	let landing_redirect;
+	{
+		let ctrl = global().lock().await;
+		landing_page = ctrl.landing_page.clone();
+		land_with_path = ctrl.land_with_path;
+		landing_redirect = ctrl.landing_redirect;
+	}
+	if landing_page.is_empty() {
+		let Some(listing) = listing_query(origin) else { return RouteResult::GetResponse(GetResponse::Error(Status::BadRequest)); };
+		RouteResult::GetResponse(with_request_timeout(serve_path(String::new(), accept, raw_query(origin), listing), origin).await)
+	}
+	else {
+		let a = landing_page.clone();
+		if land_with_path {
+			let uri = uri!(file_route(PathBuf::from(a)));
+			RouteResult::Redirect(match landing_redirect {
+				LandingRedirect::Temporary => Redirect::temporary(uri),
+				LandingRedirect::Permanent => Redirect::moved(uri)
+			})
+			// return RouteResult::String(html_redirect_str(uri!(file_route(PathBuf::from(a))).to_string()));
+		}
+		else {
+			RouteResult::GetResponse(serve_path(a, accept, false, ListingQuery::default()).await)
+		}
+	}
+}
+
+#[derive(Serialize)]
+struct ArchiveInfo {
+	path: String,
+	#[serde(flatten)]
+	stats: ArchiveStats,
+	// Entries actually reachable through the file database, others were shadowed by earlier ones
+	file_db_entries: usize,
+	// Entries whose content also exists elsewhere, with --find-duplicates
+	#[serde(skip_serializing_if = "Option::is_none")]
+	duplicate_entries: Option<usize>
+}
+
+#[rocket::get("/api/zips")]
+async fn zips_route() -> Json<Vec<ArchiveInfo>> {
+	let file_db;
+	let zip_handles;
+	let archive_stats;
+	let content_hashes;
+	{
+		let ctrl = global().lock().await;
+		file_db = ctrl.file_db.clone();
+		zip_handles = ctrl.zip_handles.clone();
+		archive_stats = ctrl.archive_stats.clone();
+		content_hashes = ctrl.content_hashes.clone();
+	}
+	let mut file_db_entries = BTreeMap::<String, usize>::new();
+	for v in file_db.lock().unwrap().values() {
+		if let Some(zip_path) = &v.1 {
+			*file_db_entries.entry(zip_path.clone()).or_default() += 1;
+		}
+	}
+	let content_hashes = content_hashes.lock().unwrap();
+	let duplicate_entries = (!content_hashes.is_empty()).then(|| {
+		let mut duplicate_entries = BTreeMap::<String, usize>::new();
+		for (zip_path, _) in content_hashes.values().filter(|x| x.len() > 1).flatten() {
+			*duplicate_entries.entry(zip_path.clone()).or_default() += 1;
+		}
+		duplicate_entries
+	});
+	let archive_stats = archive_stats.lock().unwrap();
+	let archives = zip_handles.lock().unwrap().keys().map(|path| ArchiveInfo {
+		path: path.clone(),
+		stats: archive_stats.get(path).cloned().unwrap_or_default(),
+		file_db_entries: file_db_entries.get(path).copied().unwrap_or(0),
+		duplicate_entries: duplicate_entries.as_ref().map(|x| x.get(path).copied().unwrap_or(0))
+	}).collect();
+	Json(archives)
+}
+
+#[rocket::post("/<_path..>")]
+async fn post_route(_path: PathBuf) -> &'static str {
+	// Maybe some meilisearch integration in the future?
+	"{}"
+}
+
+// Methods other than GET and HEAD on the paths file_route serves. Ranked after every other route, so
+// that the POST route under --api-path still comes first.
+async fn allowed_methods(origin: &Origin<'_>, status: Status) -> GetResponse {
+	let Some(cur_path) = normalize_request_path(origin.path().segments()) else { return GetResponse::Error(Status::Forbidden); };
+	let cur_path = resolve_path_case(cur_path).await;
+	let file_db = global().lock().await.file_db.clone();
+	let file_db = file_db.lock().unwrap();
+	if cur_path.is_empty() || file_db.contains_key(&cur_path) || has_children(&file_db, &cur_path) {
+		GetResponse::Allow(status)
+	}
+	else { GetResponse::Error(Status::NotFound) }
+}
+
+#[rocket::options("/<_path..>", rank = 100)]
+async fn options_route(_path: PathBuf, origin: &Origin<'_>) -> GetResponse {
+	allowed_methods(origin, Status::NoContent).await
+}
+
+#[rocket::post("/<_path..>", rank = 100)]
+async fn refused_post_route(_path: PathBuf, origin: &Origin<'_>) -> GetResponse {
+	allowed_methods(origin, Status::MethodNotAllowed).await
+}
+
+#[rocket::put("/<_path..>", rank = 100)]
+async fn put_route(_path: PathBuf, origin: &Origin<'_>) -> GetResponse {
+	allowed_methods(origin, Status::MethodNotAllowed).await
+}
+
+#[rocket::delete("/<_path..>", rank = 100)]
+async fn delete_route(_path: PathBuf, origin: &Origin<'_>) -> GetResponse {
+	allowed_methods(origin, Status::MethodNotAllowed).await
+}
+
+#[rocket::patch("/<_path..>", rank = 100)]
+async fn patch_route(_path: PathBuf, origin: &Origin<'_>) -> GetResponse {
+	allowed_methods(origin, Status::MethodNotAllowed).await
+}
+
+// With ignore_case, maps a path missing from file_db to the entry differing only in case.
+// Exact matches always take precedence.
+async fn resolve_path_case(path: String) -> String {
+	let file_db;
+	let file_db_lowercase;
+	{
+		let ctrl = global().lock().await;
+		if !ctrl.ignore_case { return path; }
+		file_db = ctrl.file_db.clone();
+		file_db_lowercase = ctrl.file_db_lowercase.clone();
+	}
+	if file_db.lock().unwrap().contains_key(&path) { return path; }
+	let resolved = file_db_lowercase.lock().unwrap().get(&path.to_lowercase()).cloned();
+	resolved.unwrap_or(path)
+}
+
+fn create_lowercase_db(file_db: &ArcFileMapPtr, file_db_lowercase: &ArcPinnedPtr<BTreeMap<String, String>>) {
+	let mut file_db_lowercase = file_db_lowercase.lock().unwrap();
+	// file_db is sorted, so among differently-cased duplicates the first one in order always wins
+	for k in file_db.lock().unwrap().keys() {
+		file_db_lowercase.entry(k.to_lowercase()).or_insert_with(|| k.clone());
+	}
+}
+
+fn listing_hidden(hide_listing: &[Pattern], cur_path: &str) -> bool {
+	hide_listing.iter().any(|x| x.matches(cur_path))
+}
+
+// Whether --allow-ext lets the file be served. Files without an extension are only served when
+// no extension is listed.
+fn extension_allowed(allow_ext: &[String], path: &str) -> bool {
+	allow_ext.is_empty() || Path::new(path).extension().and_then(|x| x.to_str())
+		.is_some_and(|x| allow_ext.iter().any(|y| x.eq_ignore_ascii_case(y)))
+}
+
+// Follows a listed directory down while its only child is another directory. Hidden directories
+// end the chain, so that their listing is not given away.
+fn collapse_dir(file_db: &BTreeMap<String, FileIndex>, hide_listing: &[Pattern], dir: &str) -> String {
+	let mut dir = dir.to_string();
+	while !listing_hidden(hide_listing, &dir) {
+		let mut children = children(file_db, &dir);
+		match (children.next(), children.next()) {
+			(Some((k, v)), None) if v.is_dir() => dir.clone_from(k),
+			_ => break
+		}
+	}
+	dir
+}
+
+// Entries of file_db directly under the path, in order. Each child directory's subtree is skipped with a
+// single range lookup, so that listing costs as much as the children, not as the whole index.
+fn children<'a>(file_db: &'a BTreeMap<String, FileIndex>, cur_path: &str) -> impl Iterator<Item = (&'a String, &'a FileIndex)> + 'a {
+	let prefix = if cur_path.is_empty() { String::new() } else { format!("{}/", cur_path) };
+	let mut lower = Bound::Included(prefix.clone());
+	iter::from_fn(move || loop {
+		let (k, v) = file_db.range::<str, _>((lower.as_ref().map(|x| &x[..]), Bound::Unbounded)).next()?;
+		let rest = k.strip_prefix(&prefix)?;
+		match rest.find('/') {
+			// Everything under the child sorts before its name followed by '0', which comes right after '/'
+			Some(i) => lower = Bound::Included(format!("{}{}0", prefix, &rest[..i])),
+			None => {
+				lower = Bound::Excluded(k.clone());
+				if !rest.is_empty() { return Some((k, v)); }
+			}
+		}
+	})
+}
+
+// Whether anything in file_db lies under the path, which makes it a directory even without an entry of its own
+fn has_children(file_db: &BTreeMap<String, FileIndex>, cur_path: &str) -> bool {
+	let prefix = format!("{}/", cur_path);
+	file_db.range(prefix.clone()..).next().is_some_and(|(k, _)| k.starts_with(&prefix))
+}
+
+#[derive(Clone, Copy)]
+enum DownloadFormat {
+	Zip,
+	Tar
+}
+
+// "?archive=zip" or "?archive=tar" downloads a directory as a whole. Some(None) is an unknown format.
+fn archive_query(origin: &Origin<'_>) -> Option<Option<DownloadFormat>> {
+	let (_, format) = origin.query()?.segments().find(|(k, _)| *k == "archive")?;
+	Some(match format {
+		"zip" => Some(DownloadFormat::Zip),
+		"tar" => Some(DownloadFormat::Tar),
+		_ => None
+	})
+}
+
+const DOWNLOAD_BUFFER_SIZE: usize = 64 * 1024;
+
+// Blocking write end of a streamed response body
+struct PipeWriter {
+	pipe: DuplexStream,
+	runtime: Handle,
+	// A client that stops reading for this long aborts the download
+	timeout: Option<Duration>
+}
+
+impl PipeWriter {
+	// Takes the fields apart from the pipe, which the future borrows mutably
+	fn block_on<T>(runtime: &Handle, timeout: Option<Duration>, future: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+		match timeout {
+			Some(limit) => runtime.block_on(tokio::time::timeout(limit, future))
+				.unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "Client stopped reading"))),
+			None => runtime.block_on(future)
+		}
+	}
+}
+
+impl Write for PipeWriter {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		PipeWriter::block_on(&self.runtime, self.timeout, self.pipe.write(buf))
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		PipeWriter::block_on(&self.runtime, self.timeout, self.pipe.flush())
+	}
+}
+
+// Streams every file under the directory, on disk or inside archives, as a new archive. Entries
+// of archives are still read into memory one at a time.
+async fn download_dir(cur_path: String, format: DownloadFormat) -> GetResponse {
+	let file_db;
+	let zip_handles;
+	let max_entry_size;
+	let max_request_bytes;
+	let root_dir;
+	let timeout;
+	let hide_listing;
+	let allow_ext;
+	{
+		let ctrl = global().lock().await;
+		file_db = ctrl.file_db.clone();
+		zip_handles = ctrl.zip_handles.clone();
+		max_entry_size = ctrl.max_entry_size;
+		max_request_bytes = ctrl.max_request_bytes;
+		root_dir = ctrl.root_dir.clone();
+		timeout = ctrl.request_timeout;
+		hide_listing = ctrl.hide_listing.clone();
+		allow_ext = ctrl.allow_ext.clone();
+	}
+	// The archive would list a hidden directory all the same, so it is refused, and left out of its parents'
+	if listing_hidden(&hide_listing, &cur_path) { return GetResponse::Error(Status::Forbidden); }
+	let prefix = if cur_path.is_empty() { String::new() } else { format!("{}/", cur_path) };
+	let in_hidden = |k: &str| k[prefix.len()..].match_indices('/').any(|(i, _)| listing_hidden(&hide_listing, &k[..prefix.len() + i]));
+	let entries: Vec<(String, FileIndex)> = {
+		let file_db = file_db.lock().unwrap();
+		if !cur_path.is_empty() && !has_children(&file_db, &cur_path) {
+			return GetResponse::Error(Status::NotFound);
+		}
+		file_db.range(prefix.clone()..)
+			.take_while(|(k, _)| k.starts_with(&prefix))
+			.filter(|(k, v)| v.is_file() && !in_hidden(k) && extension_allowed(&allow_ext, k))
+			.map(|(k, v)| (k.clone(), v.clone()))
+			.collect()
+	};
+
+	info!("Archive download of {}: {} file(s).", if cur_path.is_empty() { "current path" } else { &cur_path }, entries.len());
+
+	let (body, pipe) = tokio::io::duplex(DOWNLOAD_BUFFER_SIZE);
+	let runtime = Handle::current();
+	let log_path = cur_path.clone();
+	tokio::task::spawn_blocking(move || {
+		let writer = BufWriter::new(PipeWriter { pipe, runtime, timeout });
+		let mut zip_writer = None;
+		let mut tar_writer = None;
+		match format {
+			DownloadFormat::Zip => zip_writer = Some(StreamZipWriter::new(writer)),
+			DownloadFormat::Tar => tar_writer = Some(tar::Builder::new(writer))
+		}
+		let result = (|| -> Result<()> {
+			// Entries are read up to what is left of max_request_bytes, so that one expanding far
+			// beyond its declared size stops the download before it is decompressed whole
+			let mut decompressed = 0u64;
+			for (key, file_index) in entries {
+				let (mut reader, size): (Box<dyn Read>, u64) = if file_index.is_inside_zip() {
+					let (Some(zip_path), Some(zip_index)) = (&file_index.1, file_index.2) else { continue; };
+					let remaining = max_request_bytes - decompressed;
+					let over_request = max_entry_size.is_none_or(|x| x > remaining);
+					let zip_handle = zip_handles.lock().unwrap().get(zip_path).cloned();
+					let data = match zip_handle.map(|mut x| x.read_entry(zip_index, Some(max_entry_size.unwrap_or(remaining).min(remaining)))) {
+						Some(Ok(data)) => data,
+						Some(Err(err)) if over_request && err.is::<EntryTooLarge>() => {
+							bail!("More than {} bytes decompressed by the time of {}", max_request_bytes, key);
+						},
+						Some(Err(err)) => {
+							warn!("Leaving {} out of the archive: {}", key, err);
+							continue;
+						},
+						None => continue
+					};
+					let size = data.len() as u64;
+					decompressed += size;
+					(Box::new(Cursor::new(data)), size)
+				}
+				else {
+					let file = File::open(root_dir.join(&key))?;
+					let size = file.metadata()?.len();
+					(Box::new(file), size)
+				};
+				let name = &key[prefix.len()..];
+				if let Some(zip_writer) = &mut zip_writer {
+					zip_writer.add(name, &mut reader)?;
+				}
+				if let Some(tar_writer) = &mut tar_writer {
+					let mut header = tar::Header::new_gnu();
+					header.set_size(size);
+					header.set_mode(0o644);
+					tar_writer.append_data(&mut header, name, reader.take(size))?;
+				}
+			}
+			if let Some(zip_writer) = zip_writer { zip_writer.finish()?.flush()?; }
+			if let Some(tar_writer) = tar_writer { tar_writer.into_inner()?.flush()?; }
+			Ok(())
+		})();
+		if let Err(err) = result {
+			warn!("Archive download of {} stopped: {}", log_path, err);
+		}
+	});
+
+	let (ctype, ext) = match format {
+		DownloadFormat::Zip => (ContentType::ZIP, "zip"),
+		DownloadFormat::Tar => (ContentType::TAR, "tar")
+	};
+	let name = cur_path.rsplit('/').next().filter(|x| !x.is_empty()).unwrap_or("root");
+	GetResponse::Download(ctype, format!("{}.{}", name, ext), body)
+}
+
+// Answers 408 when building the response takes longer than --request-timeout
+async fn with_request_timeout(response: impl Future<Output = GetResponse>, origin: &Origin<'_>) -> GetResponse {
+	let request_timeout;
+	{
+		let ctrl = global().lock().await;
+		request_timeout = ctrl.request_timeout;
+	}
+	match request_timeout {
+		Some(limit) => tokio::time::timeout(limit, response).await.unwrap_or_else(|_| {
+			warn!("Timed out: {}", origin);
+			GetResponse::Error(Status::RequestTimeout)
+		}),
+		None => response.await
+	}
+}
+
+// "?raw" returns the file as stored: octet-stream, no Markdown rendering, precompressed sibling or base tag
+fn raw_query(origin: &Origin<'_>) -> bool {
+	origin.query().is_some_and(|x| x.segments().any(|(k, _)| k == "raw"))
+}
+
+// Page size of a listing asked for by page alone, without --per-page
+const DEFAULT_PER_PAGE: usize = 1000;
+
+// What the query asks of a directory listing
+#[derive(Clone, Copy, Default)]
+struct ListingQuery {
+	// "?dirs_first", "?dirs_first=1" or "?dirs_first=0" overrides --dirs-first
+	dirs_first: Option<bool>,
+	// "?page=N" counts from 1, "?per_page=M" overrides --per-page
+	page: Option<usize>,
+	per_page: Option<usize>
+}
+
+// None when page or per_page is not a positive number
+fn listing_query(origin: &Origin<'_>) -> Option<ListingQuery> {
+	let mut listing = ListingQuery::default();
+	let Some(query) = origin.query() else { return Some(listing); };
+	for (k, v) in query.segments() {
+		match k {
+			"dirs_first" => listing.dirs_first = Some(!matches!(v, "0" | "false")),
+			"page" => listing.page = Some(v.parse::<usize>().ok().filter(|x| *x > 0)?),
+			"per_page" => listing.per_page = Some(v.parse::<usize>().ok().filter(|x| *x > 0)?),
+			_ => {}
+		}
+	}
+	Some(listing)
+}
+
+// "Page N of M" with links to the neighbouring pages, which keep the rest of the listing query
+fn page_links(listing: ListingQuery, page: usize, pages: usize, per_page: usize) -> String {
+	let dirs_first = listing.dirs_first.map(|x| format!("&dirs_first={}", x as u8)).unwrap_or_default();
+	let link = |page: usize, text: &str| format!("<a href=\"?page={}&per_page={}{}\">{}</a>", page, per_page, dirs_first, text);
+	let mut links = format!("Page {} of {}", page, pages);
+	if page > 1 { links.push_str(&format!("  {}", link(page - 1, "prev"))); }
+	if page < pages { links.push_str(&format!("  {}", link(page + 1, "next"))); }
+	links
+}
+
+// "?meta" or "?meta=1" describes an archive entry, or an archive itself, as JSON instead of returning its content
+fn meta_query(origin: &Origin<'_>) -> bool {
+	origin.query().is_some_and(|x| x.segments().any(|(k, v)| k == "meta" && !matches!(v, "0" | "false")))
+}
+
+// Only entries of archives have metadata to show, loose files and inferred directories are 404
+async fn serve_entry_meta(cur_path: String) -> GetResponse {
+	let cur_path = resolve_path_case(cur_path).await;
+	let file_db;
+	let zip_handles;
+	let allow_ext;
+	let root_dir;
+	{
+		let ctrl = global().lock().await;
+		file_db = ctrl.file_db.clone();
+		zip_handles = ctrl.zip_handles.clone();
+		allow_ext = ctrl.allow_ext.clone();
+		root_dir = ctrl.root_dir.clone();
+	}
+	let Some(file_index) = file_db.lock().unwrap().get(&cur_path).cloned() else { return GetResponse::Error(Status::NotFound); };
+	if file_index.is_file() && !extension_allowed(&allow_ext, &cur_path) {
+		return GetResponse::Error(Status::Forbidden);
+	}
+	let in_zip = file_index.is_inside_zip();
+	let (zip_path, zip_index) = match (file_index.1, file_index.2) {
+		(Some(zip_path), Some(zip_index)) if in_zip => (zip_path, Some(zip_index)),
+		// An archive under the served directory, keyed by its path on disk like iter_dir_cb does
+		_ if !in_zip => (cur_path.split('/').fold(root_dir, |x, y| x.join(y)).to_string_lossy().into_owned(), None),
+		_ => return GetResponse::Error(Status::NotFound)
+	};
+	let Some(mut zip_handle) = zip_handles.lock().unwrap().get(&zip_path).cloned() else { return GetResponse::Error(Status::NotFound); };
+	// Remote archives read their headers over HTTP and lazily opened zips open their file
+	let meta = tokio::task::spawn_blocking(move || match zip_index {
+		Some(zip_index) => zip_handle.entry_meta(zip_index).map(|x| serde_json::to_string(&x).unwrap()),
+		None => zip_handle.archive_meta().map(|x| serde_json::to_string(&x).unwrap())
+	}).await;
+	match meta {
+		Ok(Ok(meta)) => GetResponse::StringContent(ContentType::JSON, meta),
+		Ok(Err(err)) => {
+			warn!("Cannot read the metadata of {} from {}: {}", cur_path, zip_path, err);
+			GetResponse::Error(Status::InternalServerError)
+		},
+		Err(err) => {
+			error!("Cannot read the metadata of {}: {}", cur_path, err);
+			GetResponse::Error(Status::InternalServerError)
+		}
+	}
+}
+
+// Joins the raw request segments with "/" as separator, refusing anything that could leave
+// the served directory: ".." segments, absolute segments or drive prefixes. Colons elsewhere are
+// part of the name, as in "12:30.log".
+fn normalize_request_path<'a>(segments: impl Iterator<Item = &'a str>) -> Option<String> {
+	let mut parts = vec![];
+	for segment in segments {
+		if segment.starts_with('/') || segment.starts_with('\\') { return None; }
+		for part in segment.split(['/', '\\']) {
+			match part {
+				"" | "." => {},
+				".." => return None,
+				_ if parts.is_empty() && is_drive_prefix(part) => return None,
+				_ => parts.push(part)
+			}
+		}
+	}
+	Some(parts.join("/"))
+}
+
+// "C:", "c:dir" and the like, which Windows resolves outside the served directory
+fn is_drive_prefix(part: &str) -> bool {
+	let bytes = part.as_bytes();
+	bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+#[rocket::get("/<_path..>")]
+async fn file_route(_path: PathBuf, origin: &Origin<'_>, accept: AcceptEncoding) -> GetResponse {
+	let raw = raw_query(origin);
+	let archive = archive_query(origin);
+	// Rocket already collapses ".." in PathBuf, so the original segments are checked instead
+	match normalize_request_path(origin.path().segments()) {
+		Some(cur_path) if archive.is_some() => match archive.flatten() {
+			Some(format) => with_request_timeout(async { download_dir(resolve_path_case(cur_path).await, format).await }, origin).await,
+			None => GetResponse::Error(Status::BadRequest)
+		},
+		Some(cur_path) if meta_query(origin) => with_request_timeout(serve_entry_meta(cur_path), origin).await,
+		Some(cur_path) => match listing_query(origin) {
+			Some(listing) => with_request_timeout(serve_path(cur_path, accept, raw, listing), origin).await,
+			None => GetResponse::Error(Status::BadRequest)
+		},
+		None => {
+			warn!("Refused request: {}", origin.path());
+			GetResponse::Error(Status::Forbidden)
+		}
+	}
+}
+
+async fn serve_path(cur_path: String, accept: AcceptEncoding, raw: bool, listing: ListingQuery) -> GetResponse {
+	let cur_path = resolve_path_case(cur_path).await;
+	let file_ext = Path::new(&cur_path).extension().map(|x| x.to_os_string());
+	let file_ext = file_ext.as_ref();
+
+	let file_db;
+	let spa_index;
+	let render_markdown;
+	let root_dir;
+	let index_files;
+	let auto_index;
+	let hide_listing;
+	let default_dirs_first;
+	let default_per_page;
+	let collapse_dirs;
+	let flatten;
+	let hide_source_archives;
+	let allow_ext;
+	{
+		let ctrl = global().lock().await;
+		file_db = ctrl.file_db.clone();
+		spa_index = ctrl.spa_index.clone();
+		render_markdown = ctrl.render_markdown;
+		root_dir = ctrl.root_dir.clone();
+		index_files = ctrl.index_files.clone();
+		auto_index = ctrl.auto_index;
+		hide_listing = ctrl.hide_listing.clone();
+		default_dirs_first = ctrl.dirs_first;
+		default_per_page = ctrl.per_page;
+		collapse_dirs = ctrl.collapse_dirs;
+		flatten = ctrl.flatten;
+		hide_source_archives = ctrl.hide_source_archives;
+		allow_ext = ctrl.allow_ext.clone();
+	}
+	let file_index_opt;
+	let index_file_opt;
+	{
+		let file_db_lock = file_db.lock().unwrap();
+		file_index_opt = file_db_lock.get(&cur_path).cloned();
+		// The first index file present wins
+		index_file_opt = index_files.iter()
+			.map(|x| format!("{}/{}", cur_path, x))
+			.filter(|x| extension_allowed(&allow_ext, x))
+			.find_map(|x| file_db_lock.get(&x).cloned().map(|v| (x, v)));
+	}
+	let is_known = file_index_opt.is_some();
+	// Hidden archives are only served through their content, never as files of their own
+	if hide_source_archives && !is_known && ArchiveKind::from_path(Path::new(&cur_path)).is_some() && root_dir.join(&cur_path).is_file() {
+		return GetResponse::Error(Status::NotFound);
+	}
+	let is_known_dir = file_index_opt.as_ref().is_some_and(|x| x.is_dir());
+	if file_index_opt.as_ref().is_some_and(|x| x.is_file()) && !extension_allowed(&allow_ext, &cur_path) {
+		return GetResponse::Error(Status::Forbidden);
+	}
+	let retype = |x: GetResponse| if raw { GetResponse::Raw(Box::new(x)) } else { x };
+	if let Some((index_path, index_file)) = index_file_opt {
+		response_file_index!(Some(index_file), Path::new(&index_path).extension().map(|x| x.to_os_string()).as_ref(), &index_path, !raw, retype);
+	}
+	let is_markdown = file_ext.is_some_and(|x| x.eq_ignore_ascii_case("md"));
+	if let Some(file_index) = file_index_opt.as_ref().filter(|x| render_markdown && !raw && is_markdown && x.0 & 0x10 == 0) {
+		let data = match file_index.0 {
+			0x01 => match read_file_from_zip(file_index.1.as_ref().unwrap(), file_index.2.unwrap()).await {
+				Ok(data) => data,
+				Err(err) if err.is::<EntryTooLarge>() => return GetResponse::Error(Status::PayloadTooLarge),
+				Err(err) if err.is::<ReadsBusy>() => return GetResponse::Error(Status::ServiceUnavailable),
+				Err(err) => {
+					error!("Cannot serve {}: {:#}", cur_path, err);
+					return GetResponse::Error(Status::InternalServerError);
+				}
+			},
+			_ => match tokio::fs::read(root_dir.join(&cur_path)).await {
+				Ok(data) => data,
+				Err(_) => return GetResponse::Error(Status::NotFound)
+			}
+		};
+		return GetResponse::StringContent(ContentType::HTML, markdown_to_html(&cur_path, &String::from_utf8_lossy(&data)));
+	}
+	if !raw && file_index_opt.as_ref().is_some_and(|x| x.0 & 0x10 == 0) {
+		if let Some((encoding, sibling_path, sibling_index)) = find_precompressed(&file_db, &cur_path, accept) {
+			// The sibling is served as is, but with the content type of the original file
+			let ctype = file_ext.and_then(|x| ContentType::from_extension(x.to_str().unwrap())).unwrap_or(ContentType::Bytes);
+			response_file_index!(Some(sibling_index), file_ext, &sibling_path, false, |x| GetResponse::Encoded(encoding, ctype, Box::new(x)));
+		}
+	}
+	response_file_index!(file_index_opt, file_ext, &cur_path, false, retype);
+
+	if let Some(spa_index) = spa_index.filter(|x| extension_allowed(&allow_ext, x)) {
+		// Anything neither a file nor a directory is a client-side route, unless it looks like an asset
+		if !cur_path.is_empty() && !is_known && !has_children(&file_db.lock().unwrap(), &cur_path) {
+			if file_ext.is_some() { return GetResponse::Error(Status::NotFound); }
+			let spa_index_opt = file_db.lock().unwrap().get(&spa_index).cloned();
+			response_file_index!(spa_index_opt, Path::new(&spa_index).extension().map(|x| x.to_os_string()).as_ref(), &spa_index, false);
+		}
+	}
+
+	if !auto_index || listing_hidden(&hide_listing, &cur_path) {
+		let is_dir = cur_path.is_empty() || is_known_dir || has_children(&file_db.lock().unwrap(), &cur_path);
+		return GetResponse::Error(if is_dir { Status::Forbidden } else { Status::NotFound });
+	}
+
+	// (is a directory, line), sorted by name within both groups as file_db already is
+	let mut file_list = vec![];
+	let file_db = file_db.lock().unwrap();
+	for (k, v) in children(&file_db, &cur_path) {
+		let size = if v.is_dir() { String::from("-") } else { human_size(v.size()) };
+		let k = if v.is_dir() && collapse_dirs { collapse_dir(&file_db, &hide_listing, k) } else { k.clone() };
+		// The link keeps the full path, the text only shows what lies below the listed directory
+		let parent_len = if cur_path.is_empty() { 0 } else { cur_path.len() + 1 };
+		let name = format!("{}{}", &k[parent_len..], if v.is_dir() { "/" } else { "" });
+		file_list.push((v.is_dir(), format!("{:>10}  <a href=\"\\{}\">{}</a>", size, href_path(&k), html_escape(&name))));
+	}
+	if listing.dirs_first.unwrap_or(default_dirs_first) { file_list.sort_by_key(|(is_dir, _)| !is_dir); }
+	let (file_list, page_links) = match listing.per_page.or(default_per_page).or(listing.page.map(|_| DEFAULT_PER_PAGE)) {
+		Some(per_page) => {
+			let page = listing.page.unwrap_or(1);
+			let pages = file_list.len().div_ceil(per_page).max(1);
+			let file_list = file_list.into_iter().skip((page - 1).saturating_mul(per_page)).take(per_page).collect();
+			(file_list, format!("<br><br>{}", page_links(listing, page, pages, per_page)))
+		},
+		None => (file_list, String::new())
+	};
+	let file_list: Vec<String> = file_list.into_iter().map(|(_, line)| line).collect();
+	let flattened = if flatten { " (archive entries flattened to their base names)" } else { "" };
+	GetResponse::StringContent(ContentType::HTML, format!("<pre>Files under {}{}:<br>  {}{}</pre>", if cur_path.is_empty() { String::from("current path") } else { html_escape(&cur_path) }, flattened, file_list.join("<br>  "), page_links))
+}
+
+// Prometheus text format, for scraping by monitoring stacks
+#[rocket::get("/metrics")]
+async fn metrics_route() -> (ContentType, String) {
+	let file_db;
+	let metrics;
+	{
+		let ctrl = global().lock().await;
+		file_db = ctrl.file_db.clone();
+		metrics = ctrl.metrics.clone();
+	}
+	let indexed_entries = file_db.lock().unwrap().len();
+	let mut body = String::new();
+	body.push_str("# HELP zipserver_requests_total Requests received.\n# TYPE zipserver_requests_total counter\n");
+	body.push_str(&format!("zipserver_requests_total {}\n", metrics.requests.load(Ordering::Relaxed)));
+	body.push_str("# HELP zipserver_responses_total Responses sent, by status class.\n# TYPE zipserver_responses_total counter\n");
+	for (i, count) in metrics.responses.iter().enumerate() {
+		body.push_str(&format!("zipserver_responses_total{{class=\"{}xx\"}} {}\n", i + 1, count.load(Ordering::Relaxed)));
+	}
+	body.push_str("# HELP zipserver_bytes_served_total Body bytes of responses with a known size.\n# TYPE zipserver_bytes_served_total counter\n");
+	body.push_str(&format!("zipserver_bytes_served_total {}\n", metrics.bytes.load(Ordering::Relaxed)));
+	body.push_str("# HELP zipserver_indexed_entries Paths in the file index.\n# TYPE zipserver_indexed_entries gauge\n");
+	body.push_str(&format!("zipserver_indexed_entries {}\n", indexed_entries));
+	(ContentType::new("text", "plain").with_params(("version", "0.0.4")), body)
+}
+
+// Logs every request once its response is ready, covering errors and every responder
+struct RequestLogger {
+	metrics: Arc<ServeMetrics>,
+	// Log the request details as fields of their own, for --log-format json
+	json_logs: bool
+}
+
+// Stored in the request-local cache by on_request
+struct RequestStart(Option<Instant>);
+
+#[rocket::async_trait]
+impl Fairing for RequestLogger {
+	fn info(&self) -> Info {
+		Info { name: "Request logger", kind: Kind::Request | Kind::Response }
+	}
+
+	async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+		request.local_cache(|| RequestStart(Some(Instant::now())));
+		self.metrics.requests.fetch_add(1, Ordering::Relaxed);
+	}
+
+	async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+		let elapsed = request.local_cache(|| RequestStart(None)).0.map(|x| x.elapsed().as_millis()).unwrap_or(0);
+		// Streamed bodies have no size until they are sent
+		let bytes = response.body_mut().size().await;
+		if let Some(bytes) = bytes { self.metrics.bytes.fetch_add(bytes as u64, Ordering::Relaxed); }
+		self.metrics.count_response(response.status().code);
+		if self.json_logs {
+			info!(method = %request.method(), path = %request.uri(), status = response.status().code, bytes = bytes.map(|x| x as u64),
+				duration_ms = elapsed as u64, client = request.client_ip().map(|x| x.to_string()), "{} {}", request.method(), request.uri());
+			return;
+		}
+		let size = match bytes {
+			Some(bytes) => format!("{} bytes", bytes),
+			None => String::from("streamed")
+		};
+		info!("{} {} {} {} {}ms", request.method(), request.uri(), response.status().code, size, elapsed);
+	}
+}
+
+// A server started with spawn. Only one server can run per process, as the file database is global.
+pub struct ServeHandle {
+	shutdown: Shutdown,
+	join_handle: JoinHandle<Result<()>>,
+	// Listeners on the extra ports and the HTTP redirect, stopped along with the main one
+	others: Vec<(Shutdown, JoinHandle<Result<()>>)>,
+	// Tasks reindexing on their own, aborted once the server stops
+	watchers: Vec<JoinHandle<()>>
+}
+
+impl ServeHandle {
+	// Waits until the server stops by itself
+	pub async fn wait(self) -> Result<()> {
+		let result = self.join_handle.await;
+		self.watchers.iter().for_each(JoinHandle::abort);
+		Self::stop_others(self.others).await?;
+		result?
+	}
+
+	// Asks the server to stop gracefully and waits for it
+	pub async fn shutdown(self) -> Result<()> {
+		self.shutdown.notify();
+		self.watchers.iter().for_each(JoinHandle::abort);
+		let result = self.join_handle.await?;
+		Self::stop_others(self.others).await?;
+		result
+	}
+
+	async fn stop_others(others: Vec<(Shutdown, JoinHandle<Result<()>>)>) -> Result<()> {
+		for (shutdown, join_handle) in others {
+			shutdown.notify();
+			join_handle.await??;
+		}
+		Ok(())
+	}
+}
+
+struct HttpsPort(u16);
+
+// Sends every plain HTTP request to the same path on the TLS listener
+#[rocket::get("/<_path..>")]
+fn https_redirect_route(_path: PathBuf, origin: &Origin<'_>, host: Option<&Host<'_>>, https_port: &State<HttpsPort>, config: &Config) -> Redirect {
+	let domain = match host {
+		Some(host) => host.domain().to_string(),
+		None => config.address.to_string()
+	};
+	let port = if https_port.0 == 443 { String::new() } else { format!(":{}", https_port.0) };
+	Redirect::moved(format!("https://{}{}{}", domain, port, origin))
+}
+
+fn launch_in_background(server: Rocket<Ignite>) -> (Shutdown, JoinHandle<Result<()>>) {
+	let shutdown = server.shutdown();
+	let join_handle = tokio::spawn(async move {
+		let _ = server.launch().await.map_err(|err| anyhow!("File server error: {}", err))?;
+		Ok(())
+	});
+	(shutdown, join_handle)
+}
+
+// One file database entry as written by --dump-index
+#[derive(Serialize)]
+struct IndexDumpEntry<'a> {
+	path: &'a str,
+	dir: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	archive: Option<&'a str>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	index: Option<usize>,
+	size: u64
+}
+
+// Indexes local and remote archives into file_db, the part of spawn that dry_run shares
+async fn index_files(dir: &Path, index_options: &IndexOptions, target: &IndexTarget) -> Result<()> {
+	let begin_time = Instant::now();
+	let names = Arc::new(EntryNameCheck::new(index_options.strict));
+	if index_options.lazy_open {
+		global().lock().await.open_zips = Some(arc_ptr_create!(OpenZips::new(index_options.max_open_files)));
+	}
+	let cancelled = Arc::new(AtomicBool::new(false));
+	let indexing = async {
+		if let Some(file) = &index_options.file {
+			index_archive_file(file, target, &names).await.map_err(|err| anyhow!("File server error: {}", err))?;
+		}
+		else if let Some(list) = &index_options.archive_list {
+			index_archive_list(list, target, &names).await.map_err(|err| anyhow!("File server error: {}", err))?;
+			index_remote_archives(&index_options.remote, target, &names).await.map_err(|err| anyhow!("File server error: {}", err))?;
+		}
+		else {
+			create_file_db(dir.to_str().unwrap(), index_options, target, names.clone(), cancelled.clone()).await.map_err(|err| anyhow!("File server error: {}", err))?;
+			index_remote_archives(&index_options.remote, target, &names).await.map_err(|err| anyhow!("File server error: {}", err))?;
+		}
+		Ok::<(), anyhow::Error>(())
+	};
+	match index_options.index_timeout {
+		Some(timeout) => {
+			// Indexing may keep every runtime worker too busy for the timer to fire, so a thread of its
+			// own keeps the time too, stopping the indexing threads through cancelled
+			let deadline = cancelled.clone();
+			thread::spawn(move || {
+				thread::sleep(timeout);
+				deadline.store(true, Ordering::Relaxed);
+			});
+			let finished = match tokio::time::timeout(timeout, indexing).await {
+				// Past the deadline, errors come from the threads stopping
+				Ok(result) if !cancelled.load(Ordering::Relaxed) => { result?; true },
+				_ => false
+			};
+			cancelled.store(true, Ordering::Relaxed);
+			if !finished {
+				if !index_options.partial_index {
+					bail!("Indexing did not finish within {}s. Add --partial-index to serve what it reached.", timeout.as_secs());
+				}
+				warn!("Indexing did not finish within {}s, serving the {} paths indexed so far.", timeout.as_secs(), target.file_db.lock().unwrap().len());
+			}
+		},
+		None => indexing.await?
+	}
+	names.report();
+	report_index_stats(target, &names, begin_time).await;
+	Ok(())
+}
+
+// Peak resident memory of the process, where the OS reports it cheaply
+fn peak_memory() -> Option<u64> {
+	let status = fs::read_to_string("/proc/self/status").ok()?;
+	let line = status.lines().find(|x| x.starts_with("VmHWM:"))?;
+	Some(line.split_whitespace().nth(1)?.parse::<u64>().ok()? * 1024)
+}
+
+// Totals to sanity-check a deployment against, from what the indexing callbacks collected
+async fn report_index_stats(target: &IndexTarget, names: &EntryNameCheck, begin_time: Instant) {
+	let IndexTarget { file_db, zip_handles, archive_stats, .. } = target;
+	let archives = zip_handles.lock().unwrap().len();
+	let entries: usize = archive_stats.lock().unwrap().values().map(|x| x.entries).sum();
+	let (reachable, files) = {
+		let file_db = file_db.lock().unwrap();
+		(file_db.values().filter(|x| x.is_inside_zip()).count(), file_db.values().filter(|x| !x.is_inside_zip() && x.is_file()).count())
+	};
+	info!("Indexed {} archive(s) holding {} entries ({} reachable, {} path collision(s)) and {} files on disk. Time: {}ms.",
+		archives, entries, reachable, names.collided.load(Ordering::Relaxed), files, (Instant::now() - begin_time).as_millis());
+	if let Some(peak) = peak_memory() { info!("Peak memory: {}.", human_size(peak)); }
+}
+
+// Indexes the directory like serve would, including its summary, and stops there. With dump, the file
+// database is also written to it as JSON.
+pub async fn dry_run(dir: &str, index_options: &IndexOptions, dump: Option<&str>) -> Result<()> {
+	let current_path = PathBuf::from(dir);
+	if !current_path.is_dir() {
+		bail!("Target path is not a directory!");
+	}
+
+	let target = IndexTarget::global().await;
+	index_files(&current_path, index_options, &target).await?;
+	info!("Dry run done, not serving.");
+
+	if let Some(dump) = dump {
+		let file_db = target.file_db.lock().unwrap();
+		let entries: Vec<IndexDumpEntry> = file_db.iter().map(|(path, x)| IndexDumpEntry {
+			path, dir: x.is_dir(), archive: x.1.as_deref(), index: x.2, size: x.size()
+		}).collect();
+		File::create(dump).map_err(anyhow::Error::from)
+			.and_then(|x| Ok(serde_json::to_writer_pretty(BufWriter::new(x), &entries)?))
+			.map_err(|err| anyhow!("Cannot write {}: {}", dump, err))?;
+		info!("File database written to {}.", dump);
+	}
+	Ok(())
+}
+
+pub async fn launch(dir: &str, index_options: &IndexOptions, serve_options: &ServeOptions<'_>) -> Result<()> {
+	let mut serve_handle = spawn(dir, index_options, serve_options).await?;
+	// Only the command line reindexes by itself, a server started with spawn is left to its caller
+	if let Some(file) = &index_options.file {
+		serve_handle.watchers.push(tokio::spawn(watch_archive_file(file.clone(), index_options.strict, index_options.preload)));
+	}
+	#[cfg(unix)]
+	serve_handle.watchers.push(tokio::spawn(reindex_on_hangup(PathBuf::from(dir), index_options.clone())));
+	serve_handle.wait().await
+}
+
+// Indexes the directory and starts serving in the background
+pub async fn spawn(dir: &str, index_options: &IndexOptions, serve_options: &ServeOptions<'_>) -> Result<ServeHandle> {
+	let current_path = PathBuf::from(dir);
+	if !current_path.is_dir() {
+		bail!("Target path is not a directory!");
+	}
+
+	let address = match serve_options.host.parse::<Ipv4Addr>() {
+		Ok(x) => { IpAddr::V4(x) },
+		Err(err) => bail!("Invalid IP address: {}, error: {}", serve_options.host, err)
+	};
+
+	if serve_options.redirect_http.is_some() && !serve_options.use_ssl {
+		bail!("Redirecting HTTP requires SSL to be configured");
+	}
+
+	// Rocket panics on an invalid mount point, so it is checked first
+	if let Some(api_path) = &serve_options.api_path {
+		if !api_path.starts_with('/') || Origin::parse(api_path).is_err() {
+			bail!("Invalid API path: {}", api_path);
+		}
+	}
+
+	let mut server_config = Config {
+		log_level: rocket_log_level(),
+		cli_colors: false,
+		address,
+		port: serve_options.port,
+		secret_key: SecretKey::generate().unwrap_or(SecretKey::from(&[0; 64])),
+		..Default::default()
+	};
+	if let Some(workers) = serve_options.workers { server_config.workers = workers; }
+	if let Some(keep_alive) = serve_options.keep_alive { server_config.keep_alive = keep_alive; }
+
+	let file_db;
+	let metrics;
+	{
+		let mut ctrl = global().lock().await;
+		file_db = ctrl.file_db.clone();
+		metrics = ctrl.metrics.clone();
+
+		if let Some(landing) = &serve_options.landing {
+			ctrl.landing_page.clone_from(landing);
+			ctrl.land_with_path = serve_options.land_with_path;
+			ctrl.landing_redirect = serve_options.landing_redirect;
+			info!("Serving default page: {}", landing);
+		}
+		ctrl.max_entry_size = serve_options.max_entry_size;
+		ctrl.max_request_bytes = serve_options.max_request_bytes;
+		ctrl.ignore_case = serve_options.ignore_case;
+		ctrl.render_markdown = serve_options.render_markdown;
+		ctrl.index_files.clone_from(&serve_options.index_files);
+		ctrl.auto_index = serve_options.auto_index;
+		ctrl.hide_listing.clone_from(&serve_options.hide_listing);
+		ctrl.dirs_first = serve_options.dirs_first;
+		ctrl.per_page = serve_options.per_page;
+		ctrl.collapse_dirs = serve_options.collapse_dirs;
+		ctrl.flatten = index_options.flatten;
+		ctrl.hide_source_archives = serve_options.hide_source_archives;
+		ctrl.allow_ext = serve_options.allow_ext.iter().map(|x| x.trim_start_matches('.').to_lowercase()).collect();
+		ctrl.spa_index = serve_options.spa.as_ref().map(|x| x.trim_matches('/').to_string());
+		ctrl.root_dir = current_path.clone();
+		ctrl.request_timeout = serve_options.request_timeout;
+		ctrl.read_permits = serve_options.max_concurrent_reads.map(|x| Arc::new(Semaphore::new(x)));
+		ctrl.entry_cache.lock().unwrap().budget = index_options.cache_size;
+		ctrl.prefetch = serve_options.prefetch;
+	}
+
+	index_files(&current_path, index_options, &IndexTarget::global().await).await?;
+
+	if index_options.preload {
+		preload_entries(&file_db).await.map_err(|err| anyhow!("File server error: {}", err))?;
+	}
+
+	if serve_options.ignore_case {
+		let ctrl = global().lock().await;
+		create_lowercase_db(&file_db, &ctrl.file_db_lowercase);
+	}
+
+	if serve_options.use_ssl {
+		server_config.tls = Some(TlsConfig::from_paths(
+			serve_options.ssl_cert.clone().unwrap(),
+			serve_options.ssl_key.clone().unwrap()
+		));
+	}
+	
+	let server = build_server(server_config.clone(), serve_options, metrics.clone());
+	let server = if serve_options.open {
+		// The root route takes care of the landing page
+		let url = format!("http{}://{}:{}/", if serve_options.use_ssl { "s" } else { "" }, browser_host(serve_options.host), serve_options.port);
+		server.attach(AdHoc::on_liftoff("Browser opener", move |_| Box::pin(async move { open_browser(&url); })))
+	} else { server };
+	let server = server
+		.ignite()
+		.await
+		.map_err(|err| anyhow!("File server error: {}", err))?;
+
+	// Every listener is ignited before any is launched, so that a bad port stops them all
+	let mut extra_servers = vec![];
+	for port in &serve_options.extra_ports {
+		let config = Config { port: *port, ..server_config.clone() };
+		extra_servers.push(build_server(config, serve_options, metrics.clone())
+			.ignite()
+			.await
+			.map_err(|err| anyhow!("File server error: {}", err))?);
+	}
+
+	let mut others = vec![];
+	if let Some(redirect_port) = serve_options.redirect_http {
+		let redirect_config = Config {
+			log_level: rocket_log_level(),
+			cli_colors: false,
+			address,
+			port: redirect_port,
+			..Default::default()
+		};
+		let redirect_server = rocket::custom(redirect_config)
+			.manage(HttpsPort(serve_options.port))
+			.mount("/", rocket::routes![https_redirect_route])
+			.ignite()
+			.await
+			.map_err(|err| anyhow!("File server error: {}", err))?;
+		info!("Redirecting http://{}:{} to HTTPS.", serve_options.host, redirect_port);
+		others.push(launch_in_background(redirect_server));
+	}
+	others.extend(extra_servers.into_iter().map(launch_in_background));
+
+	let (shutdown, join_handle) = launch_in_background(server);
+
+	Ok(ServeHandle { shutdown, join_handle, others, watchers: vec![] })
+}
+
+// One listener serving the shared file database, spawn builds one per port
+fn build_server(server_config: Config, serve_options: &ServeOptions<'_>, metrics: Arc<ServeMetrics>) -> Rocket<Build> {
+	// NoSniff is off by default: files in archives are typed by their extension alone, and browsers
+	// sniffing those without a known extension is often what makes them viewable. It can be turned
+	// back on when the archive content is not trusted.
+	let shield = if serve_options.nosniff { Shield::default() } else { Shield::default().disable::<NoSniff>() };
+	let server = rocket::custom(server_config)
+		.attach(shield)
+		// Before the logger, so that it sees the partial responses
+		.attach(AdHoc::on_response("Byte ranges", |request, response| Box::pin(serve_byte_range(request, response))))
+		.attach(RequestLogger { metrics, json_logs: serve_options.json_logs })
+		.mount("/", rocket::routes![file_route])
+		.mount("/", rocket::routes![landing_route])
+		.mount("/", rocket::routes![zips_route])
+		.mount("/", rocket::routes![options_route, refused_post_route, put_route, delete_route, patch_route]);
+	let server = match &serve_options.api_path {
+		Some(api_path) => server.mount(&api_path[..], rocket::routes![post_route]),
+		None => server
+	};
+	let server = match serve_options.csp.clone() {
+		Some(csp) => server.attach(AdHoc::on_response("Content-Security-Policy", move |_, response| {
+			let csp = csp.clone();
+			Box::pin(async move { response.set_raw_header("Content-Security-Policy", csp); })
+		})),
+		None => server
+	};
+	if serve_options.metrics { server.mount("/", rocket::routes![metrics_route]) } else { server }
+}
+
+// Browsers cannot connect to the unspecified address, so it is opened as localhost instead
+fn browser_host(host: &str) -> &str {
+	if host == "0.0.0.0" { "localhost" } else { host }
+}
+
+fn open_browser(url: &str) {
+	#[cfg(target_os = "macos")]
+	let mut command = Command::new("open");
+	#[cfg(target_os = "windows")]
+	let mut command = {
+		// start is a cmd builtin, and its first quoted argument is the window title
+		let mut command = Command::new("cmd");
+		command.args(["/C", "start", ""]);
+		command
+	};
+	#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+	let mut command = Command::new("xdg-open");
+
+	match command.arg(url).stdout(Stdio::null()).stderr(Stdio::null()).spawn() {
+		Ok(mut child) => {
+			info!("Opened {} in the browser.", url);
+			// Reaped in the background, as openers may wait for the browser to close
+			std::thread::spawn(move || child.wait());
+		},
+		Err(err) => warn!("Cannot open a browser ({}). Visit {} instead.", err, url)
+	}
+}
+
+// Rocket keeps its own logger, which is only chatty when --log-level asks for debug output
+fn rocket_log_level() -> LogLevel {
+	match LevelFilter::current() {
+		LevelFilter::OFF => LogLevel::Off,
+		LevelFilter::TRACE => LogLevel::Debug,
+		LevelFilter::DEBUG => LogLevel::Normal,
+		_ => LogLevel::Critical
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn listing_links_cannot_break_out_of_attributes() {
+		assert_eq!(html_escape(r#"a"><script>'"#), "a&quot;&gt;&lt;script&gt;&#39;");
+		assert_eq!(href_path(r#"d"><x/a b/é.txt"#), "d%22%3E%3Cx/a%20b/%C3%A9.txt");
+		assert_eq!(href_path("dir/file-1_v2.txt"), "dir/file-1_v2.txt");
+	}
+
+	#[test]
+	fn unusual_statuses_are_not_counted() {
+		let metrics = ServeMetrics::default();
+		for code in [0, 42, 99, 100, 204, 404, 503, 599, 600, 999] {
+			metrics.count_response(code);
+		}
+		let counts: Vec<u64> = metrics.responses.iter().map(|x| x.load(Ordering::Relaxed)).collect();
+		assert_eq!(counts, [1, 1, 0, 1, 2]);
+	}
+
+	#[test]
+	fn malformed_ranges_are_ignored() {
+		assert_eq!(parse_byte_range("bytes=2-4", 10), Some(Some((2, 4))));
+		assert_eq!(parse_byte_range("bytes=7-", 10), Some(Some((7, 9))));
+		assert_eq!(parse_byte_range("bytes=-3", 10), Some(Some((7, 9))));
+		assert_eq!(parse_byte_range("bytes=5-100", 10), Some(Some((5, 9))));
+		assert_eq!(parse_byte_range("bytes=10-", 10), Some(None));
+		for header in ["bytes=4-2", "items=0-1", "bytes=a-b", "bytes=0-1,3-4", "bytes=", "bytes=-", "0-1"] {
+			assert_eq!(parse_byte_range(header, 10), None, "{}", header);
+		}
+	}
+
+	const RANGE_BODY: &[u8] = b"0123456789";
+	const RANGE_ETAG: &str = "\"range-test\"";
+
+	#[rocket::get("/body")]
+	fn validated_body() -> GetResponse {
+		let validators = Validators { etag: RANGE_ETAG.to_string(), last_modified: UNIX_EPOCH + Duration::from_secs(1_000_000_000) };
+		GetResponse::Validated(validators, Box::new(GetResponse::Bytes(ContentType::Plain, RANGE_BODY.to_vec())))
+	}
+
+	// (status, body) of GET /body with these headers, through the byte range fairing serve attaches
+	async fn get_range(headers: &[(&'static str, &'static str)]) -> (Status, Vec<u8>) {
+		use rocket::http::Header;
+		use rocket::local::asynchronous::Client;
+		let rocket = rocket::build()
+			.attach(AdHoc::on_response("Byte ranges", |request, response| Box::pin(serve_byte_range(request, response))))
+			.mount("/", rocket::routes![validated_body]);
+		let client = Client::untracked(rocket).await.unwrap();
+		let mut request = client.get("/body");
+		for (name, value) in headers { request.add_header(Header::new(*name, *value)); }
+		let response = request.dispatch().await;
+		(response.status(), response.into_bytes().await.unwrap_or_default())
+	}
+
+	#[rocket::async_test]
+	async fn stale_if_range_falls_back_to_full_body() {
+		assert_eq!(get_range(&[("Range", "bytes=2-4")]).await, (Status::PartialContent, b"234".to_vec()));
+		assert_eq!(get_range(&[("Range", "bytes=2-4"), ("If-Range", RANGE_ETAG)]).await, (Status::PartialContent, b"234".to_vec()));
+		assert_eq!(get_range(&[("Range", "bytes=2-4"), ("If-Range", "Sun, 09 Sep 2001 01:46:40 GMT")]).await, (Status::PartialContent, b"234".to_vec()));
+		// The client's copy is outdated, so it gets the whole current body instead of a piece to splice in
+		assert_eq!(get_range(&[("Range", "bytes=2-4"), ("If-Range", "\"stale\"")]).await, (Status::Ok, RANGE_BODY.to_vec()));
+		assert_eq!(get_range(&[("Range", "bytes=2-4"), ("If-Range", "W/\"range-test\"")]).await, (Status::Ok, RANGE_BODY.to_vec()));
+		assert_eq!(get_range(&[("Range", "bytes=2-4"), ("If-Range", "Mon, 01 Jan 2001 00:00:00 GMT")]).await, (Status::Ok, RANGE_BODY.to_vec()));
+	}
+
+	#[rocket::async_test]
+	async fn malformed_range_requests_get_the_full_body() {
+		for range in ["bytes=4-2", "items=0-1", "bytes=0-1,3-4", "bytes=x-"] {
+			assert_eq!(get_range(&[("Range", range)]).await, (Status::Ok, RANGE_BODY.to_vec()), "{}", range);
+		}
+		assert_eq!(get_range(&[("Range", "bytes=10-")]).await.0, Status::RangeNotSatisfiable);
+	}
+
+	#[rocket::async_test]
+	async fn entries_of_dropped_archives_answer_500() {
+		use rocket::local::asynchronous::Client;
+		let zip_path = String::from("dropped.zip");
+		let err = read_file_from_zip(&zip_path, 0).await.unwrap_err();
+		assert_eq!(err.to_string(), "dropped.zip is not an indexed archive");
+
+		// Left behind in the index by a reindex that dropped its archive
+		let file_db = global().lock().await.file_db.clone();
+		file_db.lock().unwrap().insert(String::from("dropped/entry.txt"), FileIndex::new(true, false, Some(zip_path), Some(0), 5));
+		let client = Client::untracked(rocket::build().mount("/", rocket::routes![file_route])).await.unwrap();
+		assert_eq!(client.get("/dropped/entry.txt").dispatch().await.status(), Status::InternalServerError);
+	}
+
+	#[test]
+	fn entry_names_with_colons_are_kept() {
+		let names = EntryNameCheck::new(false);
+		for name in ["logs/12:30.log", "12:30.log", "dir/a:b.txt"] {
+			assert_eq!(names.check("a.zip", name).unwrap().as_deref(), Some(name));
+		}
+		assert_eq!(names.check("a.zip", "C:/x").unwrap(), None);
+		assert_eq!(names.check("a.zip", "C:\\x").unwrap(), None);
+		assert_eq!(names.refused.load(Ordering::Relaxed), 2);
+		let strict = EntryNameCheck::new(true);
+		assert!(strict.check("a.zip", "logs/12:30.log").is_ok());
+		assert!(strict.check("a.zip", "C:/x").is_err());
+	}
+
+	// Sets Content-Length from the body size as Rocket does when sending, which the local client skips
+	fn content_length() -> AdHoc {
+		AdHoc::on_response("Content-Length", |_, response| Box::pin(async move {
+			if let Some(size) = response.body_mut().size().await {
+				response.set_raw_header("Content-Length", size.to_string());
+			}
+		}))
+	}
+
+	#[rocket::get("/disk")]
+	async fn validated_file(path: &State<PathBuf>) -> GetResponse {
+		let validators = Validators { etag: RANGE_ETAG.to_string(), last_modified: UNIX_EPOCH + Duration::from_secs(1_000_000_000) };
+		GetResponse::Validated(validators, Box::new(GetResponse::File(NamedFile::open(path.inner()).await.ok())))
+	}
+
+	#[rocket::async_test]
+	async fn ranges_of_large_files_are_read_from_disk() {
+		use rocket::http::Header;
+		use rocket::local::asynchronous::Client;
+		const LEN: usize = 4 * 1024 * 1024;
+		let data: Vec<u8> = (0..LEN).map(|x| (x % 251) as u8).collect();
+		let path = std::env::temp_dir().join(format!("zip_handler-range-{}", std::process::id()));
+		fs::write(&path, &data).unwrap();
+		let rocket = rocket::build()
+			.attach(AdHoc::on_response("Byte ranges", |request, response| Box::pin(serve_byte_range(request, response))))
+			.attach(content_length())
+			.manage(path.clone())
+			.mount("/", rocket::routes![validated_file]);
+		let client = Client::untracked(rocket).await.unwrap();
+		let get = |headers: &[(&'static str, &'static str)]| {
+			let mut request = client.get("/disk");
+			for (name, value) in headers { request.add_header(Header::new(*name, *value)); }
+			async move {
+				let response = request.dispatch().await;
+				let header = |name| response.headers().get_one(name).map(String::from);
+				let (content_range, content_length) = (header("Content-Range"), header("Content-Length"));
+				(response.status(), content_range, content_length, response.into_bytes().await.unwrap_or_default())
+			}
+		};
+
+		let (status, content_range, content_length, body) = get(&[("Range", "bytes=3000000-3000009")]).await;
+		assert_eq!(status, Status::PartialContent);
+		assert_eq!(content_range.as_deref(), Some("bytes 3000000-3000009/4194304"));
+		assert_eq!(content_length.as_deref(), Some("10"));
+		assert_eq!(body, &data[3000000..3000010]);
+
+		let (status, content_range, _, body) = get(&[("Range", "bytes=4194304-")]).await;
+		assert_eq!(status, Status::RangeNotSatisfiable);
+		assert_eq!(content_range.as_deref(), Some("bytes */4194304"));
+		assert!(body.is_empty());
+
+		let (status, content_range, content_length, body) = get(&[("Range", "bytes=0-9"), ("If-Range", "\"stale\"")]).await;
+		assert_eq!(status, Status::Ok);
+		assert_eq!(content_range, None);
+		assert_eq!(content_length, Some(LEN.to_string()));
+		assert_eq!(body, data);
+		let _ = fs::remove_file(&path);
+	}
+
+	#[rocket::get("/variant/<name>")]
+	async fn response_variant(name: &str, path: &State<PathBuf>) -> GetResponse {
+		let bytes = || GetResponse::Bytes(ContentType::Plain, RANGE_BODY.to_vec());
+		let validators = || Validators { etag: RANGE_ETAG.to_string(), last_modified: UNIX_EPOCH };
+		match name {
+			"string" => GetResponse::StringContent(ContentType::HTML, String::from("<pre>listing</pre>")),
+			"bytes" => bytes(),
+			"file" => GetResponse::File(NamedFile::open(path.inner()).await.ok()),
+			"encoded" => GetResponse::Encoded("gzip", ContentType::Plain, Box::new(bytes())),
+			"raw" => GetResponse::Raw(Box::new(bytes())),
+			"validated" => GetResponse::Validated(validators(), Box::new(bytes())),
+			"validated-file" => GetResponse::Validated(validators(), Box::new(GetResponse::File(NamedFile::open(path.inner()).await.ok()))),
+			"download" => {
+				let (body, mut pipe) = tokio::io::duplex(4);
+				tokio::spawn(async move { pipe.write_all(RANGE_BODY).await });
+				GetResponse::Download(ContentType::ZIP, String::from("a.zip"), body)
+			},
+			_ => GetResponse::Error(Status::NotFound)
+		}
+	}
+
+	#[rocket::async_test]
+	async fn sized_responses_send_their_length() {
+		use rocket::local::asynchronous::Client;
+		let path = std::env::temp_dir().join(format!("zip_handler-variants-{}", std::process::id()));
+		fs::write(&path, b"a file on disk\n").unwrap();
+		let rocket = rocket::build()
+			.attach(AdHoc::on_response("Byte ranges", |request, response| Box::pin(serve_byte_range(request, response))))
+			.attach(content_length())
+			.manage(path.clone())
+			.mount("/", rocket::routes![response_variant]);
+		let client = Client::untracked(rocket).await.unwrap();
+		for name in ["string", "bytes", "file", "encoded", "raw", "validated", "validated-file", "error"] {
+			let response = client.get(format!("/variant/{}", name)).dispatch().await;
+			let content_length = response.headers().get_one("Content-Length").map(String::from);
+			let body = response.into_bytes().await.unwrap();
+			assert!(!body.is_empty(), "{}", name);
+			assert_eq!(content_length, Some(body.len().to_string()), "{}", name);
+		}
+
+		// Without a length, hyper sends the body chunked
+		let response = client.get("/variant/download").dispatch().await;
+		assert_eq!(response.headers().get_one("Content-Length"), None);
+		assert_eq!(response.into_bytes().await.unwrap(), RANGE_BODY);
+		let _ = fs::remove_file(&path);
+	}
+
+	// As file_route sees a request: the decoded segments of its path
+	fn normalize_uri(uri: &str) -> Option<String> {
+		normalize_request_path(Origin::parse(uri).unwrap().path().segments())
+	}
+
+	#[test]
+	fn traversal_requests_are_refused() {
+		assert_eq!(normalize_uri("/../../etc/passwd"), None);
+		assert_eq!(normalize_uri("/a/../../etc/passwd"), None);
+		assert_eq!(normalize_uri("/%2e%2e/%2e%2e/etc/passwd"), None);
+		assert_eq!(normalize_uri("/a/%2E%2E/b"), None);
+		assert_eq!(normalize_uri("/..%5c..%5cetc%5cpasswd"), None);
+		assert_eq!(normalize_uri("/%5cetc%5cpasswd"), None);
+		assert_eq!(normalize_uri("/%2fetc/passwd"), None);
+		assert_eq!(normalize_uri("/C:/Windows/win.ini"), None);
+		assert_eq!(normalize_request_path(["..\\..\\etc"].into_iter()), None);
+		assert_eq!(normalize_uri("/c:dir/x"), None);
+	}
+
+	#[test]
+	fn normal_paths_are_kept() {
+		assert_eq!(normalize_uri("/").as_deref(), Some(""));
+		assert_eq!(normalize_uri("/index.html").as_deref(), Some("index.html"));
+		assert_eq!(normalize_uri("/a/b/c.txt").as_deref(), Some("a/b/c.txt"));
+		assert_eq!(normalize_uri("/a/./b//c.txt").as_deref(), Some("a/b/c.txt"));
+		assert_eq!(normalize_uri("/a%20b/..c.txt").as_deref(), Some("a b/..c.txt"));
+		// Backslashes inside a segment are separators
+		assert_eq!(normalize_uri("/a%5cb.txt").as_deref(), Some("a/b.txt"));
+		// Only a drive prefix at the start is refused, other colons are part of the name
+		assert_eq!(normalize_uri("/12:30.log").as_deref(), Some("12:30.log"));
+		assert_eq!(normalize_uri("/logs/12%3A30.log").as_deref(), Some("logs/12:30.log"));
+		assert_eq!(normalize_uri("/logs/C:/x").as_deref(), Some("logs/C:/x"));
+	}
+}
@@ -1,204 +1,908 @@
-use std::collections::BTreeMap;
-use std::process::exit;
-use std::sync::Arc;
-use std::path::PathBuf;
-use std::fs::{self, File};
-use std::io::{self, BufWriter, BufReader, Write};
-use std::time::{Duration, Instant};
-
-use crossbeam::channel::{self, Sender, Receiver};
-use anyhow::Result;
-use tokio::time::sleep;
-use zip::write::FileOptions;
-use zip::{ZipArchive, ZipWriter};
-
-use crate::arc_pinned_ptr_create;
-use crate::utils::ArcPinnedPtr;
-use crate::utils::index_callback::ZipCallback;
-use crate::utils::index_zip::index_zip_single_thread;
-
-pub struct SplitOptions<'a> {
-	pub core_num: usize,
-	pub channel_size: usize,
-	pub thread_delay: usize,
-	pub quiet: bool,
-	pub verbose: bool,
-	pub sort_by: &'a str
-}
-
-enum ControlCommand {
-	FileSend(String, Vec<u8>),
-	Shutdown
-}
-
-fn remove_target(input: &str, quiet: bool) {
-	let meta = fs::metadata(input).unwrap();
-	if meta.is_dir() {
-		if quiet {
-			println!("[WARN] Destination is a directroy. Removing because of the quiet flag.");
-			if let Err(err) = fs::remove_dir_all(input) {
-				println!("[ERROR] Cannot remove directory: {}", err);
-				exit(1);
-			}
-		}
-		else if let Err(err) = fs::remove_dir(input) {
-			println!("[ERROR] Cannot remove directory: {}", err);
-			println!("[INFO] Add \"--quiet\" to forcibly remove non-empty directories.");
-			exit(1);
-		}
-	}
-	else {
-		if quiet { println!("[WARN] Destination already exists. Removing because of the quiet flag."); }
-		if let Err(err) = fs::remove_file(input) {
-			println!("[ERROR] Cannot remove file: {}", err);
-			exit(1);
-		}
-	}
-}
-
-pub async fn split_archive_files(input: &str, output: &str, options: SplitOptions<'_>) {
-	if let Err(err) = File::open(input) {
-		println!("[ERROR] Cannot open file: {}", err);
-		exit(1);
-	}
-
-	let SplitOptions { core_num, channel_size, thread_delay, quiet, verbose, sort_by } = options;
-
-	if PathBuf::from(output).exists() {
-		if quiet { remove_target(output, quiet); }
-		else {
-			print!("Target already exists. Overwrite? [y/N]: ");
-			io::stdout().flush().unwrap();
-			let mut buffer = String::with_capacity(255);
-			if let Err(err) = io::stdin().read_line(&mut buffer) {
-				println!("[ERROR] {}", err);
-			}
-			let buffer = buffer.trim().to_lowercase();
-			match &buffer[..] {
-				"y" | "yes" => { remove_target(output, quiet); },
-				_ => exit(0)
-			}
-		}
-	}
-
-	if let Err(err) = fs::create_dir_all(output) {
-		println!("[ERROR] Cannot create directory {}: {}", output, err);
-		exit(1);
-	}
-
-	let begin = Instant::now();
-	println!("[INFO] Indexing...");
-
-	let file_map = arc_pinned_ptr_create!(BTreeMap::<String, usize>::new());
-	file_indexer(input, file_map.clone(), sort_by).await;
-	
-	let (tx, rx) = channel::bounded::<ControlCommand>(channel_size);
-
-	println!("[INFO] Spliting...");
-	if verbose { println!("[VERBOSE] Sending file..."); }
-	let sender_thread = file_sender(input, file_map, tx, core_num);
-
-	let mut join_handles = vec![];
-	for i in 0..core_num {
-		join_handles.push(tokio::spawn(file_receiver(rx.clone(), input.to_string(), output.to_string(), i, verbose, thread_delay)));
-	}
-
-	if let Err(err) = sender_thread.await {
-		println!("[ERROR] Thread communication error (Sender): {}", err);
-		exit(1);
-	}
-	if verbose { println!("[VERBOSE] Send done."); }
-
-	for i in join_handles {
-		if let Err(err) = i.await {
-			println!("[ERROR] Thread communication error (Receiver): {}", err);
-			exit(1);
-		}
-	}
-
-	println!("[INFO] Split completed! Time: {}ms.", (Instant::now() - begin).as_millis());
-}
-
-async fn file_indexer(input: &str, file_map: ArcPinnedPtr<BTreeMap<String, usize>>, sort_by: &str) {
-	let file_map = Arc::downgrade(&file_map);
-	let sort_by = String::from(sort_by);
-	if let Err(err) = index_zip_single_thread(input, ZipCallback::new(move |x, i, _| {
-		if let Some(file_map) = file_map.upgrade() {
-			let sort_by = match &sort_by[..] {
-				"name" => x.name().to_string(),
-				"size" => format!("{}-{}", x.size(), x.name()),
-				"time" => format!("{}-{}", x.last_modified().to_time().unwrap().unix_timestamp_nanos(), x.name()),
-				_ => {
-					println!("[ERROR] Sort field must be \"name\" or \"size\" or \"time\"");
-					exit(1);
-				}
-			};
-			file_map.lock().unwrap().insert(sort_by, i);
-		}
-	})) {
-		println!("[ERROR] Index failed: {}", err);
-		exit(1);
-	}
-}
-
-async fn file_receiver(
-	rx: Receiver<ControlCommand>,
-	input: String,
-	output: String,
-	index: usize,
-	verbose: bool,
-	thread_delay: usize
-) -> Result<()> {
-	let mut path = PathBuf::from(output);
-	match PathBuf::from(input).file_stem() {
-		Some(fname) => path.push(format!("{}-{:03}.zip", fname.to_str().unwrap(), index)),
-		None => path.push(format!("{:03}.zip", index))
-	};
-	if verbose { println!("[RECV {}] Thread initializing...", index); }
-	if thread_delay > 0 { sleep(Duration::from_millis(thread_delay as u64)).await; }
-	if verbose { println!("[RECV {}] Thread initialized.", index); }
-	let mut archive_file = ZipWriter::new(BufWriter::new(File::create(path)?)); {
-		loop {
-			if let Ok(cmd) = rx.recv() {
-				match cmd {
-					ControlCommand::FileSend(fname, fcontent) => {
-						if verbose { println!("[RECV {}] File {} received.", index, fname); }
-						archive_file.start_file(fname, FileOptions::default())?;
-						archive_file.write_all(&fcontent)?;
-					},
-					_ => { break; }
-				}
-			}
-		}
-	}
-	if verbose { println!("[RECV {}] Thread done.", index); }
-	archive_file.finish()?;
-
-	Ok(())
-}
-
-
-async fn file_sender(
-	input: &str,
-	file_map: ArcPinnedPtr<BTreeMap<String, usize>>,
-	tx: Sender<ControlCommand>,
-	core_num: usize
-) -> Result<()> {
-	let mut archive_file = ZipArchive::new(BufReader::new(File::open(input)?))?;
-	// let mut a = 0;
-	for (_, i) in file_map.lock().unwrap().iter() {
-		let zip_file = &mut archive_file.by_index(*i)?;
-		let name = String::from(zip_file.name());
-		let mut vec = Vec::<u8>::with_capacity(zip_file.size() as usize);
-		io::copy(zip_file, &mut vec)?;
-		tx.send(ControlCommand::FileSend(name, vec))?;
-		// a += 1;
-		// println!("[INFO] Split {} file(s).", a);
-	}
-
-	for _ in 0..core_num {
-		tx.send(ControlCommand::Shutdown)?;
-	}
-
-	Ok(())
-}
\ No newline at end of file
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::time::{Duration, Instant};
+
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crossbeam::channel::{self, Sender, Receiver};
+use anyhow::{anyhow, bail, Result};
+use tracing::{debug, error, info, warn};
+use tokio::time::sleep;
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+use crate::arc_pinned_ptr_create;
+use crate::utils::ArcPinnedPtr;
+use crate::utils::index_callback::ZipCallback;
+use crate::utils::archive::{open_buffered, ArchiveEntry, LooseFile};
+use crate::utils::index_zip::{index_zip_single_thread, iter_dir, partition_indices};
+use crate::utils::progress::WorkerProgress;
+use crate::utils::zip_stream::StreamZipWriter;
+
+pub struct SplitOptions<'a> {
+	// Writer threads, one per part
+	pub core_num: usize,
+	// Threads reading the entries from the inputs, each over its own run of the sorted entries
+	pub reader_threads: usize,
+	pub channel_size: usize,
+	// Bytes of entry data queued for the receivers, on top of channel_size
+	pub max_memory: Option<u64>,
+	pub thread_delay: usize,
+	pub quiet: bool,
+	pub force: bool,
+	pub sort_by: &'a str,
+	// Entry names one per line, placed in that order ahead of the rest, which sort_by still orders
+	pub order_file: Option<String>,
+	pub include: Vec<Pattern>,
+	pub exclude: Vec<Pattern>,
+	// Removed from the start of every entry name, entries lacking it being an error unless strip_prefix_optional
+	pub strip_prefix: Option<String>,
+	pub strip_prefix_optional: bool,
+	pub password: Option<String>,
+	pub checksum: bool,
+	// Draw per-receiver progress instead of relying on log lines
+	pub progress: bool,
+	// Names the parts instead of "{stem}-{index}.zip", see part_names
+	pub name_template: Option<String>,
+	// One part per first path component of the entries, named after it, instead of core_num parts
+	pub per_top_level: bool
+}
+
+// Written next to the parts as "{stem}-manifest.json", listing where every entry went
+#[derive(Serialize, Deserialize)]
+pub(super) struct Manifest {
+	// File name of the split input, which the part names derive from
+	pub source: String,
+	pub parts: usize,
+	// Part file names, only recorded when they came from --name-template
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub part_names: Vec<String>,
+	pub entries: Vec<ManifestEntry>
+}
+
+impl Manifest {
+	pub fn read(path: &Path) -> Result<Self> {
+		serde_json::from_str(&fs::read_to_string(path)?)
+			.map_err(|err| anyhow!("Cannot read manifest {}: {}", path.display(), err))
+	}
+
+	pub fn part_name(&self, index: usize) -> String {
+		self.part_names.get(index).cloned().unwrap_or_else(|| part_file_name(&self.source, index))
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct ManifestEntry {
+	pub name: String,
+	pub part: usize,
+	pub size: u64,
+	// SHA-256 of the decompressed data, only with --checksum
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub sha256: Option<String>
+}
+
+// Name of the index-th part written from input
+pub(super) fn part_file_name(input: &str, index: usize) -> String {
+	match Path::new(input).file_stem() {
+		Some(fname) => format!("{}-{:03}.zip", fname.to_string_lossy(), index),
+		None => format!("{:03}.zip", index)
+	}
+}
+
+// Names of the parts written from input, from template when given. The template may hold {stem},
+// {index} (three digits) and, given units, {unit}, and gets ".zip" appended unless it already ends
+// with it. Parts of units are named "{unit}.zip" without a template.
+fn part_names(input: &str, parts: usize, template: Option<&str>, units: Option<&[String]>) -> Result<Vec<String>> {
+	let template = match (template, units) {
+		(Some(template), _) => template,
+		(None, Some(_)) => "{unit}.zip",
+		(None, None) => return Ok((0..parts).map(|x| part_file_name(input, x)).collect())
+	};
+	if template.contains("{unit}") && units.is_none() {
+		bail!("{{unit}} needs --per-top-level");
+	}
+	let stem = Path::new(input).file_stem().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+	let mut names = vec![];
+	for index in 0..parts {
+		let mut name = template.replace("{stem}", &stem).replace("{index}", &format!("{:03}", index));
+		if let Some(units) = units { name = name.replace("{unit}", &units[index]); }
+		if name.contains(['{', '}']) { bail!("Unknown placeholder in name template {}", template); }
+		if name.is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+			bail!("Name template {} must yield plain file names", template);
+		}
+		let name = if name.to_lowercase().ends_with(".zip") { name } else { format!("{}.zip", name) };
+		if names.contains(&name) { bail!("Name template {} yields {} more than once, add {{index}}", template, name); }
+		names.push(name);
+	}
+	Ok(names)
+}
+
+pub(super) fn manifest_file_name(input: &str) -> String {
+	match Path::new(input).file_stem() {
+		Some(fname) => format!("{}-manifest.json", fname.to_string_lossy()),
+		None => String::from("manifest.json")
+	}
+}
+
+// Hashes what is read through it, so that entries are hashed while being sent
+pub(super) struct HashReader<'a> {
+	inner: &'a mut dyn Read,
+	hasher: Option<Sha256>
+}
+
+impl<'a> HashReader<'a> {
+	pub fn new(inner: &'a mut dyn Read, checksum: bool) -> Self {
+		Self { inner, hasher: if checksum { Some(Sha256::new()) } else { None } }
+	}
+
+	pub fn finish(self) -> Option<String> {
+		self.hasher.map(|x| x.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+	}
+}
+
+impl Read for HashReader<'_> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let read = self.inner.read(buf)?;
+		if let Some(hasher) = &mut self.hasher { hasher.update(&buf[..read]); }
+		Ok(read)
+	}
+}
+
+// Entries larger than this are streamed to the receiver in chunks of this size
+// instead of being buffered whole.
+const STREAM_CHUNK_SIZE: usize = 1024 * 1024;
+const STREAM_CHANNEL_SIZE: usize = 4;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+// Entries from this size on are written with Zip64 headers. The margin covers deflate growing
+// incompressible data a little.
+const ZIP64_THRESHOLD: u64 = u32::MAX as u64 - 16 * 1024 * 1024;
+
+// First path component of an entry name. Components that cannot name a file become "_".
+fn top_level_unit(name: &str) -> &str {
+	match name.trim_start_matches('/').split('/').next() {
+		Some("" | "." | "..") | None => "_",
+		Some(unit) => unit
+	}
+}
+
+// Where split reads the entries from, one per input
+enum SplitSource {
+	Zip(String),
+	// Loose files under a directory, in the order of their entry index
+	Dir(Vec<LooseFile>)
+}
+
+impl SplitSource {
+	fn open(input: &str) -> Result<Self> {
+		File::open(input).map_err(|err| anyhow!("Cannot open file {}: {}", input, err))?;
+		if Path::new(input).is_dir() { Ok(SplitSource::Dir(collect_loose_files(input)?)) }
+		else { Ok(SplitSource::Zip(input.to_string())) }
+	}
+}
+
+// Expands glob patterns among the inputs, keeping their order and dropping repeated paths
+fn expand_inputs(inputs: &[String]) -> Result<Vec<String>> {
+	let mut expanded: Vec<String> = vec![];
+	for input in inputs {
+		if !input.contains(['*', '?', '[']) {
+			if !expanded.contains(input) { expanded.push(input.clone()); }
+			continue;
+		}
+		let mut matched = false;
+		for path in glob::glob(input).map_err(|err| anyhow!("Invalid pattern {}: {}", input, err))? {
+			let path = path?.to_string_lossy().into_owned();
+			matched = true;
+			if !expanded.contains(&path) { expanded.push(path); }
+		}
+		if !matched { bail!("No file matches {}", input); }
+	}
+	if expanded.len() > 1 && expanded.iter().any(|x| x == "-") {
+		bail!("Stdin (\"-\") cannot be combined with other inputs");
+	}
+	Ok(expanded)
+}
+
+enum ControlCommand {
+	FileSend(String, Vec<u8>),
+	// Chunks are sent through a dedicated channel, so that the whole entry ends up in one receiver
+	FileStream(String, u64, Receiver<Vec<u8>>),
+	Shutdown
+}
+
+// Bytes of entry data between the sender and the receivers. The sender takes them before sending and
+// the receivers give them back once written.
+struct MemoryBudget {
+	limit: u64,
+	used: Mutex<u64>,
+	freed: Condvar,
+	closed: AtomicBool
+}
+
+impl MemoryBudget {
+	fn new(limit: u64) -> Self {
+		Self { limit, used: Mutex::new(0), freed: Condvar::new(), closed: AtomicBool::new(false) }
+	}
+
+	// Blocks until size fits. An entry larger than the limit waits for everything else to be written.
+	fn acquire(&self, size: u64) {
+		let mut used = self.used.lock().unwrap();
+		while *used > 0 && *used + size > self.limit && !self.closed.load(Ordering::Relaxed) {
+			used = self.freed.wait(used).unwrap();
+		}
+		*used += size;
+	}
+
+	// Counts size without waiting. Chunks of a streamed entry take this, as the receiver writing the
+	// entry may only free anything once they arrive, and their own channel bounds them already.
+	fn add(&self, size: u64) {
+		*self.used.lock().unwrap() += size;
+	}
+
+	fn release(&self, size: u64) {
+		let mut used = self.used.lock().unwrap();
+		*used = used.saturating_sub(size);
+		self.freed.notify_all();
+	}
+
+	// Lifts the limit, so that the sender does not wait on receivers which stopped
+	fn close(&self) {
+		let _used = self.used.lock().unwrap();
+		self.closed.store(true, Ordering::Relaxed);
+		self.freed.notify_all();
+	}
+}
+
+// Closes the budget when a receiver returns, whether it finished or failed
+struct BudgetGuard(Option<Arc<MemoryBudget>>);
+
+impl Drop for BudgetGuard {
+	fn drop(&mut self) {
+		if let Some(budget) = &self.0 { budget.close(); }
+	}
+}
+
+// Reads the chunks of a streamed entry as they arrive
+struct ChunkReader {
+	rx: Receiver<Vec<u8>>,
+	chunk: Vec<u8>,
+	pos: usize,
+	budget: Option<Arc<MemoryBudget>>
+}
+
+impl ChunkReader {
+	fn release_chunk(&mut self) {
+		if let Some(budget) = &self.budget { budget.release(self.chunk.len() as u64); }
+		self.chunk = vec![];
+		self.pos = 0;
+	}
+}
+
+impl Read for ChunkReader {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		while self.pos >= self.chunk.len() {
+			self.release_chunk();
+			match self.rx.recv() {
+				Ok(chunk) => { self.chunk = chunk; },
+				Err(_) => return Ok(0)
+			}
+		}
+		let read = buf.len().min(self.chunk.len() - self.pos);
+		buf[..read].copy_from_slice(&self.chunk[self.pos..self.pos + read]);
+		self.pos += read;
+		Ok(read)
+	}
+}
+
+impl Drop for ChunkReader {
+	fn drop(&mut self) {
+		self.release_chunk();
+		if let Some(budget) = &self.budget {
+			for chunk in self.rx.try_iter() { budget.release(chunk.len() as u64); }
+		}
+	}
+}
+
+// The zip crate cannot encrypt, so encrypted parts are written by StreamZipWriter instead
+enum PartWriter {
+	Plain(ZipWriter<BufWriter<File>>),
+	Encrypted(StreamZipWriter<BufWriter<File>>)
+}
+
+impl PartWriter {
+	fn add(&mut self, name: String, size: u64, reader: &mut dyn Read) -> Result<()> {
+		match self {
+			PartWriter::Plain(archive_file) => {
+				archive_file.start_file(name, FileOptions::default().large_file(size >= ZIP64_THRESHOLD))?;
+				io::copy(reader, archive_file)?;
+			},
+			PartWriter::Encrypted(archive_file) => { archive_file.add(&name, reader)?; }
+		}
+		Ok(())
+	}
+
+	fn finish(self) -> Result<()> {
+		match self {
+			PartWriter::Plain(mut archive_file) => { archive_file.finish()?; },
+			PartWriter::Encrypted(archive_file) => { archive_file.finish()?; }
+		}
+		Ok(())
+	}
+}
+
+fn remove_target(input: &str, force: bool) -> Result<()> {
+	let meta = fs::metadata(input)?;
+	if meta.is_dir() {
+		if force {
+			warn!("Destination is a directroy. Removing because of the force flag.");
+			fs::remove_dir_all(input).map_err(|err| anyhow!("Cannot remove directory: {}", err))?;
+		}
+		else if let Err(err) = fs::remove_dir(input) {
+			bail!("Cannot remove directory: {}\n[INFO] Add \"--force\" to remove non-empty directories.", err);
+		}
+	}
+	else {
+		if force { warn!("Destination already exists. Removing because of the force flag."); }
+		fs::remove_file(input).map_err(|err| anyhow!("Cannot remove file: {}", err))?;
+	}
+	Ok(())
+}
+
+// Removes an existing target, asking first unless force is set. Quiet never asks, so without force
+// it fails instead. Returns false if the user declined.
+pub(super) fn clear_target(output: &str, quiet: bool, force: bool) -> Result<bool> {
+	if PathBuf::from(output).exists() {
+		if force { remove_target(output, force)?; }
+		else if quiet { bail!("Target already exists. Add \"--force\" to overwrite it."); }
+		else {
+			print!("Target already exists. Overwrite? [y/N]: ");
+			io::stdout().flush()?;
+			let mut buffer = String::with_capacity(255);
+			if let Err(err) = io::stdin().read_line(&mut buffer) {
+				error!("{}", err);
+			}
+			let buffer = buffer.trim().to_lowercase();
+			match &buffer[..] {
+				"y" | "yes" => { remove_target(output, force)?; },
+				_ => return Ok(false)
+			}
+		}
+	}
+	Ok(true)
+}
+
+// A copy of stdin in a temporary directory, removed when dropped
+struct StdinCopy {
+	dir: PathBuf,
+	path: String
+}
+
+impl StdinCopy {
+	fn create() -> Result<Self> {
+		let dir = std::env::temp_dir().join(format!("zip_handler-{}", std::process::id()));
+		fs::create_dir_all(&dir).map_err(|err| anyhow!("Cannot create directory {}: {}", dir.display(), err))?;
+		// Parts are named after the file, so they come out as stdin-NNN.zip
+		let path = dir.join("stdin.zip");
+		let copy = Self { path: path.to_string_lossy().into_owned(), dir };
+		let mut file = BufWriter::new(File::create(&path)?);
+		let size = io::copy(&mut io::stdin().lock(), &mut file)?;
+		file.flush()?;
+		debug!("Buffered {} bytes from stdin to {}.", size, copy.path);
+		Ok(copy)
+	}
+}
+
+impl Drop for StdinCopy {
+	fn drop(&mut self) {
+		if let Err(err) = fs::remove_dir_all(&self.dir) {
+			warn!("Cannot remove {}: {}", self.dir.display(), err);
+		}
+	}
+}
+
+// Splits the entries of every input into the parts. Parts and manifest are named after the first input,
+// and an entry name found in several inputs is taken from the first one holding it.
+pub async fn split_archive_files(inputs: &[String], output: &str, options: SplitOptions<'_>) -> Result<()> {
+	let inputs = expand_inputs(inputs)?;
+	let input = &inputs[0][..];
+	// ZipArchive needs to seek, so "-" is copied to a temporary file first
+	let stdin_copy = if input == "-" {
+		if !options.force && PathBuf::from(output).exists() {
+			bail!("Target already exists. Add \"--force\" to overwrite it, as stdin holds the archive.");
+		}
+		info!("Reading archive from stdin...");
+		Some(StdinCopy::create()?)
+	} else { None };
+	let input = stdin_copy.as_ref().map(|x| &x.path[..]).unwrap_or(input);
+
+	let mut sources = vec![SplitSource::open(input)?];
+	for input in &inputs[1..] {
+		sources.push(SplitSource::open(input)?);
+	}
+
+	let SplitOptions { core_num, reader_threads, channel_size, max_memory, thread_delay, quiet, force, sort_by, order_file, include, exclude, strip_prefix, strip_prefix_optional, password, checksum, progress, name_template, per_top_level } = options;
+	// Always a whole directory, so that no name is left starting with a slash
+	let strip_prefix = strip_prefix.map(|x| if x.ends_with('/') { x } else { format!("{}/", x) });
+	// Checked before indexing, the units being known only after it
+	if !per_top_level { part_names(input, core_num, name_template.as_deref(), None)?; }
+
+	if !["name", "size", "time", "depth", "ext"].contains(&sort_by) {
+		bail!("Sort field must be \"name\", \"size\", \"time\", \"depth\" or \"ext\"");
+	}
+	let order = order_file.map(|x| read_order_file(&x)).transpose()?;
+	if reader_threads == 0 {
+		bail!("Reader threads must be at least 1");
+	}
+
+	if !clear_target(output, quiet, force)? { return Ok(()); }
+
+	fs::create_dir_all(output).map_err(|err| anyhow!("Cannot create directory {}: {}", output, err))?;
+
+	let begin = Instant::now();
+	info!("Indexing...");
+
+	let file_map = arc_pinned_ptr_create!(BTreeMap::<String, (usize, usize)>::new());
+	let rules = NameRules { include, exclude, strip_prefix: strip_prefix.clone(), strip_prefix_optional };
+	let skipped = file_indexer(&sources, file_map.clone(), sort_by, order, rules, per_top_level).await?;
+
+	// Sort keys are prefixed with the unit and a slash with per_top_level, which keeps every unit together
+	let units = per_top_level.then(|| {
+		let mut units = Vec::<(String, BTreeMap<String, (usize, usize)>)>::new();
+		for (key, value) in std::mem::take(&mut **file_map.lock().unwrap()) {
+			let (unit, _) = key.split_once('/').unwrap();
+			match units.last_mut() {
+				Some((last, entries)) if last == unit => { entries.insert(key, value); },
+				_ => units.push((unit.to_string(), BTreeMap::from([(key, value)])))
+			}
+		}
+		units
+	});
+	let parts = units.as_ref().map(|x| x.len()).unwrap_or(core_num);
+	let names = part_names(input, parts, name_template.as_deref(), units.as_ref().map(|x| x.iter().map(|(unit, _)| unit.clone()).collect::<Vec<_>>()).as_deref())?;
+	let total = units.as_ref().map(|x| x.iter().map(|(_, entries)| entries.len()).sum()).unwrap_or(file_map.lock().unwrap().len());
+
+	// Receivers block their threads while waiting, so the bars are redrawn from a thread of their own
+	let progress = progress.then(|| Arc::new(WorkerProgress::new(total, if per_top_level { 1 } else { core_num })));
+	let progress_thread = progress.clone().map(|progress| thread::spawn(move || {
+		while !progress.is_finished() {
+			progress.draw();
+			thread::sleep(PROGRESS_INTERVAL);
+		}
+	}));
+
+	info!("Spliting...");
+	let part_options = PartOptions { reader_threads, channel_size, max_memory, thread_delay, password, checksum, strip_prefix };
+	let mut entries = vec![];
+	match units {
+		// Receivers take whichever entry comes next, so a unit is written by a single receiver at a time
+		Some(units) => for (i, (unit, unit_entries)) in units.into_iter().enumerate() {
+			info!("Writing {} ({} entries) to {}...", unit, unit_entries.len(), names[i]);
+			let paths = [Path::new(output).join(&names[i])];
+			let written = write_parts(&sources, arc_pinned_ptr_create!(unit_entries), &paths, progress.clone(), &part_options).await?;
+			entries.extend(written.into_iter().map(|x| ManifestEntry { part: i, ..x }));
+		},
+		None => {
+			let paths: Vec<PathBuf> = names.iter().map(|x| Path::new(output).join(x)).collect();
+			entries = write_parts(&sources, file_map, &paths, progress.clone(), &part_options).await?;
+		}
+	}
+	if let (Some(progress), Some(progress_thread)) = (progress, progress_thread) {
+		progress.finish();
+		let _ = progress_thread.join();
+		progress.draw();
+	}
+
+	let source = Path::new(input).file_name().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+	let part_names = if name_template.is_some() || per_top_level { names } else { vec![] };
+	let manifest = Manifest { source, parts, part_names, entries };
+	let manifest_path = Path::new(output).join(manifest_file_name(input));
+	fs::write(&manifest_path, serde_json::to_string_pretty(&manifest)?)
+		.map_err(|err| anyhow!("Cannot write manifest {}: {}", manifest_path.display(), err))?;
+
+	info!("Split completed! {} archive(s) written. Time: {}ms.", parts, (Instant::now() - begin).as_millis());
+	if skipped > 0 { info!("Skipped {} entries not matching the filters.", skipped); }
+
+	Ok(())
+}
+
+// How write_parts reads the entries and writes the parts
+struct PartOptions {
+	reader_threads: usize,
+	channel_size: usize,
+	max_memory: Option<u64>,
+	thread_delay: usize,
+	password: Option<String>,
+	checksum: bool,
+	strip_prefix: Option<String>
+}
+
+// Writes the entries of file_map into one part per path, with one receiver for each. Returns them in
+// the order of file_map, each with the part it went to.
+async fn write_parts(
+	sources: &[SplitSource],
+	file_map: ArcPinnedPtr<BTreeMap<String, (usize, usize)>>,
+	paths: &[PathBuf],
+	progress: Option<Arc<WorkerProgress>>,
+	options: &PartOptions
+) -> Result<Vec<ManifestEntry>> {
+	let (tx, rx) = channel::bounded::<ControlCommand>(options.channel_size);
+	let budget = options.max_memory.map(|x| Arc::new(MemoryBudget::new(x)));
+
+	debug!("Sending file...");
+	let sender_thread = file_sender(sources, file_map, tx, budget.clone(), paths.len(), options);
+
+	let mut join_handles = vec![];
+	for (i, path) in paths.iter().enumerate() {
+		join_handles.push(tokio::spawn(file_receiver(rx.clone(), path.clone(), i, options.thread_delay, options.password.clone(), progress.clone(), budget.clone())));
+	}
+
+	let mut entries = sender_thread.await.map_err(|err| anyhow!("Thread communication error (Sender): {}", err))?;
+	debug!("Send done.");
+
+	// entry name -> part it was written to
+	let mut parts = HashMap::new();
+	for (i, handle) in join_handles.into_iter().enumerate() {
+		let names = handle.await.map_err(|err| anyhow!("Thread communication error (Receiver): {}", err))??;
+		parts.extend(names.into_iter().map(|x| (x, i)));
+	}
+
+	for entry in &mut entries {
+		entry.part = parts.get(&entry.name).copied().ok_or(anyhow!("{} was not written to any part", entry.name))?;
+	}
+	Ok(entries)
+}
+
+// Every file under the directory, named by its path relative to it
+fn collect_loose_files(input: &str) -> Result<Vec<LooseFile>> {
+	let mut paths = vec![];
+	iter_dir(Path::new(input), -1, &mut |x| paths.push(x.to_path_buf()))?;
+	paths.into_iter().map(|path| {
+		let name = path.strip_prefix(input)?.to_string_lossy().replace('\\', "/");
+		LooseFile::new(name, path)
+	}).collect()
+}
+
+// Entry name -> line number, the first line naming an entry deciding its place
+fn read_order_file(path: &str) -> Result<HashMap<String, usize>> {
+	let content = fs::read_to_string(path).map_err(|err| anyhow!("Cannot read order file {}: {}", path, err))?;
+	let mut order = HashMap::new();
+	for (i, line) in content.lines().enumerate() {
+		let name = line.trim();
+		if name.is_empty() { continue; }
+		if order.contains_key(name) {
+			warn!("{} is listed more than once in {}. Keeping line {}.", name, path, order[name] + 1);
+			continue;
+		}
+		order.insert(name.to_string(), i);
+	}
+	Ok(order)
+}
+
+// Which entries are split, matched by their name in the input, and the names they are written under
+struct NameRules {
+	include: Vec<Pattern>,
+	exclude: Vec<Pattern>,
+	// Ends with a slash
+	strip_prefix: Option<String>,
+	strip_prefix_optional: bool
+}
+
+impl NameRules {
+	fn selects(&self, name: &str) -> bool {
+		(self.include.is_empty() || self.include.iter().any(|p| p.matches(name))) &&
+		!self.exclude.iter().any(|p| p.matches(name))
+	}
+
+	// None for the prefix directory itself, which is left out
+	fn output_name<'a>(&self, name: &'a str, source: &str) -> Result<Option<&'a str>> {
+		let Some(prefix) = &self.strip_prefix else { return Ok(Some(name)); };
+		match name.strip_prefix(&prefix[..]) {
+			Some("") => Ok(None),
+			Some(stripped) => Ok(Some(stripped)),
+			None if self.strip_prefix_optional => Ok(Some(name)),
+			None => bail!("{} in {} does not start with {}. Add --strip-prefix-optional to keep such entries as they are.", name, source, prefix)
+		}
+	}
+}
+
+// Name an entry is written under, checked by NameRules::output_name while indexing
+fn strip_name<'a>(name: &'a str, strip_prefix: Option<&str>) -> &'a str {
+	strip_prefix.and_then(|x| name.strip_prefix(x)).unwrap_or(name)
+}
+
+// Sort key for the fields that depend on the name alone: name, depth and ext
+fn name_sort_key(sort_by: &str, name: &str) -> String {
+	match sort_by {
+		// Padded so that depth 10 comes after depth 9
+		"depth" => format!("{:05}-{}", name.trim_end_matches('/').matches('/').count(), name),
+		// Extensions hold no slash, names without one come first
+		"ext" => format!("{}/{}", Path::new(name).extension().map(|x| x.to_string_lossy().to_lowercase()).unwrap_or_default(), name),
+		_ => name.to_string()
+	}
+}
+
+// Fills file_map with sort key -> (source, entry index) and returns how many entries the filters skipped.
+// Sorting, the order file and per_top_level, which starts the keys with the top-level unit of the entry
+// and a slash, all go by the names the entries are written under.
+async fn file_indexer(
+	sources: &[SplitSource],
+	file_map: ArcPinnedPtr<BTreeMap<String, (usize, usize)>>,
+	sort_by: &str,
+	order: Option<HashMap<String, usize>>,
+	rules: NameRules,
+	per_top_level: bool
+) -> Result<usize> {
+	let order = Arc::new(order);
+	let rules = Arc::new(rules);
+	let skipped = arc_pinned_ptr_create!(0usize);
+	// Names taken by earlier sources, so that the first input holding a name wins
+	let seen = arc_pinned_ptr_create!(HashSet::<String>::new());
+	let collisions = arc_pinned_ptr_create!(0usize);
+	for (source_index, source) in sources.iter().enumerate() {
+		let file_map = Arc::downgrade(&file_map);
+		let sort_by = String::from(sort_by);
+		let order = order.clone();
+		let rules = rules.clone();
+		let (skipped, seen, collisions) = (skipped.clone(), seen.clone(), collisions.clone());
+		let cb = ZipCallback::new(move |x, i, f| {
+			if !rules.selects(x.name()) {
+				**skipped.lock().unwrap() += 1;
+				return Ok(());
+			}
+			let Some(name) = rules.output_name(x.name(), f)? else { return Ok(()); };
+			if !seen.lock().unwrap().insert(name.to_string()) {
+				warn!("{} from {} is already in an earlier input. Keeping the earlier one.", name, f);
+				**collisions.lock().unwrap() += 1;
+				return Ok(());
+			}
+			if let Some(file_map) = file_map.upgrade() {
+				let sort_by = match &sort_by[..] {
+					"size" => format!("{}-{}", x.size(), name),
+					"time" => format!("{}-{}", x.last_modified()?, name),
+					sort_by => name_sort_key(sort_by, name)
+				};
+				// Listed entries go first by their line, unlisted ones after them
+				let sort_by = match order.as_ref() {
+					Some(order) => match order.get(name) {
+						Some(line) => format!("0-{:020}", line),
+						None => format!("1-{}", sort_by)
+					},
+					None => sort_by
+				};
+				let sort_by = if per_top_level { format!("{}/{}", top_level_unit(name), sort_by) } else { sort_by };
+				file_map.lock().unwrap().insert(sort_by, (source_index, i));
+			}
+			Ok(())
+		});
+		match source {
+			SplitSource::Zip(input) => { index_zip_single_thread(input, cb).map_err(|err| anyhow!("Index failed: {}", err))?; },
+			SplitSource::Dir(files) => {
+				for (i, file) in files.iter().enumerate() {
+					cb.lock().unwrap().exec(file, i, file.path().to_str().unwrap_or_default())?;
+				}
+			}
+		}
+	}
+	if let Some(order) = order.as_ref() {
+		let seen = seen.lock().unwrap();
+		let missing = order.keys().filter(|x| !seen.contains(*x)).count();
+		if missing > 0 { warn!("{} entries in the order file matched nothing to split.", missing); }
+	}
+	let collisions = **collisions.lock().unwrap();
+	if collisions > 0 { warn!("Skipped {} entries found in more than one input.", collisions); }
+	let skipped = **skipped.lock().unwrap();
+	Ok(skipped)
+}
+
+async fn file_receiver(
+	rx: Receiver<ControlCommand>,
+	path: PathBuf,
+	index: usize,
+	thread_delay: usize,
+	password: Option<String>,
+	progress: Option<Arc<WorkerProgress>>,
+	budget: Option<Arc<MemoryBudget>>
+) -> Result<Vec<String>> {
+	let _guard = BudgetGuard(budget.clone());
+	let mut names = vec![];
+	debug!(receiver = index, "Thread initializing...");
+	if thread_delay > 0 { sleep(Duration::from_millis(thread_delay as u64)).await; }
+	debug!(receiver = index, "Thread initialized.");
+	let writer = BufWriter::new(File::create(path)?);
+	let mut archive_file = match &password {
+		Some(password) => PartWriter::Encrypted(StreamZipWriter::with_password(writer, password.as_bytes())),
+		None => PartWriter::Plain(ZipWriter::new(writer))
+	}; {
+		loop {
+			if let Ok(cmd) = rx.recv() {
+				match cmd {
+					ControlCommand::FileSend(fname, fcontent) => {
+						debug!(receiver = index, "File {} received.", fname);
+						let size = fcontent.len() as u64;
+						names.push(fname.clone());
+						let result = archive_file.add(fname, size, &mut &fcontent[..]);
+						if let Some(budget) = &budget { budget.release(size); }
+						result?;
+						if let Some(progress) = &progress { progress.add(index, size); }
+					},
+					ControlCommand::FileStream(fname, size, chunk_rx) => {
+						debug!(receiver = index, "File {} streaming...", fname);
+						names.push(fname.clone());
+						archive_file.add(fname, size, &mut ChunkReader { rx: chunk_rx, chunk: vec![], pos: 0, budget: budget.clone() })?;
+						if let Some(progress) = &progress { progress.add(index, size); }
+					},
+					_ => { break; }
+				}
+			}
+		}
+	}
+	debug!(receiver = index, "Thread done.");
+	archive_file.finish()?;
+
+	Ok(names)
+}
+
+
+fn send_entry(tx: &Sender<ControlCommand>, budget: Option<&MemoryBudget>, name: String, size: u64, reader: &mut dyn Read) -> Result<()> {
+	if size <= STREAM_CHUNK_SIZE as u64 {
+		// Taken before reading, so that the entry is not held in memory while waiting
+		if let Some(budget) = budget { budget.acquire(size); }
+		let mut vec = Vec::<u8>::with_capacity(size as usize);
+		let sent = io::copy(reader, &mut vec).map_err(anyhow::Error::from)
+			.and_then(|_| Ok(tx.send(ControlCommand::FileSend(name, vec))?));
+		if let (Err(_), Some(budget)) = (&sent, budget) { budget.release(size); }
+		sent?;
+	}
+	else {
+		let (chunk_tx, chunk_rx) = channel::bounded::<Vec<u8>>(STREAM_CHANNEL_SIZE);
+		tx.send(ControlCommand::FileStream(name, size, chunk_rx))?;
+		loop {
+			let mut chunk = Vec::<u8>::with_capacity(STREAM_CHUNK_SIZE);
+			let read = io::copy(&mut reader.take(STREAM_CHUNK_SIZE as u64), &mut chunk)?;
+			if read == 0 { break; }
+			if let Some(budget) = budget { budget.add(read); }
+			if let Err(err) = chunk_tx.send(chunk) {
+				if let Some(budget) = budget { budget.release(read); }
+				return Err(err.into());
+			}
+		}
+	}
+	Ok(())
+}
+
+async fn file_sender(
+	sources: &[SplitSource],
+	file_map: ArcPinnedPtr<BTreeMap<String, (usize, usize)>>,
+	tx: Sender<ControlCommand>,
+	budget: Option<Arc<MemoryBudget>>,
+	core_num: usize,
+	options: &PartOptions
+) -> Result<Vec<ManifestEntry>> {
+	let order: Vec<(usize, usize)> = file_map.lock().unwrap().values().copied().collect();
+	// The manifest keeps the sorted order, as every reader's entries are appended in turn
+	let entries = thread::scope(|scope| -> Result<Vec<ManifestEntry>> {
+		let readers: Vec<_> = partition_indices(order.len(), options.reader_threads).into_iter().map(|range| {
+			let (order, tx, budget) = (&order[range], tx.clone(), budget.as_deref());
+			let (checksum, strip_prefix) = (options.checksum, options.strip_prefix.as_deref());
+			scope.spawn(move || read_entries(sources, order, &tx, budget, checksum, strip_prefix))
+		}).collect();
+		let mut entries = vec![];
+		for reader in readers {
+			entries.extend(reader.join().map_err(|_| anyhow!("Thread communication error (Reader)"))??);
+		}
+		Ok(entries)
+	})?;
+
+	for _ in 0..core_num {
+		tx.send(ControlCommand::Shutdown)?;
+	}
+
+	Ok(entries)
+}
+
+// Sends the given (source, entry index) in order, reading zips through handles of its own
+fn read_entries(sources: &[SplitSource], order: &[(usize, usize)], tx: &Sender<ControlCommand>, budget: Option<&MemoryBudget>, checksum: bool, strip_prefix: Option<&str>) -> Result<Vec<ManifestEntry>> {
+	let mut entries = vec![];
+	let mut archive_files = vec![];
+	for source in sources {
+		archive_files.push(match source {
+			SplitSource::Zip(input) => Some(ZipArchive::new(open_buffered(input)?)?),
+			SplitSource::Dir(_) => None
+		});
+	}
+	// let mut a = 0;
+	for (source, i) in order {
+		match (&sources[*source], &mut archive_files[*source]) {
+			(SplitSource::Dir(files), _) => {
+				let file = &files[*i];
+				let mut reader = open_buffered(file.path())?;
+				let mut reader = HashReader::new(&mut reader, checksum);
+				let name = strip_name(file.name(), strip_prefix).to_string();
+				send_entry(tx, budget, name.clone(), file.size(), &mut reader)?;
+				entries.push(ManifestEntry { name, part: 0, size: file.size(), sha256: reader.finish() });
+			},
+			(SplitSource::Zip(_), Some(archive_file)) => {
+				let zip_file = &mut archive_file.by_index(*i)?;
+				let (name, size) = (strip_name(zip_file.name(), strip_prefix).to_string(), zip_file.size());
+				let mut reader = HashReader::new(zip_file, checksum);
+				send_entry(tx, budget, name.clone(), size, &mut reader)?;
+				entries.push(ManifestEntry { name, part: 0, size, sha256: reader.finish() });
+			},
+			_ => unreachable!()
+		}
+		// a += 1;
+		// info!("Split {} file(s).", a);
+	}
+
+	Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sorted(sort_by: &str, names: &[&'static str]) -> Vec<&'static str> {
+		let mut names = names.to_vec();
+		names.sort_by_key(|x| name_sort_key(sort_by, x));
+		names
+	}
+
+	#[test]
+	fn depth_sorts_shallow_entries_first() {
+		assert_eq!(name_sort_key("depth", "a/b/c.txt"), "00002-a/b/c.txt");
+		// Directory entries count at the depth of their own name
+		assert_eq!(name_sort_key("depth", "a/b/"), "00001-a/b/");
+		let deep = "1/2/3/4/5/6/7/8/9/10/x";
+		let nine = "1/2/3/4/5/6/7/8/9/x";
+		assert_eq!(sorted("depth", &[deep, "z.txt", nine, "a/y"]), ["z.txt", "a/y", nine, deep]);
+	}
+
+	#[test]
+	fn ext_groups_by_lowercase_extension() {
+		assert_eq!(name_sort_key("ext", "docs/Readme.MD"), "md/docs/Readme.MD");
+		assert_eq!(name_sort_key("ext", "Makefile"), "/Makefile");
+		assert_eq!(name_sort_key("ext", "src/"), "/src/");
+		assert_eq!(sorted("ext", &["b.txt", "a.rs", "LICENSE", "c.TXT", "bin/run"]), ["LICENSE", "bin/run", "a.rs", "b.txt", "c.TXT"]);
+	}
+
+	fn strip_rules(prefix: &str, optional: bool) -> NameRules {
+		NameRules { include: vec![], exclude: vec![], strip_prefix: Some(prefix.to_string()), strip_prefix_optional: optional }
+	}
+
+	#[test]
+	fn output_name_strips_the_prefix() {
+		let rules = strip_rules("project/", false);
+		assert_eq!(rules.output_name("project/src/main.rs", "in.zip").unwrap(), Some("src/main.rs"));
+		assert_eq!(rules.output_name("project/src/", "in.zip").unwrap(), Some("src/"));
+		// Only whole leading directories are stripped
+		assert!(rules.output_name("project2/a.txt", "in.zip").is_err());
+		assert_eq!(strip_name("project/src/main.rs", Some("project/")), "src/main.rs");
+	}
+
+	#[test]
+	fn output_name_requires_the_prefix_unless_optional() {
+		let err = strip_rules("project/", false).output_name("other/a.txt", "in.zip").unwrap_err();
+		assert!(err.to_string().contains("other/a.txt in in.zip does not start with project/"));
+		assert_eq!(strip_rules("project/", true).output_name("other/a.txt", "in.zip").unwrap(), Some("other/a.txt"));
+		let rules = NameRules { include: vec![], exclude: vec![], strip_prefix: None, strip_prefix_optional: false };
+		assert_eq!(rules.output_name("other/a.txt", "in.zip").unwrap(), Some("other/a.txt"));
+	}
+
+	#[test]
+	fn output_name_drops_the_prefix_directory() {
+		assert_eq!(strip_rules("project/", false).output_name("project/", "in.zip").unwrap(), None);
+		assert_eq!(strip_rules("a/b/", true).output_name("a/b/", "in.zip").unwrap(), None);
+		// Its parent is not the prefix, so it is kept as it is or refused
+		assert_eq!(strip_rules("a/b/", true).output_name("a/", "in.zip").unwrap(), Some("a/"));
+	}
+
+	#[test]
+	fn name_keys_are_the_name() {
+		assert_eq!(name_sort_key("name", "a/b.txt"), "a/b.txt");
+	}
+}
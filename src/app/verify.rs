@@ -0,0 +1,116 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::{anyhow, bail, Result};
+use tracing::{debug, info, warn};
+use zip::ZipArchive;
+
+use super::split::{HashReader, Manifest};
+
+// Unbuffered on purpose: zip's ZipCrypto reader decrypts the whole buffer even on a short read,
+// which a BufReader returns at its boundaries
+type PartReader = ZipArchive<File>;
+
+// Every manifest written by split under the directory
+pub(super) fn collect_manifests(input: &str) -> Result<Vec<PathBuf>> {
+	let mut manifests = vec![];
+	for entry in fs::read_dir(input).map_err(|err| anyhow!("Cannot open directory {}: {}", input, err))? {
+		let path = entry?.path();
+		if path.file_name().and_then(|x| x.to_str()).is_some_and(|x| x.ends_with("manifest.json")) {
+			manifests.push(path);
+		}
+	}
+	if manifests.is_empty() { bail!("No split manifest (*-manifest.json) found under {}", input); }
+	manifests.sort();
+	Ok(manifests)
+}
+
+// Re-reads every entry listed in the manifests and compares it against them
+pub async fn verify_split_files(input: &str, password: Option<&str>) -> Result<()> {
+	let begin = Instant::now();
+	let mut problems = 0usize;
+	let mut checked = 0usize;
+
+	for manifest_path in collect_manifests(input)? {
+		info!("Verifying against {}...", manifest_path.display());
+		let manifest = Manifest::read(&manifest_path)?;
+
+		let mut parts = BTreeMap::<usize, Option<PartReader>>::new();
+		for i in 0..manifest.parts {
+			let path = Path::new(input).join(manifest.part_name(i));
+			let part = File::open(&path).map_err(anyhow::Error::from)
+				.and_then(|x| Ok(ZipArchive::new(x)?));
+			match part {
+				Ok(part) => { parts.insert(i, Some(part)); },
+				Err(err) => {
+					warn!("Cannot open part {}: {}", path.display(), err);
+					problems += 1;
+					parts.insert(i, None);
+				}
+			}
+		}
+
+		let listed: HashSet<&str> = manifest.entries.iter().map(|x| &x.name[..]).collect();
+		for (i, part) in parts.iter_mut() {
+			let Some(part) = part else { continue; };
+			for name in part.file_names().filter(|x| !listed.contains(x)) {
+				warn!("{} in part {:03} is not in the manifest", name, i);
+				problems += 1;
+			}
+		}
+
+		for entry in &manifest.entries {
+			let Some(Some(part)) = parts.get_mut(&entry.part) else {
+				// A missing part has already been reported
+				if !parts.contains_key(&entry.part) {
+					warn!("{} is listed in part {:03}, which is beyond the {} parts", entry.name, entry.part, manifest.parts);
+					problems += 1;
+				}
+				continue;
+			};
+			let zip_file = match password {
+				Some(password) => part.by_name_decrypt(&entry.name, password.as_bytes())
+					.map_err(anyhow::Error::from)
+					.and_then(|x| x.map_err(|_| anyhow!("Wrong password"))),
+				None => part.by_name(&entry.name).map_err(anyhow::Error::from)
+			};
+			let mut zip_file = match zip_file {
+				Ok(zip_file) => zip_file,
+				Err(err) => {
+					warn!("Cannot read {} in part {:03}: {}", entry.name, entry.part, err);
+					problems += 1;
+					continue;
+				}
+			};
+			// Reading to the end also checks the CRC
+			let mut reader = HashReader::new(&mut zip_file, entry.sha256.is_some());
+			let size = match io::copy(&mut reader, &mut io::sink()) {
+				Ok(size) => size,
+				Err(err) => {
+					warn!("{} in part {:03} is corrupt: {}", entry.name, entry.part, err);
+					problems += 1;
+					continue;
+				}
+			};
+			checked += 1;
+			if size != entry.size {
+				warn!("{} in part {:03} has {} bytes instead of {}", entry.name, entry.part, size, entry.size);
+				problems += 1;
+			}
+			else if reader.finish() != entry.sha256 {
+				warn!("{} in part {:03} does not match its checksum", entry.name, entry.part);
+				problems += 1;
+			}
+			else {
+				debug!("{} OK.", entry.name);
+			}
+		}
+	}
+
+	info!("Verified {} entries. Time: {}ms.", checked, (Instant::now() - begin).as_millis());
+	if problems > 0 { bail!("Verification failed with {} problem(s)", problems); }
+	Ok(())
+}
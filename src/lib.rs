@@ -0,0 +1,10 @@
+pub mod utils;
+pub mod app;
+
+pub use app::serve::{launch, spawn, IndexOptions, ServeHandle, ServeOptions};
+pub use app::split::{split_archive_files, SplitOptions};
+pub use utils::archive::ArchiveEntry;
+pub use utils::index_callback::ZipCallback;
+pub use utils::index_zip::{index_zip_dir, index_zip_dir_single_thread, index_zip_multi_thread, index_zip_single_thread};
+#[cfg(feature = "rayon")]
+pub use utils::index_zip::index_zip_rayon;
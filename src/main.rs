@@ -1,55 +1,190 @@
-use clap::{command, arg, Command};
+use std::io;
+use std::process::exit;
+
+use clap::{command, arg, ArgAction, ArgMatches, Command};
+use clap::parser::ValueSource;
 use anyhow::Result;
+use tracing::{error, level_filters::LevelFilter};
 
-mod utils;
-mod app;
+use zip_handler::app;
+use zip_handler::utils::json_log::JsonFormat;
 
 #[tokio::main]
 async fn main() -> Result<()> {
 	let matches = command!()
+		.arg(arg!(log_level: --"log-level" <LEVEL> "Logging verbosity (off, error, warn, info, debug, trace)").global(true).default_value("info"))
+		.arg(arg!(log_format: --"log-format" <FORMAT> "Log as plain text or as one JSON object per line").global(true).value_parser(["text", "json"]).default_value("text"))
 		.subcommand(
 			Command::new("bench")
 			.about("Benchmark")
 			.arg(arg!(-f --file <FILE> "Open zip file").required_unless_present("dir").conflicts_with("dir"))
 			.arg(arg!(-d --dir <DIR> "Open all zip files under a directory").required_unless_present("file").conflicts_with("file"))
-			.arg(arg!(--depth <DEPTH> "How deep the iteration to subdirectories goes (-1 as infinite)").default_value("-1").conflicts_with("file"))
+			.arg(arg!(--depth <DEPTH> "How deep the iteration to subdirectories goes (-1 as infinite)").allow_negative_numbers(true).default_value("-1").conflicts_with("file"))
 			.arg(arg!(-j --jobs <CORE_NUMBER> "How many thread to spawn").default_value("4"))
+			.arg(arg!(--format <FORMAT> "Output format of the results (text, json, csv)").default_value("text"))
+			.arg(arg!(-n --iterations <ITERATIONS> "How many measured runs per scenario").default_value("1"))
+			.arg(arg!(--warmup <WARMUP> "How many runs to discard before measuring").default_value("0"))
+			.arg(arg!(--sweep <MAX> "Run the MT scenario with 1, 2, 4, ... up to MAX threads").conflicts_with("dir"))
+			.arg(arg!(serve_read: --"serve-read" "Time reading every entry the way serve does, without and with the entry cache").requires("file").conflicts_with("sweep"))
+			.arg(arg!(serve_list: --"serve-list" "Time gathering the children of every directory the way serve lists them, against a scan of the whole index").requires("file").conflicts_with_all(["sweep", "serve_read"]))
+			.arg(arg!(cache_size: --"cache-size" <BYTES> "Entry cache size for --serve-read").default_value("268435456"))
+			.arg(arg!(read_buffer: --"read-buffer" <BYTES> "Buffer size for reading archives").default_value("8192"))
 		)
 		.subcommand(
 			Command::new("split")
 			.about("Split a zip file into several self-contained ones")
-			.arg(arg!(-i --input <INPUT_FILES> "Open zip file").required(true))
+			.arg(arg!(-i --input <INPUT_FILES> "Zip files or directories to split, or glob patterns matching them (- reads a zip from stdin)").required(true).num_args(1..).action(ArgAction::Append))
 			.arg(arg!(-o --output <OUTPUT_DIR> "Destnation directory").required(true))
 			.arg(arg!(-j --jobs <CORE_NUMBER> "How many threads to spawn").default_value("4"))
+			.arg(arg!(reader_threads: --"reader-threads" <THREAD_NUMBER> "How many threads read the entries from the inputs").default_value("1"))
 			.arg(arg!(-c --chunks <CHUNK_NUMBER> "How many archives to split into (default is thread number)"))
 			.arg(arg!(channel_size: --"channel-size" <CHANNEL_SIZE> "How many files to cache into the memory").default_value("512"))
+			.arg(arg!(max_memory: --"max-memory" <BYTES> "Cap the bytes of entry data held in memory between reading and writing"))
+			.arg(arg!(read_buffer: --"read-buffer" <BYTES> "Buffer size for reading archives").default_value("8192"))
 			.arg(arg!(thread_delay: --"thread-delay" <THREAD_DELAY> "How many milliseconds to wait until the thread begins to write").default_value("0"))
-			.arg(arg!(sort_by: --"sort-by" <SORT_FIELD> "Which field to sort against (name, time, size)").default_value("name"))
+			.arg(arg!(sort_by: --"sort-by" <SORT_FIELD> "Which field to sort against (name, time, size, depth, ext)").default_value("name"))
+			.arg(arg!(order_file: --"order-file" <PATH> "Place the entry names listed in this file, one per line, first and in that order"))
 			.arg(arg!(unit_depth: --"unit-depth" <UNIT_PATH> "At what depth the subdirectory shall be regarded as a single unit to split"))
-			.arg(arg!(-q --quiet "Overwrite file if exists"))
+			.arg(arg!(--include <GLOB> "Only split entries whose name matches this pattern (repeatable)").action(ArgAction::Append))
+			.arg(arg!(--exclude <GLOB> "Skip entries whose name matches this pattern (repeatable)").action(ArgAction::Append))
+			.arg(arg!(strip_prefix: --"strip-prefix" <PREFIX> "Remove this leading directory from every entry name, failing on entries outside it"))
+			.arg(arg!(strip_prefix_optional: --"strip-prefix-optional" "With --strip-prefix, keep entries outside the directory under their own names").requires("strip_prefix"))
+			.arg(arg!(--encrypt <PASSWORD> "Encrypt every output archive with this password (ZipCrypto)"))
+			.arg(arg!(--checksum "Record a SHA-256 of every entry in the manifest"))
+			.arg(arg!(name_template: --"name-template" <TEMPLATE> "Name the output archives after this, with {stem} and {index} placeholders, and {unit} with --per-top-level"))
+			.arg(arg!(per_top_level: --"per-top-level" "Write one archive per top-level directory or file of the entries, named after it, instead of one per thread"))
+			.arg(arg!(--progress "Show overall progress and the files and throughput of every thread"))
+			.arg(arg!(-q --quiet "Only log warnings and errors, and never ask before overwriting"))
+			.arg(arg!(-f --force "Overwrite the output if it exists, even a non-empty directory"))
 			.arg(arg!(-v --verbose "Verbose logging to terminal"))
 		)
+		.subcommand(
+			Command::new("merge")
+			.about("Merge the archives written by split back into one")
+			.arg(arg!(-i --input <INPUT_DIR> "Directory holding the split archives").required(true))
+			.arg(arg!(-o --output <OUTPUT_FILE> "Destination zip file").required(true))
+			.arg(arg!(-q --quiet "Only log warnings and errors, and never ask before overwriting"))
+			.arg(arg!(-f --force "Overwrite the output if it exists, even a non-empty directory"))
+		)
+		.subcommand(
+			Command::new("verify")
+			.about("Check the archives written by split against their manifest")
+			.arg(arg!(-i --input <INPUT_DIR> "Directory holding the split archives").required(true))
+			.arg(arg!(--password <PASSWORD> "Password the archives were encrypted with"))
+		)
+		.subcommand(
+			Command::new("selftest")
+			.about("Split a zip, merge the parts back and check that every entry survived")
+			.arg(arg!(-i --input <INPUT_FILE> "Zip file to test with").required(true))
+			.arg(arg!(-j --jobs <CORE_NUMBER> "How many parts to split into").default_value("4"))
+			.arg(arg!(work_dir: --"work-dir" <DIR> "Where to write the parts and the merged zip, which must not exist yet (default is a temporary directory)"))
+		)
 		.subcommand(
 			Command::new("serve")
 			.about("Serve zip files")
 			.arg(arg!(dir: [DIRECTORY] "Starting directory").default_value("."))
-			.arg(arg!(-d --depth <DEPTH> "How deep the zip files are indexeds (-1 as infinite)").default_value("-1"))
+			.arg(arg!(--config <PATH> "Read settings from a TOML file (command line flags take precedence)"))
+			.arg(arg!(-d --depth <DEPTH> "How deep the zip files are indexeds (-1 as infinite)").allow_negative_numbers(true).default_value("-1"))
 			.arg(arg!(-j --jobs <CORE_NUMBER> "How many thread to index the files").default_value("4"))
 			.arg(arg!(-l --listen <LISTEN_HOST> "Listen host address").default_value("0.0.0.0"))
-			.arg(arg!(-p --port <LISTEN_PORT> "Listen port").default_value("8192"))
-			.arg(arg!(ssl_cert: --"ssl-cert" <SSL_CERT> "SSL certificate for TLS (optional, required if --ssl-key is set)").requires("ssl_key"))
-			.arg(arg!(ssl_key: --"ssl-key" <SSL_KEY> "SSL key for TLS (optional, required if --ssl-cert is set)").requires("ssl_cert"))
+			.arg(arg!(-p --port <LISTEN_PORT> "Listen port, repeat to listen on several").default_value("8192").action(ArgAction::Append))
+			.arg(arg!(ssl_cert: --"ssl-cert" <SSL_CERT> "SSL certificate for TLS (optional, required if --ssl-key is set)"))
+			.arg(arg!(ssl_key: --"ssl-key" <SSL_KEY> "SSL key for TLS (optional, required if --ssl-cert is set)"))
 			.arg(arg!(landing: --"landing-page" <PAGE_PATH> "The path to the landing page when getting the root route."))
-			.arg(arg!(land_with_path: --"land-with-path" "Open landing page with full path").requires("landing"))
+			.arg(arg!(land_with_path: --"land-with-path" "Open landing page with full path"))
+			.arg(arg!(landing_redirect: --"landing-redirect" <TYPE> "Redirect to the landing page as temporary (307) or permanent (301)"))
+			.arg(arg!(index_cache: --"index-cache" <PATH> "Save the file index to this file and reuse it while the archives are unchanged"))
+			.arg(arg!(recurse_zips: --"recurse-zips" "Browse zip files stored inside archives as directories"))
+			.arg(arg!(find_duplicates: --"find-duplicates" "Report zip entries with identical content across archives"))
+			.arg(arg!(--remote <URL> "Also serve a zip file from this URL, read with HTTP range requests (repeatable)").action(ArgAction::Append))
+			.arg(arg!(--include <GLOB> "Only index archives whose path under the directory matches this pattern (repeatable)").action(ArgAction::Append))
+			.arg(arg!(--exclude <GLOB> "Skip archives whose path under the directory matches this pattern (repeatable)").action(ArgAction::Append))
+			.arg(arg!(--preload "Decompress archive entries into memory at startup, up to --cache-size"))
+			.arg(arg!(cache_size: --"cache-size" <BYTES> "Memory budget for --preload and --prefetch").default_value("268435456"))
+			.arg(arg!(--prefetch "Cache the CSS and JS next to an HTML entry from an archive when it is served"))
+			.arg(arg!(dry_run: --"dry-run" "Index the directory, print a summary and exit without serving"))
+			.arg(arg!(dump_index: --"dump-index" <FILE> "With --dry-run, write the file database as JSON"))
+			.arg(arg!(follow_symlinks: --"follow-symlinks" <BOOL> "Whether to index symlinked files and directories, which may lead outside the served directory").value_parser(["true", "false"]).default_value("false"))
+			.arg(arg!(read_buffer: --"read-buffer" <BYTES> "Buffer size for reading archives").default_value("8192"))
+			.arg(arg!(open_retries: --"open-retries" <COUNT> "How many times to retry opening an archive after a transient I/O error, before skipping it").default_value("3"))
+			.arg(arg!(index_open_files: --"index-open-files" <COUNT> "How many archives indexing opens at once, whatever --jobs is (default is --jobs)"))
+			.arg(arg!(index_timeout: --"index-timeout" <SECONDS> "Give up when indexing takes longer than this"))
+			.arg(arg!(partial_index: --"partial-index" "With --index-timeout, serve what was indexed so far instead of giving up"))
+			.arg(arg!(lazy_open: --"lazy-open" "Open zips when reading from them instead of keeping one file open per zip, for huge archive counts and network mounts"))
+			.arg(arg!(max_open_files: --"max-open-files" <COUNT> "How many zips --lazy-open keeps open between reads").default_value("64"))
+			.arg(arg!(--file <ZIP> "Serve the content of this zip alone instead of the directory, reindexing it whenever it changes"))
+			.arg(arg!(archive_list: --"archive-list" <PATH> "Serve the zips listed in this file, one PATH or PREFIX=PATH per line, instead of scanning the directory").conflicts_with("file"))
+			.arg(arg!(--flatten "Serve every entry of the archives under the directory by its base name alone, at the root").conflicts_with_all(["file", "archive_list"]))
+			.arg(arg!(--strict "Refuse to start when an archive has absolute or traversal entry names"))
+			.arg(arg!(max_entry_size: --"max-entry-size" <BYTES> "Refuse to decompress archive entries larger than this"))
+			.arg(arg!(verify_crc: --"verify-crc" "Check every entry read from a zip against its stored CRC32, answering 500 on a mismatch"))
+			.arg(arg!(max_request_bytes: --"max-request-bytes" <BYTES> "Stop a download, or refuse an entry, once a request decompresses more than this").default_value("17179869184"))
+			.arg(arg!(ignore_case: --"ignore-case" "Fall back to case-insensitive path lookups"))
+			.arg(arg!(render_markdown: --"render-markdown" "Serve Markdown files as HTML (add ?raw to get the original)"))
+			.arg(arg!(index_file: --"index-file" <NAME> "File served for a directory, tried in order (repeatable, default index.html)").action(ArgAction::Append))
+			.arg(arg!(no_auto_index: --"no-auto-index" "Refuse directories without an index file instead of listing them"))
+			.arg(arg!(dirs_first: --"dirs-first" "List subdirectories before files (?dirs_first=0 or =1 overrides it per request)"))
+			.arg(arg!(per_page: --"per-page" <N> "Split directory listings into pages of N entries (?page and ?per_page pick them per request)"))
+			.arg(arg!(collapse_dirs: --"collapse-dirs" "List chains of directories holding a single subdirectory as one a/b/c/ entry"))
+			.arg(arg!(hide_source_archives: --"hide-source-archives" "Serve only the content of archives, never list or download the archive files themselves"))
+			.arg(arg!(allow_ext: --"allow-ext" <EXT> "Only serve files with this extension, refusing others with 403 (repeatable)").action(ArgAction::Append))
+			.arg(arg!(hide_listing: --"hide-listing" <GLOB> "Refuse to list the directories whose path matches this pattern, still serving their files (repeatable)").action(ArgAction::Append))
+			.arg(arg!(--spa [INDEX] "Serve this page (index.html by default) for unknown routes without an extension").num_args(0..=1).default_missing_value("index.html"))
+			.arg(arg!(redirect_http: --"redirect-http" <PORT> "Also listen for plain HTTP on this port and redirect to HTTPS"))
+			.arg(arg!(--open "Open the default browser once the server is listening"))
+			.arg(arg!(--metrics "Serve Prometheus-style request counters at /metrics"))
+			.arg(arg!(--workers <N> "Worker threads handling requests (1-1024, Rocket's default is the CPU count)"))
+			.arg(arg!(request_timeout: --"request-timeout" <SECONDS> "Answer 408 when a response takes longer, and drop downloads the client stops reading"))
+			.arg(arg!(max_concurrent_reads: --"max-concurrent-reads" <N> "Answer 503 instead of decompressing another entry while this many are being read (default is unlimited)"))
+			.arg(arg!(keep_alive: --"keep-alive" <SECONDS> "Keep idle connections open this long (0-3600, 0 disables, default 5)"))
+			.arg(arg!(--nosniff "Send X-Content-Type-Options: nosniff (off by default so browsers can sniff untyped files)"))
+			.arg(arg!(--csp <POLICY> "Send this Content-Security-Policy with every response"))
+			.arg(arg!(api_path: --"api-path" <PATH> "Accept POST requests under this path (all POSTs get 404 otherwise)"))
+			.arg(arg!(secure_headers: --"secure-headers" "Turn on nosniff and a restrictive default CSP (--csp overrides the policy)"))
 		)
 		.get_matches();
 
+	init_logging(&matches);
+
 	match matches.subcommand() {
 		Some(("bench", arguments)) => { app::app_bench(arguments).await; },
 		Some(("split", arguments)) => { app::app_split(arguments).await; },
+		Some(("merge", arguments)) => { app::app_merge(arguments).await; },
+		Some(("verify", arguments)) => { app::app_verify(arguments).await; },
+		Some(("selftest", arguments)) => { app::app_selftest(arguments).await; },
 		Some(("serve", arguments)) => { app::app_serve(arguments).await; },
-		_ => { println!("[ERROR] Unrecognized command or subcommand. Run this program again with --help for more information."); }
+		_ => { error!("Unrecognized command or subcommand. Run this program again with --help for more information."); }
 	}
 
 	Ok(())
 }
+
+fn init_logging(matches: &ArgMatches) {
+	let arguments = matches.subcommand().map(|(_, x)| x).unwrap_or(matches);
+	let log_level = arguments.get_one::<String>("log_level").unwrap();
+	let mut level = match log_level.trim().parse::<LevelFilter>() {
+		Ok(level) => level,
+		Err(_) => {
+			eprintln!("[ERROR] Log level must be \"off\" or \"error\" or \"warn\" or \"info\" or \"debug\" or \"trace\"");
+			exit(1);
+		}
+	};
+	// The older split and merge flags still pick the level unless it is given explicitly
+	if let Some((name @ ("split" | "merge"), arguments)) = matches.subcommand() {
+		if arguments.value_source("log_level") != Some(ValueSource::CommandLine) {
+			if name == "split" && arguments.get_flag("verbose") { level = LevelFilter::DEBUG; }
+			else if arguments.get_flag("quiet") { level = LevelFilter::WARN; }
+		}
+	}
+	// Logs go to stderr so that they never mix with results printed to stdout
+	let subscriber = tracing_subscriber::fmt()
+		.with_max_level(level)
+		.with_writer(io::stderr)
+		.with_ansi(false)
+		.with_target(false)
+		.without_time();
+	match &arguments.get_one::<String>("log_format").unwrap()[..] {
+		"json" => subscriber.event_format(JsonFormat).init(),
+		_ => subscriber.init()
+	}
+}
@@ -0,0 +1,540 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+
+use anyhow::{anyhow, Result};
+use flate2::read::GzDecoder;
+use serde::Serialize;
+use tar::EntryType;
+use tracing::warn;
+use zip::ZipArchive;
+use zip::read::ZipFile;
+use zip::result::ZipError;
+
+use super::ArcPtr;
+use super::remote::HttpRangeReader;
+
+// What the indexing callbacks get to see of an entry, regardless of the archive format
+pub trait ArchiveEntry {
+	fn name(&self) -> &str;
+	fn size(&self) -> u64;
+	// Size as stored in the archive
+	fn compressed_size(&self) -> u64;
+	fn is_dir(&self) -> bool;
+	// Checksum of the content, when the format stores one
+	fn crc32(&self) -> Option<u32>;
+	// Unix timestamp in nanoseconds
+	fn last_modified(&self) -> Result<i128>;
+}
+
+// What an archive records about an entry, read on request rather than kept from indexing
+#[derive(Serialize)]
+pub struct EntryMeta {
+	pub name: String,
+	pub size: u64,
+	pub compressed_size: u64,
+	pub crc32: Option<u32>,
+	// "stored", "deflated" and so on, "none" for tar entries
+	pub compression: String,
+	// Unix timestamp in seconds
+	pub mtime: Option<i64>,
+	pub unix_mode: Option<u32>,
+	// Only zips have comments, as does the archive itself
+	pub comment: Option<String>,
+	pub archive_comment: Option<String>
+}
+
+// What an archive records about itself
+#[derive(Serialize)]
+pub struct ArchiveMeta {
+	pub entries: usize,
+	// Only zips have a comment
+	pub comment: Option<String>
+}
+
+impl<'a> ArchiveEntry for ZipFile<'a> {
+	fn name(&self) -> &str { ZipFile::name(self) }
+	fn size(&self) -> u64 { ZipFile::size(self) }
+	fn compressed_size(&self) -> u64 { ZipFile::compressed_size(self) }
+	fn is_dir(&self) -> bool { ZipFile::is_dir(self) }
+	fn crc32(&self) -> Option<u32> { Some(ZipFile::crc32(self)) }
+	fn last_modified(&self) -> Result<i128> {
+		Ok(ZipFile::last_modified(self).to_time()?.unix_timestamp_nanos())
+	}
+}
+
+#[derive(Clone)]
+pub struct TarEntry {
+	name: String,
+	size: u64,
+	is_dir: bool,
+	mtime: u64,
+	mode: u32,
+	// Position of the entry data in the (decompressed) tar stream
+	offset: u64
+}
+
+impl ArchiveEntry for TarEntry {
+	fn name(&self) -> &str { &self.name }
+	fn size(&self) -> u64 { self.size }
+	// Tar entries are not compressed individually
+	fn compressed_size(&self) -> u64 { self.size }
+	fn is_dir(&self) -> bool { self.is_dir }
+	fn crc32(&self) -> Option<u32> { None }
+	fn last_modified(&self) -> Result<i128> { Ok(self.mtime as i128 * 1_000_000_000) }
+}
+
+// A file on disk, so that loose files can go where archive entries are expected
+pub struct LooseFile {
+	name: String,
+	path: PathBuf,
+	size: u64,
+	mtime: i128
+}
+
+impl LooseFile {
+	pub fn new(name: String, path: PathBuf) -> Result<Self> {
+		let meta = fs::metadata(&path)?;
+		let mtime = meta.modified()?.duration_since(UNIX_EPOCH)?.as_nanos() as i128;
+		Ok(Self { name, path, size: meta.len(), mtime })
+	}
+
+	pub fn path(&self) -> &Path { &self.path }
+}
+
+impl ArchiveEntry for LooseFile {
+	fn name(&self) -> &str { &self.name }
+	fn size(&self) -> u64 { self.size }
+	fn compressed_size(&self) -> u64 { self.size }
+	fn is_dir(&self) -> bool { false }
+	fn crc32(&self) -> Option<u32> { None }
+	fn last_modified(&self) -> Result<i128> { Ok(self.mtime) }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ArchiveKind {
+	Zip,
+	Tar,
+	TarGz
+}
+
+impl ArchiveKind {
+	pub fn from_path(path: &Path) -> Option<Self> {
+		let name = path.file_name()?.to_str()?.to_lowercase();
+		if name.ends_with(".zip") { Some(Self::Zip) }
+		else if name.ends_with(".tar") { Some(Self::Tar) }
+		else if name.ends_with(".tar.gz") || name.ends_with(".tgz") { Some(Self::TarGz) }
+		else { None }
+	}
+}
+
+// BufReader's own default
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 8 * 1024;
+// Capacity of the buffers archives are read through, set from --read-buffer before anything is opened
+static READ_BUFFER_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_READ_BUFFER_SIZE);
+
+pub fn set_read_buffer_size(size: usize) {
+	READ_BUFFER_SIZE.store(size, Ordering::Relaxed);
+}
+
+// Compare the data of zip entries with their stored CRC32 after reading them, set from --verify-crc
+static VERIFY_CRC: AtomicBool = AtomicBool::new(false);
+
+pub fn set_verify_crc(verify: bool) {
+	VERIFY_CRC.store(verify, Ordering::Relaxed);
+}
+
+// Opens a file for reading through a buffer of the configured size
+pub fn open_buffered(path: impl AsRef<Path>) -> io::Result<BufReader<File>> {
+	Ok(BufReader::with_capacity(READ_BUFFER_SIZE.load(Ordering::Relaxed), File::open(path)?))
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+	std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+	std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+// Read + Seek over a file read at explicit offsets, through a buffer of the configured size. Clones
+// share the open file but keep their own position, so that several threads read one file at once.
+pub struct SharedFile {
+	file: Arc<File>,
+	pos: u64,
+	buf: Vec<u8>,
+	// Offset in the file of buf[0]
+	buf_start: u64
+}
+
+impl SharedFile {
+	pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+		Ok(Self { file: Arc::new(File::open(path)?), pos: 0, buf: Vec::new(), buf_start: 0 })
+	}
+}
+
+impl Clone for SharedFile {
+	// The buffered data is left behind, clones mostly read elsewhere in the file
+	fn clone(&self) -> Self {
+		Self { file: self.file.clone(), pos: self.pos, buf: Vec::new(), buf_start: 0 }
+	}
+}
+
+impl Read for SharedFile {
+	fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+		let capacity = READ_BUFFER_SIZE.load(Ordering::Relaxed);
+		if out.len() >= capacity {
+			// Like BufReader, reads at least as large as the buffer skip it
+			let read = read_at(&self.file, out, self.pos)?;
+			self.pos += read as u64;
+			return Ok(read);
+		}
+		if self.pos < self.buf_start || self.pos >= self.buf_start + self.buf.len() as u64 {
+			self.buf.resize(capacity, 0);
+			let read = read_at(&self.file, &mut self.buf, self.pos)?;
+			self.buf.truncate(read);
+			self.buf_start = self.pos;
+		}
+		let offset = (self.pos - self.buf_start) as usize;
+		let read = out.len().min(self.buf.len().saturating_sub(offset));
+		out[..read].copy_from_slice(&self.buf[offset..offset + read]);
+		self.pos += read as u64;
+		Ok(read)
+	}
+}
+
+impl Seek for SharedFile {
+	fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		let new_pos = match pos {
+			SeekFrom::Start(x) => Some(x),
+			SeekFrom::End(x) => self.file.metadata()?.len().checked_add_signed(x),
+			SeekFrom::Current(x) => self.pos.checked_add_signed(x)
+		};
+		match new_pos {
+			Some(new_pos) => {
+				self.pos = new_pos;
+				Ok(new_pos)
+			},
+			None => Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek to a negative position"))
+		}
+	}
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+// Starts the first part of a split zip set
+const SPLIT_ZIP_MAGIC: [u8; 4] = *b"PK\x07\x08";
+
+// Why a file that looks like a zip cannot be served, so that skipping it says more than the zip
+// error. Split sets are recognized by their .z01 part or by the signature starting the set, and only
+// their .zip is reported, the .z01 and later parts being plain files.
+pub fn unsupported_zip(path: &Path) -> Option<&'static str> {
+	let name = path.file_name()?.to_str()?.to_lowercase();
+	if name.ends_with(".zip.gz") { return Some("it is a gzip-compressed zip, decompress it with gunzip to serve its content"); }
+	if !name.ends_with(".zip") { return None; }
+	let mut magic = [0u8; 4];
+	let read = File::open(path).and_then(|mut x| x.read(&mut magic)).unwrap_or(0);
+	if read >= 2 && magic[..2] == GZIP_MAGIC {
+		return Some("it is gzip-compressed rather than a zip, decompress it with gunzip to serve its content");
+	}
+	if (read == 4 && magic == SPLIT_ZIP_MAGIC) || path.with_extension("z01").exists() {
+		return Some("it is the last part of a split zip set, which cannot be served. Join the parts with \"zip -s 0 <name>.zip --out <joined>.zip\"");
+	}
+	None
+}
+
+fn open_tar_stream(path: &str, kind: ArchiveKind) -> Result<Box<dyn Read>> {
+	let file = open_buffered(path)?;
+	Ok(match kind {
+		ArchiveKind::TarGz => Box::new(GzDecoder::new(file)),
+		_ => Box::new(file)
+	})
+}
+
+// Lists every entry of a tar archive. Only regular files and directories are kept, but the
+// position in the returned list is the entry index used everywhere else.
+pub fn scan_tar(path: &str, kind: ArchiveKind) -> Result<Vec<Option<TarEntry>>> {
+	let mut archive = tar::Archive::new(open_tar_stream(path, kind)?);
+	let mut entries = Vec::new();
+	for entry in archive.entries()? {
+		let entry = entry?;
+		let entry_type = entry.header().entry_type();
+		if entry_type != EntryType::Regular && entry_type != EntryType::Directory {
+			entries.push(None);
+			continue;
+		}
+		let name = entry.path()?.to_string_lossy().replace('\\', "/");
+		let name = name.trim_start_matches("./").trim_end_matches('/').to_string();
+		if name.is_empty() {
+			entries.push(None);
+			continue;
+		}
+		entries.push(Some(TarEntry {
+			name,
+			size: entry.size(),
+			is_dir: entry_type == EntryType::Directory,
+			mtime: entry.header().mtime().unwrap_or(0),
+			mode: entry.header().mode().unwrap_or(0),
+			offset: entry.raw_file_position()
+		}));
+	}
+	Ok(entries)
+}
+
+// Returned by read_entry when an entry is larger than the allowed limit
+#[derive(Debug)]
+pub struct EntryTooLarge(pub u64);
+
+impl fmt::Display for EntryTooLarge {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Entry exceeds the size limit of {} bytes", self.0)
+	}
+}
+
+impl std::error::Error for EntryTooLarge {}
+
+// Returned by read_entry when the data of a zip entry does not match its stored CRC32
+#[derive(Debug)]
+pub struct EntryCorrupt(pub String);
+
+impl fmt::Display for EntryCorrupt {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Entry {} does not match its CRC32", self.0)
+	}
+}
+
+impl std::error::Error for EntryCorrupt {}
+
+// Zip64 sizes go far beyond what should be allocated up front on the word of a header
+const MAX_PREALLOCATION: u64 = 64 * 1024 * 1024;
+
+// Reads at most `limit` bytes, failing instead of truncating when there is more
+fn read_limited(reader: &mut dyn Read, size_hint: u64, limit: Option<u64>) -> Result<Vec<u8>> {
+	let limit = limit.unwrap_or(u64::MAX);
+	// The declared size cannot be trusted for the allocation, but it is enough to reject early
+	if size_hint > limit { return Err(EntryTooLarge(limit).into()); }
+	let mut vec = Vec::<u8>::with_capacity(size_hint.min(MAX_PREALLOCATION) as usize);
+	reader.take(limit.saturating_add(1)).read_to_end(&mut vec)?;
+	if vec.len() as u64 > limit { return Err(EntryTooLarge(limit).into()); }
+	Ok(vec)
+}
+
+const OPEN_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// I/O errors other than a missing file or a denied access may go away, as on a flaky network mount
+fn is_transient(err: &anyhow::Error) -> bool {
+	let io_err = match err.downcast_ref::<ZipError>() {
+		Some(ZipError::Io(io_err)) => Some(io_err),
+		Some(_) => None,
+		None => err.downcast_ref::<io::Error>()
+	};
+	io_err.is_some_and(|x| !matches!(x.kind(), io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied))
+}
+
+// Calls open again up to `retries` times while it fails transiently, waiting twice as long each time
+pub fn retry_open<T>(path: &str, retries: usize, mut open: impl FnMut() -> Result<T>) -> Result<T> {
+	let mut delay = OPEN_RETRY_DELAY;
+	let mut attempt = 0;
+	loop {
+		match open() {
+			Err(err) if attempt < retries && is_transient(&err) => {
+				warn!("Cannot open {} ({}), retrying in {}ms", path, err, delay.as_millis());
+				thread::sleep(delay);
+				delay *= 2;
+				attempt += 1;
+			},
+			result => return result
+		}
+	}
+}
+
+// An opened archive which entries can be read from by index. A clone reads on its own without
+// opening or parsing the archive again, so reads take one each instead of sharing a handle.
+#[derive(Clone)]
+pub enum ArchiveHandle {
+	Zip(ZipArchive<SharedFile>),
+	// A zip that was itself stored inside another archive
+	NestedZip(ZipArchive<Cursor<Arc<[u8]>>>),
+	// A zip read over HTTP, entries are fetched on demand
+	Remote(ZipArchive<HttpRangeReader>),
+	Tar(String, ArchiveKind, Arc<Vec<Option<TarEntry>>>),
+	// A zip opened through the pool on every read instead of holding a file open
+	LazyZip(String, ArcPtr<OpenZips>)
+}
+
+// Zip files kept open for lazily opened handles, closing the least recently read past the limit.
+// Reopening one parses its central directory again. A zip is taken out of the pool while it is
+// read, and reads of a zip that is already taken open another.
+pub struct OpenZips {
+	limit: usize,
+	// Most recently read first
+	open: VecDeque<(String, ZipArchive<BufReader<File>>)>
+}
+
+impl OpenZips {
+	pub fn new(limit: usize) -> Self {
+		Self { limit: limit.max(1), open: VecDeque::new() }
+	}
+
+	fn with_zip<T>(open_zips: &ArcPtr<Self>, path: &str, f: impl FnOnce(&mut ZipArchive<BufReader<File>>) -> Result<T>) -> Result<T> {
+		let taken = {
+			let mut open_zips = open_zips.lock().unwrap();
+			open_zips.open.iter().position(|(x, _)| x == path).and_then(|i| open_zips.open.remove(i))
+		};
+		let (path, mut zip_handle) = match taken {
+			Some(taken) => taken,
+			None => (path.to_string(), ZipArchive::new(open_buffered(path)?)?)
+		};
+		let result = f(&mut zip_handle);
+		let mut open_zips = open_zips.lock().unwrap();
+		open_zips.open.push_front((path, zip_handle));
+		let limit = open_zips.limit;
+		open_zips.open.truncate(limit);
+		result
+	}
+}
+
+fn zip_archive_meta<R: Read + Seek>(zip_handle: &ZipArchive<R>) -> ArchiveMeta {
+	ArchiveMeta {
+		entries: zip_handle.len(),
+		comment: Some(String::from_utf8_lossy(zip_handle.comment()).into_owned())
+	}
+}
+
+fn zip_entry_meta<R: Read + Seek>(zip_handle: &mut ZipArchive<R>, index: usize) -> Result<EntryMeta> {
+	let archive_comment = String::from_utf8_lossy(zip_handle.comment()).into_owned();
+	// The raw entry is enough, nothing is decompressed
+	let zip_file = zip_handle.by_index_raw(index)?;
+	Ok(EntryMeta {
+		name: zip_file.name().to_string(),
+		size: zip_file.size(),
+		compressed_size: zip_file.compressed_size(),
+		crc32: Some(zip_file.crc32()),
+		compression: format!("{:?}", zip_file.compression()).to_lowercase(),
+		mtime: zip_file.last_modified().to_time().ok().map(|x| x.unix_timestamp()),
+		unix_mode: zip_file.unix_mode(),
+		comment: Some(zip_file.comment().to_string()),
+		archive_comment: Some(archive_comment)
+	})
+}
+
+fn read_zip_entry<R: Read + Seek>(zip_handle: &mut ZipArchive<R>, index: usize, limit: Option<u64>) -> Result<Vec<u8>> {
+	let mut zip_file = zip_handle.by_index(index)?;
+	let size = zip_file.size();
+	// The zip crate checks the CRC32 itself once a read reaches the end of the stream, so reading no
+	// further than the declared size leaves the check to VERIFY_CRC
+	let data = read_limited(&mut (&mut zip_file).take(size), size, limit)?;
+	if VERIFY_CRC.load(Ordering::Relaxed) && crc32fast::hash(&data) != zip_file.crc32() {
+		return Err(EntryCorrupt(zip_file.name().to_string()).into());
+	}
+	Ok(data)
+}
+
+impl ArchiveHandle {
+	pub fn open(path: &str) -> Result<Self> {
+		match ArchiveKind::from_path(Path::new(path)) {
+			Some(ArchiveKind::Zip) => Ok(Self::Zip(ZipArchive::new(SharedFile::open(path)?)?)),
+			Some(kind) => Ok(Self::Tar(path.to_string(), kind, Arc::new(scan_tar(path, kind)?))),
+			None => Err(anyhow!("Unsupported archive: {}", path))
+		}
+	}
+
+	// Zips only hold their path, tars never hold a file open anyway. The file is still opened once to check it.
+	pub fn open_lazy(path: &str, open_zips: &ArcPtr<OpenZips>) -> Result<Self> {
+		match Self::open(path)? {
+			Self::Zip(_) => Ok(Self::LazyZip(path.to_string(), open_zips.clone())),
+			handle => Ok(handle)
+		}
+	}
+
+	pub fn read_entry(&mut self, index: usize, limit: Option<u64>) -> Result<Vec<u8>> {
+		match self {
+			Self::Zip(zip_handle) => read_zip_entry(zip_handle, index, limit),
+			Self::NestedZip(zip_handle) => read_zip_entry(zip_handle, index, limit),
+			Self::Remote(zip_handle) => read_zip_entry(zip_handle, index, limit),
+			Self::LazyZip(path, open_zips) => OpenZips::with_zip(open_zips, path, |x| read_zip_entry(x, index, limit)),
+			Self::Tar(path, kind, entries) => {
+				let entry = entries.get(index).cloned().flatten().ok_or(anyhow!("No entry {} in {}", index, path))?;
+				let mut stream: Box<dyn Read> = if *kind == ArchiveKind::Tar {
+					let mut file = File::open(&path)?;
+					file.seek(SeekFrom::Start(entry.offset))?;
+					Box::new(file)
+				}
+				else {
+					// Compressed streams cannot seek, so the preceding data is decompressed and dropped
+					let mut stream = open_tar_stream(path, *kind)?;
+					io::copy(&mut stream.by_ref().take(entry.offset), &mut io::sink())?;
+					stream
+				};
+				read_limited(&mut stream.take(entry.size), entry.size, limit)
+			}
+		}
+	}
+
+	pub fn entry_meta(&mut self, index: usize) -> Result<EntryMeta> {
+		match self {
+			Self::Zip(zip_handle) => zip_entry_meta(zip_handle, index),
+			Self::NestedZip(zip_handle) => zip_entry_meta(zip_handle, index),
+			Self::Remote(zip_handle) => zip_entry_meta(zip_handle, index),
+			Self::LazyZip(path, open_zips) => OpenZips::with_zip(open_zips, path, |x| zip_entry_meta(x, index)),
+			Self::Tar(path, _, entries) => {
+				let entry = entries.get(index).cloned().flatten().ok_or(anyhow!("No entry {} in {}", index, path))?;
+				Ok(EntryMeta {
+					name: entry.name,
+					size: entry.size,
+					compressed_size: entry.size,
+					crc32: None,
+					compression: String::from("none"),
+					mtime: Some(entry.mtime as i64),
+					unix_mode: Some(entry.mode),
+					comment: None,
+					archive_comment: None
+				})
+			}
+		}
+	}
+
+	pub fn archive_meta(&mut self) -> Result<ArchiveMeta> {
+		match self {
+			Self::Zip(zip_handle) => Ok(zip_archive_meta(zip_handle)),
+			Self::NestedZip(zip_handle) => Ok(zip_archive_meta(zip_handle)),
+			Self::Remote(zip_handle) => Ok(zip_archive_meta(zip_handle)),
+			Self::LazyZip(path, open_zips) => OpenZips::with_zip(open_zips, path, |x| Ok(zip_archive_meta(x))),
+			Self::Tar(_, _, entries) => Ok(ArchiveMeta { entries: entries.iter().flatten().count(), comment: None })
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const CRC_BROKEN_ZIP: &[u8] = include_bytes!("../../tests/fixtures/crc_broken.zip");
+
+	#[test]
+	fn corrupt_entry_is_refused_only_when_verifying() {
+		let mut zip_handle = ZipArchive::new(Cursor::new(CRC_BROKEN_ZIP)).unwrap();
+		set_verify_crc(false);
+		assert_eq!(read_zip_entry(&mut zip_handle, 1, None).unwrap(), b"this Byte gets flipped\n");
+		set_verify_crc(true);
+		assert_eq!(read_zip_entry(&mut zip_handle, 0, None).unwrap(), b"intact data\n");
+		let err = read_zip_entry(&mut zip_handle, 1, None).unwrap_err();
+		assert!(err.is::<EntryCorrupt>());
+		set_verify_crc(false);
+	}
+
+	#[test]
+	fn archive_meta_counts_entries() {
+		let mut zip_handle = ArchiveHandle::NestedZip(ZipArchive::new(Cursor::new(Arc::from(CRC_BROKEN_ZIP))).unwrap());
+		let meta = zip_handle.archive_meta().unwrap();
+		assert_eq!(meta.entries, 2);
+		assert_eq!(meta.comment.as_deref(), Some(""));
+	}
+}
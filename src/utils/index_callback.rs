@@ -1,33 +1,54 @@
-use zip::read::ZipFile;
-use super::ArcPtr;
-use crate::arc_ptr_create;
-
-pub struct ZipCallback<T>
-	where T: FnMut(&ZipFile, usize, &str) + Send + 'static {
-		callback: Box<T>
-}
-
-#[allow(unused)]
-impl<T> ZipCallback<T>
-	where T: FnMut(&ZipFile, usize, &str) + Send + 'static {
-		pub fn new(callback: T) -> ArcPtr<Self> {
-			arc_ptr_create!(Self {
-				callback: Box::new(callback)
-			})
-		}
-
-		pub fn replace(&mut self, callback: T) {
-			self.callback = Box::new(callback);
-		}
-
-		pub fn exec(&mut self, zip_file: &ZipFile, index: usize, zip_file_path: &str) {
-			(self.callback)(zip_file, index, zip_file_path);
-		}
-}
-
-#[macro_export]
-macro_rules! noop_callback {
-    () => {
-        $crate::utils::index_callback::ZipCallback::new(|_| {})
-    };
-}
+use anyhow::Result;
+use super::ArcPtr;
+use super::archive::ArchiveEntry;
+use crate::arc_ptr_create;
+
+pub struct ZipCallback<T>
+	where T: FnMut(&dyn ArchiveEntry, usize, &str) -> Result<()> + Send + 'static {
+		callback: Box<T>,
+		// Receives (processed, total) as the indexer goes
+		progress: Option<Box<dyn FnMut(usize, usize) + Send>>
+}
+
+#[allow(unused)]
+impl<T> ZipCallback<T>
+	where T: FnMut(&dyn ArchiveEntry, usize, &str) -> Result<()> + Send + 'static {
+		pub fn new(callback: T) -> ArcPtr<Self> {
+			arc_ptr_create!(Self {
+				callback: Box::new(callback),
+				progress: None
+			})
+		}
+
+		pub fn with_progress(callback: T, progress: impl FnMut(usize, usize) + Send + 'static) -> ArcPtr<Self> {
+			arc_ptr_create!(Self {
+				callback: Box::new(callback),
+				progress: Some(Box::new(progress))
+			})
+		}
+
+		pub fn set_progress(&mut self, progress: impl FnMut(usize, usize) + Send + 'static) {
+			self.progress = Some(Box::new(progress));
+		}
+
+		pub fn replace(&mut self, callback: T) {
+			*self.callback = callback;
+		}
+
+		pub fn exec(&mut self, zip_file: &dyn ArchiveEntry, index: usize, zip_file_path: &str) -> Result<()> {
+			(self.callback)(zip_file, index, zip_file_path)
+		}
+
+		pub fn report_progress(&mut self, processed: usize, total: usize) {
+			if let Some(progress) = &mut self.progress {
+				progress(processed, total);
+			}
+		}
+}
+
+#[macro_export]
+macro_rules! noop_callback {
+    () => {
+        $crate::utils::index_callback::ZipCallback::new(|_, _, _| Ok(()))
+    };
+}
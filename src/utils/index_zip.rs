@@ -1,111 +1,411 @@
-use std::fs::{self, File};
-use std::path::Path;
-use std::io::BufReader;
-use std::time::Instant;
-
-use anyhow::Result;
-use zip::{ZipArchive, read::ZipFile};
-
-use super::index_callback::ZipCallback;
-use super::{ArcPinnedPtr, ArcPtr};
-use crate::arc_pinned_ptr_create;
-
-type BufZipReader = ZipArchive<BufReader<File>>;
-
-pub fn index_zip_single_thread<T: FnMut(&ZipFile, usize, &str) + Send + 'static>(file: &str, cb: ArcPtr<ZipCallback<T>>) -> Result<u128> {
-	let begin_time = Instant::now();
-	let mut zip_file = ZipArchive::new(BufReader::new(File::open(file)?))?;
-	for i in 0..zip_file.len() {
-		cb.lock().unwrap().exec(&zip_file.by_index(i)?, i, file);
-	}
-
-	Ok((Instant::now() - begin_time).as_millis())
-}
-
-pub async fn index_zip_multi_thread<T: FnMut(&ZipFile, usize, &str) + Send + 'static>(file: &str, worker: usize, cb: ArcPtr<ZipCallback<T>>) -> Result<u128> {
-	let begin_time = Instant::now();
-
-	let zip_file = arc_pinned_ptr_create!(ZipArchive::new(BufReader::new(File::open(file)?))?);
-
-	let mut file_len = zip_file.lock().unwrap().len();
-	let thread_count = if file_len > worker { worker } else { file_len };
-	let file_per_thread = file_len / thread_count;
-
-	let mut join_handles = Vec::new();
-
-	for i in 0..thread_count {
-		join_handles.push(tokio::spawn(index_zip_mt_child(zip_file.clone(), file.to_string(), i, file_per_thread, if i == thread_count - 1 { file_len } else { file_per_thread }, cb.clone())));
-		file_len -= file_per_thread;
-	}
-
-	for i in join_handles {
-		if let Err(err) = i.await {
-			println!("[ERROR] Error in thread: {}", err);
-		}
-	}
-
-	Ok((Instant::now() - begin_time).as_millis())
-}
-
-async fn index_zip_mt_child<T: FnMut(&ZipFile, usize, &str) + Send + 'static>(zip_file: ArcPinnedPtr<BufZipReader>, zip_file_path: String, base_index: usize, file_per_thread:usize, how_much: usize, cb: ArcPtr<ZipCallback<T>>) -> Result<()> {
-	for i in 0..how_much {
-		let mut zip_ref = zip_file.lock().unwrap();
-		let index = base_index * file_per_thread + i;
-		cb.lock().unwrap().exec(&zip_ref.by_index(index)?, index, &zip_file_path);
-	}
-	Ok(())
-}
-
-fn iter_dir(dir: &Path, depth: isize, cb: &mut dyn FnMut(&Path)) -> Result<()> {
-	if dir.is_dir() {
-		for i in fs::read_dir(dir)? {
-			let entry = i?;
-			let entry_path = entry.path();
-			if (depth > 0 || depth == -1) && entry_path.is_dir() {
-				iter_dir(&entry_path, if depth == -1 { depth } else { depth - 1 }, cb)?;
-			}
-			else {
-				cb(&entry_path);
-			}
-		}
-	}
-	Ok(())
-}
-
-pub async fn index_zip_dir<T: FnMut(&ZipFile, usize, &str) + Send + 'static>(dir: &str, worker: usize, depth: isize, cb: ArcPtr<ZipCallback<T>>) -> Result<u128> {
-	let mut zip_files: Vec<String> = Vec::new();
-	iter_dir(Path::new(dir), depth, &mut |x| {
-		if x.extension().and_then(|x| { x.to_str() }) == Some("zip") {
-			zip_files.push(String::from(x.to_str().unwrap()));
-		}
-	})?;
-
-	let zip_files = arc_pinned_ptr_create!(zip_files);
-	let begin_time = Instant::now();
-
-	let mut join_handles = Vec::new();
-
-	for _ in 0..worker {
-		join_handles.push(tokio::spawn(index_zip_dir_child(zip_files.clone(), cb.clone())));
-	}
-
-	for i in join_handles {
-		if let Ok(()) = i.await? {};
-	}
-
-	Ok((Instant::now() - begin_time).as_millis())
-}
-
-async fn index_zip_dir_child<T: FnMut(&ZipFile, usize, &str) + Send + 'static>(zip_files: ArcPinnedPtr<Vec<String>>, cb: ArcPtr<ZipCallback<T>>) -> Result<()> {
-	'master: loop {
-		let fname;
-		'a: {
-			if let Some(next_zip_file) = zip_files.lock().unwrap().pop() {
-				fname = next_zip_file.clone();
-			}
-			else { break 'master; }
-		}
-		index_zip_single_thread(&fname, cb.clone())?;
-	}
-	Ok(())
-}
\ No newline at end of file
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::io::BufReader;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::Result;
+use tokio::sync::Semaphore;
+use tracing::{debug, warn};
+use zip::{ZipArchive, result::ZipError};
+
+use super::archive::{open_buffered, retry_open, scan_tar, unsupported_zip, ArchiveEntry, ArchiveKind};
+use super::index_callback::ZipCallback;
+use super::{ArcPinnedPtr, ArcPtr};
+use crate::arc_pinned_ptr_create;
+
+type BufZipReader = ZipArchive<BufReader<File>>;
+
+pub fn index_zip_single_thread<T: FnMut(&dyn ArchiveEntry, usize, &str) -> Result<()> + Send + 'static>(file: &str, cb: ArcPtr<ZipCallback<T>>) -> Result<u128> {
+	let begin_time = Instant::now();
+	let mut zip_file = ZipArchive::new(open_buffered(file)?)?;
+	let total = zip_file.len();
+	for i in 0..total {
+		let entry = zip_file.by_index(i)?;
+		let mut cb = cb.lock().unwrap();
+		cb.exec(&entry, i, file)?;
+		cb.report_progress(i + 1, total);
+	}
+
+	Ok((Instant::now() - begin_time).as_millis())
+}
+
+pub async fn index_zip_multi_thread<T: FnMut(&dyn ArchiveEntry, usize, &str) -> Result<()> + Send + 'static>(file: &str, worker: usize, cb: ArcPtr<ZipCallback<T>>) -> Result<u128> {
+	let begin_time = Instant::now();
+
+	let file_clone = file.to_string();
+	let file_len = tokio::task::spawn_blocking(move || -> Result<usize> { Ok(ZipArchive::new(open_buffered(&file_clone)?)?.len()) }).await??;
+
+	let processed = arc_pinned_ptr_create!(0usize);
+	let mut join_handles = Vec::new();
+
+	// Reading entries blocks, so the children run on blocking threads rather than on the runtime
+	for range in partition_indices(file_len, worker) {
+		let (file, processed, cb) = (file.to_string(), processed.clone(), cb.clone());
+		join_handles.push(tokio::task::spawn_blocking(move || index_zip_mt_child(file, range, (processed, file_len), cb)));
+	}
+
+	for i in join_handles {
+		i.await??;
+	}
+
+	Ok((Instant::now() - begin_time).as_millis())
+}
+
+#[cfg(feature = "rayon")]
+pub fn index_zip_rayon<T: FnMut(&dyn ArchiveEntry, usize, &str) -> Result<()> + Send + 'static>(file: &str, worker: usize, cb: ArcPtr<ZipCallback<T>>) -> Result<u128> {
+	use rayon::prelude::*;
+
+	let begin_time = Instant::now();
+
+	let file_len = ZipArchive::new(open_buffered(file)?)?.len();
+	let processed = arc_pinned_ptr_create!(0usize);
+	let pool = rayon::ThreadPoolBuilder::new().num_threads(worker.max(1)).build()?;
+
+	pool.install(|| {
+		partition_indices(file_len, worker).into_par_iter().try_for_each(|range| -> Result<()> {
+			let mut zip_file: BufZipReader = ZipArchive::new(open_buffered(file)?)?;
+			for index in range {
+				let entry = zip_file.by_index(index)?;
+				let mut cb = cb.lock().unwrap();
+				cb.exec(&entry, index, file)?;
+				let mut processed = processed.lock().unwrap();
+				**processed += 1;
+				cb.report_progress(**processed, file_len);
+			}
+			Ok(())
+		})
+	})?;
+
+	Ok((Instant::now() - begin_time).as_millis())
+}
+
+// Splits 0..len into at most `worker` contiguous, non-empty ranges whose sizes differ by at most one
+pub fn partition_indices(len: usize, worker: usize) -> Vec<Range<usize>> {
+	let thread_count = worker.max(1).min(len);
+	let mut ranges = Vec::with_capacity(thread_count);
+	let mut start = 0;
+	for i in 0..thread_count {
+		let end = start + len / thread_count + if i < len % thread_count { 1 } else { 0 };
+		ranges.push(start..end);
+		start = end;
+	}
+	ranges
+}
+
+// Each child opens its own handle so that entries are read concurrently
+fn index_zip_mt_child<T: FnMut(&dyn ArchiveEntry, usize, &str) -> Result<()> + Send + 'static>(zip_file_path: String, range: Range<usize>, progress: (ArcPinnedPtr<usize>, usize), cb: ArcPtr<ZipCallback<T>>) -> Result<()> {
+	let (processed, total) = progress;
+	let mut zip_file: BufZipReader = ZipArchive::new(open_buffered(&zip_file_path)?)?;
+	for index in range {
+		let entry = zip_file.by_index(index)?;
+		let mut cb = cb.lock().unwrap();
+		cb.exec(&entry, index, &zip_file_path)?;
+		let mut processed = processed.lock().unwrap();
+		**processed += 1;
+		cb.report_progress(**processed, total);
+	}
+	Ok(())
+}
+
+// Hard cap on nesting, which also applies when the depth is unlimited
+pub const MAX_DIR_DEPTH: usize = 64;
+
+// Tracks the directories a recursive walk is currently inside, so that a symlink pointing back to
+// one of them is skipped instead of being followed forever
+pub struct DirWalk {
+	ancestors: Vec<PathBuf>,
+	// Without it, symlinked files and directories are left out of the walk
+	follow_symlinks: bool
+}
+
+impl DirWalk {
+	pub fn new(follow_symlinks: bool) -> Self {
+		Self { ancestors: vec![], follow_symlinks }
+	}
+
+	pub fn skips(&self, entry: &fs::DirEntry) -> bool {
+		if self.follow_symlinks || !entry.file_type().is_ok_and(|x| x.is_symlink()) { return false; }
+		debug!("Skipping symlink {}", entry.path().display());
+		true
+	}
+
+	// Returns false (after warning) if dir must not be entered. Every true is paired with a leave().
+	pub fn enter(&mut self, dir: &Path) -> bool {
+		let canonical = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+		if self.ancestors.len() >= MAX_DIR_DEPTH {
+			warn!("Skipping {}, which is nested more than {} levels deep", dir.display(), MAX_DIR_DEPTH);
+			return false;
+		}
+		if self.ancestors.contains(&canonical) {
+			warn!("Skipping {}, which links back to {}", dir.display(), canonical.display());
+			return false;
+		}
+		self.ancestors.push(canonical);
+		true
+	}
+	pub fn leave(&mut self) {
+		self.ancestors.pop();
+	}
+}
+
+// Calls cb on every file under dir. A depth of 0 only visits dir itself, 1 also visits its
+// direct subdirectories and so on; a negative depth is unlimited.
+pub fn iter_dir(dir: &Path, depth: isize, cb: &mut dyn FnMut(&Path)) -> Result<()> {
+	iter_dir_guarded(dir, depth, cb, &mut DirWalk::new(true))
+}
+
+fn iter_dir_guarded(dir: &Path, depth: isize, cb: &mut dyn FnMut(&Path), walk: &mut DirWalk) -> Result<()> {
+	if dir.is_dir() {
+		if !walk.enter(dir) { return Ok(()); }
+		for i in fs::read_dir(dir)? {
+			let entry = i?;
+			if walk.skips(&entry) { continue; }
+			let entry_path = entry.path();
+			if entry_path.is_dir() {
+				// Directories beyond the depth are skipped rather than passed to cb as files
+				if depth != 0 {
+					iter_dir_guarded(&entry_path, if depth < 0 { depth } else { depth - 1 }, cb, walk)?;
+				}
+			}
+			else {
+				cb(&entry_path);
+			}
+		}
+		walk.leave();
+	}
+	Ok(())
+}
+
+// Collects every supported archive (zip, tar, tar.gz) under the directory that filter accepts
+fn collect_zip_files(dir: &str, depth: isize, mut walk: DirWalk, filter: &dyn Fn(&Path) -> bool) -> Result<Vec<String>> {
+	let mut zip_files: Vec<String> = Vec::new();
+	iter_dir_guarded(Path::new(dir), depth, &mut |x| {
+		if ArchiveKind::from_path(x).is_some() && filter(x) {
+			match x.to_str() {
+				Some(x) => zip_files.push(String::from(x)),
+				None => warn!("Skipping archive {}, its path is not valid UTF-8", x.display())
+			}
+		}
+	}, &mut walk)?;
+	Ok(zip_files)
+}
+
+// Like index_zip_single_thread, but an archive that cannot be opened or read is reported and skipped
+// (returning false) instead of failing. Errors returned by the callback are still propagated.
+// Opening is retried up to open_retries times on transient errors, reads are not.
+fn index_zip_skip_unreadable<T: FnMut(&dyn ArchiveEntry, usize, &str) -> Result<()> + Send + 'static>(file: &str, open_retries: usize, cb: ArcPtr<ZipCallback<T>>) -> Result<bool> {
+	if let Some(kind @ (ArchiveKind::Tar | ArchiveKind::TarGz)) = ArchiveKind::from_path(Path::new(file)) {
+		// Tar archives are scanned before the callback is called, so a failure there is always a read error
+		let entries = match retry_open(file, open_retries, || scan_tar(file, kind)) {
+			Ok(entries) => entries,
+			Err(err) => {
+				warn!("Skipping unreadable archive {}: {}", file, err);
+				return Ok(false);
+			}
+		};
+		for (i, entry) in entries.iter().enumerate() {
+			if let Some(entry) = entry {
+				cb.lock().unwrap().exec(entry, i, file)?;
+			}
+		}
+		return Ok(true);
+	}
+
+	if let Some(reason) = unsupported_zip(Path::new(file)) {
+		warn!("Skipping archive {}: {}", file, reason);
+		return Ok(false);
+	}
+	let open = || Ok(open_buffered(file).map_err(ZipError::from).and_then(ZipArchive::new)?);
+	let mut zip_file: BufZipReader = match retry_open(file, open_retries, open) {
+		Ok(zip_file) => zip_file,
+		Err(err) => {
+			warn!("Skipping unreadable archive {}: {}", file, err);
+			return Ok(false);
+		}
+	};
+	for i in 0..zip_file.len() {
+		let entry = match zip_file.by_index(i) {
+			Ok(entry) => entry,
+			Err(err) => {
+				warn!("Skipping corrupt archive {} at entry {}: {}", file, i, err);
+				return Ok(false);
+			}
+		};
+		cb.lock().unwrap().exec(&entry, i, file)?;
+	}
+	Ok(true)
+}
+
+pub fn index_zip_dir_single_thread<T: FnMut(&dyn ArchiveEntry, usize, &str) -> Result<()> + Send + 'static>(dir: &str, depth: isize, cb: ArcPtr<ZipCallback<T>>) -> Result<u128> {
+	let zip_files = collect_zip_files(dir, depth, DirWalk::new(true), &|_| true)?;
+	let begin_time = Instant::now();
+
+	let mut skipped = 0usize;
+	for (i, zip_file) in zip_files.iter().enumerate() {
+		if !index_zip_skip_unreadable(zip_file, 0, cb.clone())? { skipped += 1; }
+		cb.lock().unwrap().report_progress(i + 1, zip_files.len());
+	}
+	if skipped > 0 { warn!("Skipped {} unreadable archive(s).", skipped); }
+
+	Ok((Instant::now() - begin_time).as_millis())
+}
+
+pub async fn index_zip_dir<T: FnMut(&dyn ArchiveEntry, usize, &str) -> Result<()> + Send + 'static>(dir: &str, worker: usize, depth: isize, cb: ArcPtr<ZipCallback<T>>) -> Result<u128> {
+	index_zip_dir_filtered(dir, worker, depth, DirWalk::new(true), &|_| true, OpenLimits { retries: 0, max_open: worker }, cb).await
+}
+
+// How index_zip_dir_filtered opens the archives
+#[derive(Clone, Copy)]
+pub struct OpenLimits {
+	// Retries of an open failing with a transient error
+	pub retries: usize,
+	// Archives being indexed at once across all workers, each holding its file open meanwhile
+	pub max_open: usize
+}
+
+// Like index_zip_dir, but archives rejected by filter are neither opened nor indexed. Opening one
+// is retried on transient errors, and workers beyond open.max_open wait for an archive to close.
+pub async fn index_zip_dir_filtered<T: FnMut(&dyn ArchiveEntry, usize, &str) -> Result<()> + Send + 'static>(dir: &str, worker: usize, depth: isize, walk: DirWalk, filter: &(dyn Fn(&Path) -> bool + Sync), open: OpenLimits, cb: ArcPtr<ZipCallback<T>>) -> Result<u128> {
+	let zip_files = collect_zip_files(dir, depth, walk, filter)?;
+	let open_permits = Arc::new(Semaphore::new(open.max_open.max(1)));
+
+	let total = zip_files.len();
+	let zip_files = arc_pinned_ptr_create!(zip_files);
+	let skipped = arc_pinned_ptr_create!(0usize);
+	let processed = arc_pinned_ptr_create!(0usize);
+	let begin_time = Instant::now();
+
+	let mut join_handles = Vec::new();
+
+	for _ in 0..worker {
+		join_handles.push(tokio::spawn(index_zip_dir_child(zip_files.clone(), skipped.clone(), (processed.clone(), total), (open.retries, open_permits.clone()), cb.clone())));
+	}
+
+	for i in join_handles {
+		i.await??;
+	}
+
+	let skipped = **skipped.lock().unwrap();
+	if skipped > 0 { warn!("Skipped {} unreadable archive(s).", skipped); }
+
+	Ok((Instant::now() - begin_time).as_millis())
+}
+
+async fn index_zip_dir_child<T: FnMut(&dyn ArchiveEntry, usize, &str) -> Result<()> + Send + 'static>(zip_files: ArcPinnedPtr<Vec<String>>, skipped: ArcPinnedPtr<usize>, progress: (ArcPinnedPtr<usize>, usize), open: (usize, Arc<Semaphore>), cb: ArcPtr<ZipCallback<T>>) -> Result<()> {
+	let (processed, total) = progress;
+	let (open_retries, open_permits) = open;
+	'master: loop {
+		let fname;
+		'a: {
+			if let Some(next_zip_file) = zip_files.lock().unwrap().pop() {
+				fname = next_zip_file.clone();
+			}
+			else { break 'master; }
+		}
+		let permit = open_permits.acquire().await?;
+		// Opening parses the whole central directory and retries sleep, so neither runs on the runtime
+		let cb_clone = cb.clone();
+		if !tokio::task::spawn_blocking(move || index_zip_skip_unreadable(&fname, open_retries, cb_clone)).await?? {
+			**skipped.lock().unwrap() += 1;
+		}
+		drop(permit);
+		let mut cb = cb.lock().unwrap();
+		let mut processed = processed.lock().unwrap();
+		**processed += 1;
+		cb.report_progress(**processed, total);
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Write;
+
+	use zip::{ZipWriter, write::FileOptions};
+
+	use super::*;
+	use crate::arc_ptr_create;
+	use crate::utils::index_callback::ZipCallback;
+
+	#[test]
+	fn partitions_cover_every_index_once() {
+		for (len, worker) in [(7, 4), (13, 4), (31, 8), (3, 8), (1, 4), (0, 4), (10, 1), (16, 4)] {
+			let ranges = partition_indices(len, worker);
+			assert!(ranges.len() <= worker.max(1));
+			assert!(ranges.iter().all(|x| !x.is_empty()));
+			let indices: Vec<usize> = ranges.iter().cloned().flatten().collect();
+			assert_eq!(indices, (0..len).collect::<Vec<_>>(), "len {} with {} workers", len, worker);
+			let sizes: Vec<usize> = ranges.iter().map(|x| x.len()).collect();
+			if let (Some(min), Some(max)) = (sizes.iter().min(), sizes.iter().max()) {
+				assert!(max - min <= 1);
+			}
+		}
+	}
+
+	// A prime number of entries cannot be split evenly across the workers
+	#[tokio::test(flavor = "multi_thread")]
+	async fn multi_thread_indexes_every_entry_once() {
+		const ENTRIES: usize = 13;
+		let path = std::env::temp_dir().join(format!("zip_handler-mt-index-{}.zip", std::process::id()));
+		let mut zip_writer = ZipWriter::new(File::create(&path).unwrap());
+		for i in 0..ENTRIES {
+			zip_writer.start_file(format!("{}.txt", i), FileOptions::default()).unwrap();
+			zip_writer.write_all(i.to_string().as_bytes()).unwrap();
+		}
+		zip_writer.finish().unwrap();
+
+		for worker in [1, 2, 4, 5, 13, 16] {
+			let seen = arc_ptr_create!(vec![]);
+			let seen_clone = seen.clone();
+			let cb = ZipCallback::new(move |entry: &dyn ArchiveEntry, index: usize, _: &str| {
+				seen_clone.lock().unwrap().push((index, entry.name().to_string()));
+				Ok(())
+			});
+			index_zip_multi_thread(path.to_str().unwrap(), worker, cb).await.unwrap();
+			let mut seen = std::mem::take(&mut *seen.lock().unwrap());
+			assert_eq!(seen.len(), ENTRIES, "{} workers", worker);
+			seen.sort();
+			assert_eq!(seen, (0..ENTRIES).map(|x| (x, format!("{}.txt", x))).collect::<Vec<_>>(), "{} workers", worker);
+		}
+		let _ = fs::remove_file(&path);
+	}
+
+	// root/a, root/d1/b, root/d1/d2/c, root/d1/d2/d3/d
+	struct TreeDir(PathBuf);
+
+	impl TreeDir {
+		fn create(name: &str) -> Self {
+			let root = std::env::temp_dir().join(format!("zip_handler-{}-{}", name, std::process::id()));
+			let _ = fs::remove_dir_all(&root);
+			fs::create_dir_all(root.join("d1/d2/d3")).unwrap();
+			for file in ["a", "d1/b", "d1/d2/c", "d1/d2/d3/d"] {
+				fs::write(root.join(file), file).unwrap();
+			}
+			Self(root)
+		}
+
+		fn files(&self, depth: isize) -> Vec<String> {
+			let mut files = vec![];
+			iter_dir(&self.0, depth, &mut |x| files.push(x.strip_prefix(&self.0).unwrap().to_string_lossy().replace('\\', "/"))).unwrap();
+			files.sort();
+			files
+		}
+	}
+
+	impl Drop for TreeDir {
+		fn drop(&mut self) {
+			let _ = fs::remove_dir_all(&self.0);
+		}
+	}
+
+	#[test]
+	fn iter_dir_stops_at_depth() {
+		let tree = TreeDir::create("iter-dir-depth");
+		assert_eq!(tree.files(-1), ["a", "d1/b", "d1/d2/c", "d1/d2/d3/d"]);
+		assert_eq!(tree.files(0), ["a"]);
+		assert_eq!(tree.files(1), ["a", "d1/b"]);
+		assert_eq!(tree.files(2), ["a", "d1/b", "d1/d2/c"]);
+	}
+}
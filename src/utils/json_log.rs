@@ -0,0 +1,50 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{Map, Value};
+use tracing::{Event, Subscriber};
+use tracing::field::{Field, Visit};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::registry::LookupSpan;
+
+// One JSON object per line: the time in seconds since the epoch, the level, the message and every
+// other field of the event under its own name
+pub struct JsonFormat;
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+	fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+		self.0.insert(field.name().to_string(), Value::from(format!("{:?}", value)));
+	}
+	fn record_str(&mut self, field: &Field, value: &str) {
+		self.0.insert(field.name().to_string(), Value::from(value));
+	}
+	fn record_i64(&mut self, field: &Field, value: i64) {
+		self.0.insert(field.name().to_string(), Value::from(value));
+	}
+	fn record_u64(&mut self, field: &Field, value: u64) {
+		self.0.insert(field.name().to_string(), Value::from(value));
+	}
+	fn record_f64(&mut self, field: &Field, value: f64) {
+		self.0.insert(field.name().to_string(), Value::from(value));
+	}
+	fn record_bool(&mut self, field: &Field, value: bool) {
+		self.0.insert(field.name().to_string(), Value::from(value));
+	}
+}
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+	S: Subscriber + for<'a> LookupSpan<'a>,
+	N: for<'a> FormatFields<'a> + 'static {
+	fn format_event(&self, _: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+		let mut object = Map::new();
+		let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+		object.insert(String::from("time"), Value::from(time.as_millis() as f64 / 1000.0));
+		object.insert(String::from("level"), Value::from(event.metadata().level().as_str()));
+		event.record(&mut JsonVisitor(&mut object));
+		writeln!(writer, "{}", Value::Object(object))
+	}
+}
@@ -0,0 +1,79 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Instant;
+
+const PROGRESS_BAR_WIDTH: usize = 40;
+
+// Redraws the bar in place, only when it visibly changes, and ends the line once done
+pub fn print_progress_bar(processed: usize, total: usize) {
+	let filled = |x: usize| (x.min(total) * PROGRESS_BAR_WIDTH).checked_div(total).unwrap_or(PROGRESS_BAR_WIDTH);
+	if processed > 1 && processed < total && filled(processed) == filled(processed - 1) {
+		return;
+	}
+	print!("\r[{}{}] {}/{}", "#".repeat(filled(processed)), "-".repeat(PROGRESS_BAR_WIDTH - filled(processed)), processed, total);
+	if processed >= total { println!(); }
+	let _ = io::stdout().flush();
+}
+
+// 1536 -> "1.5 KiB", as file browsers show it
+pub fn human_size(size: u64) -> String {
+	const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+	if size < 1024 { return format!("{} B", size); }
+	let mut value = size as f64 / 1024.0;
+	let mut unit = 0;
+	while value >= 1024.0 && unit < UNITS.len() - 1 {
+		value /= 1024.0;
+		unit += 1;
+	}
+	format!("{:.1} {}", value, UNITS[unit])
+}
+
+// Progress of a job spread over several workers, drawn as an overall bar followed by one line per worker
+pub struct WorkerProgress {
+	total: usize,
+	// (items, bytes) done by each worker
+	workers: Vec<(AtomicUsize, AtomicU64)>,
+	begin: Instant,
+	drawn: AtomicBool,
+	finished: AtomicBool
+}
+
+impl WorkerProgress {
+	pub fn new(total: usize, workers: usize) -> Self {
+		Self {
+			total,
+			workers: (0..workers).map(|_| (AtomicUsize::new(0), AtomicU64::new(0))).collect(),
+			begin: Instant::now(),
+			drawn: AtomicBool::new(false),
+			finished: AtomicBool::new(false)
+		}
+	}
+
+	pub fn add(&self, worker: usize, bytes: u64) {
+		let (items, size) = &self.workers[worker];
+		items.fetch_add(1, Ordering::Relaxed);
+		size.fetch_add(bytes, Ordering::Relaxed);
+	}
+
+	pub fn finish(&self) { self.finished.store(true, Ordering::Relaxed); }
+	pub fn is_finished(&self) -> bool { self.finished.load(Ordering::Relaxed) }
+
+	// Redraws all lines in place
+	pub fn draw(&self) {
+		let mut out = String::new();
+		if self.drawn.swap(true, Ordering::Relaxed) {
+			out += &format!("\x1b[{}A", self.workers.len() + 1);
+		}
+		let processed: usize = self.workers.iter().map(|x| x.0.load(Ordering::Relaxed)).sum::<usize>().min(self.total);
+		let filled = (processed * PROGRESS_BAR_WIDTH).checked_div(self.total).unwrap_or(PROGRESS_BAR_WIDTH);
+		let percent = (processed * 100).checked_div(self.total).unwrap_or(100);
+		out += &format!("\r\x1b[2K[{}{}] {}/{} ({}%)\n", "#".repeat(filled), "-".repeat(PROGRESS_BAR_WIDTH - filled), processed, self.total, percent);
+		let seconds = self.begin.elapsed().as_secs_f64().max(0.001);
+		for (i, (items, size)) in self.workers.iter().enumerate() {
+			let size = size.load(Ordering::Relaxed);
+			out += &format!("\x1b[2K  Thread {}: {} files, {} ({}/s)\n", i, items.load(Ordering::Relaxed), human_size(size), human_size((size as f64 / seconds) as u64));
+		}
+		print!("{}", out);
+		let _ = io::stdout().flush();
+	}
+}
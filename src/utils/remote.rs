@@ -0,0 +1,126 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read, Seek, SeekFrom};
+
+use anyhow::{anyhow, Result};
+use ureq::{Agent, AgentBuilder};
+
+// Size of one range request, small enough that reading a local header does not pull much data
+const BLOCK_SIZE: u64 = 64 * 1024;
+// Up to 16 MiB of fetched blocks are kept around
+const MAX_CACHED_BLOCKS: usize = 256;
+
+// Read + Seek over a file served by HTTP. Data is fetched with range requests as it is read, and
+// recently used blocks are cached so that the central directory and headers are not fetched again.
+pub struct HttpRangeReader {
+	agent: Agent,
+	url: String,
+	len: u64,
+	pos: u64,
+	// block index -> block data
+	blocks: BTreeMap<u64, Vec<u8>>,
+	// Block indices from the least to the most recently fetched
+	recent: VecDeque<u64>
+}
+
+impl HttpRangeReader {
+	pub fn open(url: &str) -> Result<Self> {
+		let agent = AgentBuilder::new().build();
+		let response = agent.head(url).call()?;
+		let len = response.header("Content-Length")
+			.and_then(|x| x.trim().parse::<u64>().ok())
+			.ok_or(anyhow!("{} did not report its size", url))?;
+		Ok(Self {
+			agent,
+			url: url.to_string(),
+			len,
+			pos: 0,
+			blocks: BTreeMap::new(),
+			recent: VecDeque::new()
+		})
+	}
+
+	// Fetches start..end, which must lie within the file
+	fn fetch(&self, start: u64, end: u64) -> io::Result<Vec<u8>> {
+		let response = self.agent.get(&self.url)
+			.set("Range", &format!("bytes={}-{}", start, end - 1))
+			.call()
+			.map_err(io::Error::other)?;
+		if response.status() != 206 {
+			return Err(io::Error::other(format!("{} does not support range requests", self.url)));
+		}
+		let mut data = Vec::with_capacity((end - start) as usize);
+		response.into_reader().take(end - start).read_to_end(&mut data)?;
+		if (data.len() as u64) < end - start {
+			return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Short read from {}", self.url)));
+		}
+		Ok(data)
+	}
+
+	fn block(&mut self, index: u64) -> io::Result<&[u8]> {
+		if !self.blocks.contains_key(&index) {
+			let start = index * BLOCK_SIZE;
+			let data = self.fetch(start, (start + BLOCK_SIZE).min(self.len))?;
+			if self.recent.len() >= MAX_CACHED_BLOCKS {
+				if let Some(oldest) = self.recent.pop_front() {
+					self.blocks.remove(&oldest);
+				}
+			}
+			self.recent.push_back(index);
+			self.blocks.insert(index, data);
+		}
+		Ok(&self.blocks[&index])
+	}
+}
+
+impl Clone for HttpRangeReader {
+	// Starts with no cached blocks, the agent and its connections are shared
+	fn clone(&self) -> Self {
+		Self {
+			agent: self.agent.clone(),
+			url: self.url.clone(),
+			len: self.len,
+			pos: self.pos,
+			blocks: BTreeMap::new(),
+			recent: VecDeque::new()
+		}
+	}
+}
+
+impl Read for HttpRangeReader {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.pos >= self.len || buf.is_empty() { return Ok(0); }
+		let read;
+		if buf.len() as u64 >= BLOCK_SIZE {
+			// Large reads are entry data, which is fetched in one go and not worth caching
+			let end = (self.pos + buf.len() as u64).min(self.len);
+			let data = self.fetch(self.pos, end)?;
+			buf[..data.len()].copy_from_slice(&data);
+			read = data.len();
+		}
+		else {
+			let offset = (self.pos % BLOCK_SIZE) as usize;
+			let block = self.block(self.pos / BLOCK_SIZE)?;
+			read = buf.len().min(block.len() - offset);
+			buf[..read].copy_from_slice(&block[offset..offset + read]);
+		}
+		self.pos += read as u64;
+		Ok(read)
+	}
+}
+
+impl Seek for HttpRangeReader {
+	fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		let new_pos = match pos {
+			SeekFrom::Start(x) => Some(x),
+			SeekFrom::End(x) => self.len.checked_add_signed(x),
+			SeekFrom::Current(x) => self.pos.checked_add_signed(x)
+		};
+		match new_pos {
+			Some(new_pos) => {
+				self.pos = new_pos;
+				Ok(new_pos)
+			},
+			None => Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek to a negative position"))
+		}
+	}
+}
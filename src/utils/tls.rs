@@ -0,0 +1,55 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use anyhow::{anyhow, bail, Result};
+use rustls::{PrivateKey, SignatureScheme};
+use rustls_pemfile::Item;
+
+// Signature schemes tried when checking a key against its certificate, with their webpki counterparts
+const SCHEMES: [(SignatureScheme, &webpki::SignatureAlgorithm); 4] = [
+	(SignatureScheme::ECDSA_NISTP256_SHA256, &webpki::ECDSA_P256_SHA256),
+	(SignatureScheme::ECDSA_NISTP384_SHA384, &webpki::ECDSA_P384_SHA384),
+	(SignatureScheme::ED25519, &webpki::ED25519),
+	(SignatureScheme::RSA_PKCS1_SHA256, &webpki::RSA_PKCS1_2048_8192_SHA256)
+];
+
+fn open_pem(kind: &str, path: &str) -> Result<BufReader<File>> {
+	Ok(BufReader::new(File::open(path).map_err(|err| anyhow!("Cannot open SSL {} {}: {}", kind, path, err))?))
+}
+
+// Checks that the certificate and key files can be read and parsed, and that the key belongs to
+// the first certificate, so that a mistake is reported before Rocket fails to start
+pub fn check_tls_files(cert_path: &str, key_path: &str) -> Result<()> {
+	let certs = rustls_pemfile::certs(&mut open_pem("certificate", cert_path)?)
+		.map_err(|err| anyhow!("Invalid SSL certificate {}: {}", cert_path, err))?;
+	let Some(cert) = certs.first() else {
+		bail!("Invalid SSL certificate {}: no PEM certificate found", cert_path);
+	};
+	let cert = webpki::EndEntityCert::try_from(&cert[..])
+		.map_err(|err| anyhow!("Invalid SSL certificate {}: {:?}", cert_path, err))?;
+
+	let mut key_reader = open_pem("key", key_path)?;
+	let key = loop {
+		match rustls_pemfile::read_one(&mut key_reader).map_err(|err| anyhow!("Invalid SSL key {}: {}", key_path, err))? {
+			Some(Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key)) => break key,
+			Some(_) => continue,
+			None => bail!("Invalid SSL key {}: no PEM private key found", key_path)
+		}
+	};
+	let key = rustls::sign::any_supported_type(&PrivateKey(key))
+		.map_err(|_| anyhow!("Invalid SSL key {}: unsupported key type", key_path))?;
+
+	// Signs a message with the key and checks the signature with the certificate's public key
+	let message = b"zip_handler key check";
+	let offered: Vec<SignatureScheme> = SCHEMES.iter().map(|x| x.0).collect();
+	let signer = key.choose_scheme(&offered)
+		.ok_or_else(|| anyhow!("Invalid SSL key {}: unsupported signature scheme", key_path))?;
+	let Some((_, algorithm)) = SCHEMES.iter().find(|x| x.0 == signer.scheme()) else {
+		bail!("Invalid SSL key {}: unsupported signature scheme", key_path);
+	};
+	let signature = signer.sign(message).map_err(|err| anyhow!("Invalid SSL key {}: {}", key_path, err))?;
+	if cert.verify_signature(algorithm, message, &signature).is_err() {
+		bail!("SSL key {} does not match certificate {}", key_path, cert_path);
+	}
+	Ok(())
+}
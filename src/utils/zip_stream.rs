@@ -0,0 +1,257 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+
+use flate2::Compression;
+use flate2::write::DeflateEncoder;
+
+// Version 4.5 is the first one with Zip64
+const ZIP_VERSION: u16 = 45;
+// Sizes follow the data in a descriptor (bit 3), names are UTF-8 (bit 11)
+const ZIP_FLAGS: u16 = 0x0808;
+// Bit 0 marks traditional PKWARE (ZipCrypto) encryption
+const ZIP_FLAG_ENCRYPTED: u16 = 0x0001;
+const ZIP_DEFLATED: u16 = 8;
+// 1980-01-01 00:00, the earliest DOS date
+const ZIP_DOS_DATE: u16 = 0x0021;
+const ZIP_DOS_TIME: u16 = 0;
+
+struct CentralEntry {
+	name: String,
+	crc: u32,
+	compressed_size: u64,
+	size: u64,
+	offset: u64
+}
+
+// Counts what goes through, as the output cannot be seeked to find out offsets
+struct CountingWriter<W: Write> {
+	inner: W,
+	count: u64
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let written = self.inner.write(buf)?;
+		self.count += written as u64;
+		Ok(written)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+// The CRC-32 table, which the ZipCrypto key schedule steps through one byte at a time
+const CRC_TABLE: [u32; 256] = {
+	let mut table = [0u32; 256];
+	let mut i = 0;
+	while i < 256 {
+		let mut crc = i as u32;
+		let mut bit = 0;
+		while bit < 8 {
+			crc = if crc & 1 != 0 { 0xEDB88320 ^ (crc >> 1) } else { crc >> 1 };
+			bit += 1;
+		}
+		table[i] = crc;
+		i += 1;
+	}
+	table
+};
+
+// Traditional PKWARE encryption. It is weak and only kept out of casual reach, but every zip tool can open it.
+#[derive(Clone)]
+struct ZipCryptoKeys([u32; 3]);
+
+impl ZipCryptoKeys {
+	fn new(password: &[u8]) -> Self {
+		let mut keys = Self([0x12345678, 0x23456789, 0x34567890]);
+		for byte in password { keys.update(*byte); }
+		keys
+	}
+
+	fn crc_step(crc: u32, byte: u8) -> u32 {
+		CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+	}
+
+	fn update(&mut self, byte: u8) {
+		self.0[0] = Self::crc_step(self.0[0], byte);
+		self.0[1] = self.0[1].wrapping_add(self.0[0] & 0xff).wrapping_mul(134775813).wrapping_add(1);
+		self.0[2] = Self::crc_step(self.0[2], (self.0[1] >> 24) as u8);
+	}
+
+	fn encrypt(&mut self, byte: u8) -> u8 {
+		let temp = (self.0[2] | 2) as u16;
+		let cipher = byte ^ (temp.wrapping_mul(temp ^ 1) >> 8) as u8;
+		self.update(byte);
+		cipher
+	}
+}
+
+// Where compressed entry data goes, encrypting it first when a password is set
+struct EntryWriter<'a, W: Write> {
+	inner: &'a mut CountingWriter<W>,
+	keys: Option<ZipCryptoKeys>
+}
+
+impl<W: Write> Write for EntryWriter<'_, W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match &mut self.keys {
+			Some(keys) => {
+				// The keys move on with every byte, so all of it has to be written
+				let cipher: Vec<u8> = buf.iter().map(|x| keys.encrypt(*x)).collect();
+				self.inner.write_all(&cipher)?;
+				Ok(buf.len())
+			},
+			None => self.inner.write(buf)
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+// Writes a zip archive to a non-seekable output. Sizes and checksums are only known once an entry
+// is written, so they go to a data descriptor after it, and every entry carries Zip64 fields.
+pub struct StreamZipWriter<W: Write> {
+	writer: CountingWriter<W>,
+	entries: Vec<CentralEntry>,
+	password: Option<Vec<u8>>
+}
+
+impl<W: Write> StreamZipWriter<W> {
+	pub fn new(writer: W) -> Self {
+		Self { writer: CountingWriter { inner: writer, count: 0 }, entries: vec![], password: None }
+	}
+
+	// Encrypts every entry with ZipCrypto using this password
+	pub fn with_password(writer: W, password: &[u8]) -> Self {
+		Self { password: Some(password.to_vec()), ..Self::new(writer) }
+	}
+
+	fn flags(&self) -> u16 {
+		if self.password.is_some() { ZIP_FLAGS | ZIP_FLAG_ENCRYPTED } else { ZIP_FLAGS }
+	}
+
+	pub fn add(&mut self, name: &str, reader: &mut dyn Read) -> io::Result<()> {
+		let offset = self.writer.count;
+		let flags = self.flags();
+		let w = &mut self.writer;
+		w.write_all(&0x04034b50u32.to_le_bytes())?;
+		w.write_all(&ZIP_VERSION.to_le_bytes())?;
+		w.write_all(&flags.to_le_bytes())?;
+		w.write_all(&ZIP_DEFLATED.to_le_bytes())?;
+		w.write_all(&ZIP_DOS_TIME.to_le_bytes())?;
+		w.write_all(&ZIP_DOS_DATE.to_le_bytes())?;
+		// CRC and sizes are in the descriptor
+		w.write_all(&[0; 12])?;
+		w.write_all(&(name.len() as u16).to_le_bytes())?;
+		w.write_all(&20u16.to_le_bytes())?;
+		w.write_all(name.as_bytes())?;
+		// Zip64 extra field, so that the descriptor holds 64-bit sizes
+		w.write_all(&1u16.to_le_bytes())?;
+		w.write_all(&16u16.to_le_bytes())?;
+		w.write_all(&[0; 16])?;
+
+		let data_start = w.count;
+		let mut hasher = crc32fast::Hasher::new();
+		let mut size = 0u64;
+		let mut entry_writer = EntryWriter { inner: &mut *w, keys: self.password.as_deref().map(ZipCryptoKeys::new) };
+		if entry_writer.keys.is_some() {
+			// 11 random bytes and a check byte, which is the high byte of the time as sizes are in a descriptor
+			let random = RandomState::new().build_hasher().finish().to_le_bytes();
+			let random2 = RandomState::new().build_hasher().finish().to_le_bytes();
+			let mut header = [0u8; 12];
+			header[..8].copy_from_slice(&random);
+			header[8..11].copy_from_slice(&random2[..3]);
+			header[11] = (ZIP_DOS_TIME >> 8) as u8;
+			entry_writer.write_all(&header)?;
+		}
+		let mut encoder = DeflateEncoder::new(entry_writer, Compression::default());
+		let mut buffer = vec![0u8; 64 * 1024];
+		loop {
+			let read = reader.read(&mut buffer)?;
+			if read == 0 { break; }
+			hasher.update(&buffer[..read]);
+			encoder.write_all(&buffer[..read])?;
+			size += read as u64;
+		}
+		encoder.finish()?;
+		let crc = hasher.finalize();
+		let compressed_size = w.count - data_start;
+
+		w.write_all(&0x08074b50u32.to_le_bytes())?;
+		w.write_all(&crc.to_le_bytes())?;
+		w.write_all(&compressed_size.to_le_bytes())?;
+		w.write_all(&size.to_le_bytes())?;
+
+		self.entries.push(CentralEntry { name: name.to_string(), crc, compressed_size, size, offset });
+		Ok(())
+	}
+
+	// Writes the central directory and returns the output
+	pub fn finish(mut self) -> io::Result<W> {
+		let cd_start = self.writer.count;
+		let flags = self.flags();
+		let w = &mut self.writer;
+		for entry in &self.entries {
+			let needs_zip64 = [entry.size, entry.compressed_size, entry.offset].iter().any(|x| *x >= u32::MAX as u64);
+			let clamp = |x: u64| if needs_zip64 { u32::MAX } else { x as u32 };
+			w.write_all(&0x02014b50u32.to_le_bytes())?;
+			w.write_all(&ZIP_VERSION.to_le_bytes())?;
+			w.write_all(&ZIP_VERSION.to_le_bytes())?;
+			w.write_all(&flags.to_le_bytes())?;
+			w.write_all(&ZIP_DEFLATED.to_le_bytes())?;
+			w.write_all(&ZIP_DOS_TIME.to_le_bytes())?;
+			w.write_all(&ZIP_DOS_DATE.to_le_bytes())?;
+			w.write_all(&entry.crc.to_le_bytes())?;
+			w.write_all(&clamp(entry.compressed_size).to_le_bytes())?;
+			w.write_all(&clamp(entry.size).to_le_bytes())?;
+			w.write_all(&(entry.name.len() as u16).to_le_bytes())?;
+			w.write_all(&(if needs_zip64 { 28u16 } else { 0 }).to_le_bytes())?;
+			// Comment length, disk number, internal and external attributes
+			w.write_all(&[0; 10])?;
+			w.write_all(&clamp(entry.offset).to_le_bytes())?;
+			w.write_all(entry.name.as_bytes())?;
+			if needs_zip64 {
+				w.write_all(&1u16.to_le_bytes())?;
+				w.write_all(&24u16.to_le_bytes())?;
+				w.write_all(&entry.size.to_le_bytes())?;
+				w.write_all(&entry.compressed_size.to_le_bytes())?;
+				w.write_all(&entry.offset.to_le_bytes())?;
+			}
+		}
+		let cd_end = w.count;
+		let cd_size = cd_end - cd_start;
+		let count = self.entries.len() as u64;
+
+		if count >= u16::MAX as u64 || cd_size >= u32::MAX as u64 || cd_start >= u32::MAX as u64 {
+			w.write_all(&0x06064b50u32.to_le_bytes())?;
+			w.write_all(&44u64.to_le_bytes())?;
+			w.write_all(&ZIP_VERSION.to_le_bytes())?;
+			w.write_all(&ZIP_VERSION.to_le_bytes())?;
+			w.write_all(&[0; 8])?;
+			w.write_all(&count.to_le_bytes())?;
+			w.write_all(&count.to_le_bytes())?;
+			w.write_all(&cd_size.to_le_bytes())?;
+			w.write_all(&cd_start.to_le_bytes())?;
+
+			w.write_all(&0x07064b50u32.to_le_bytes())?;
+			w.write_all(&0u32.to_le_bytes())?;
+			w.write_all(&cd_end.to_le_bytes())?;
+			w.write_all(&1u32.to_le_bytes())?;
+		}
+
+		w.write_all(&0x06054b50u32.to_le_bytes())?;
+		w.write_all(&[0; 4])?;
+		w.write_all(&(count.min(u16::MAX as u64) as u16).to_le_bytes())?;
+		w.write_all(&(count.min(u16::MAX as u64) as u16).to_le_bytes())?;
+		w.write_all(&(cd_size.min(u32::MAX as u64) as u32).to_le_bytes())?;
+		w.write_all(&(cd_start.min(u32::MAX as u64) as u32).to_le_bytes())?;
+		w.write_all(&0u16.to_le_bytes())?;
+		w.flush()?;
+
+		Ok(self.writer.inner)
+	}
+}
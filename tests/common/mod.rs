@@ -0,0 +1,98 @@
+// Runs the binary itself against a fixture directory
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::Read;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Killed on drop, so that a failing assertion does not leave the server running
+pub struct Server {
+	child: Child,
+	pub base: String
+}
+
+impl Server {
+	// Serves dir on a free port with these extra arguments, once it answers
+	pub fn start(dir: &str, args: &[&str]) -> Self {
+		let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+		let child = Command::new(env!("CARGO_BIN_EXE_zip_handler"))
+			.args(["serve", dir, "-l", "127.0.0.1", "-p", &port.to_string()])
+			.args(args)
+			.stdout(Stdio::null())
+			.stderr(Stdio::piped())
+			.spawn()
+			.unwrap();
+		let server = Self { child, base: format!("http://127.0.0.1:{}", port) };
+		let begin_time = Instant::now();
+		while ureq::get(&server.base).call().is_err() {
+			assert!(begin_time.elapsed() < STARTUP_TIMEOUT, "The server did not start");
+			thread::sleep(Duration::from_millis(100));
+		}
+		server
+	}
+
+	// (status, body) of GET path, whatever the status
+	pub fn get(&self, path: &str) -> (u16, Vec<u8>) {
+		let response = match ureq::get(&format!("{}{}", self.base, path)).call() {
+			Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+			Err(err) => panic!("GET {} failed: {}", path, err)
+		};
+		let status = response.status();
+		let mut body = vec![];
+		response.into_reader().read_to_end(&mut body).unwrap();
+		(status, body)
+	}
+
+	// Stops the server and returns what it logged
+	pub fn stop(mut self) -> String {
+		let _ = self.child.kill();
+		let _ = self.child.wait();
+		let mut log = String::new();
+		self.child.stderr.take().unwrap().read_to_string(&mut log).unwrap();
+		log
+	}
+}
+
+impl Drop for Server {
+	fn drop(&mut self) {
+		let _ = self.child.kill();
+		let _ = self.child.wait();
+	}
+}
+
+// Runs a command of the binary to completion, failing on an error
+pub fn run(args: &[&str]) {
+	let output = Command::new(env!("CARGO_BIN_EXE_zip_handler")).args(args).stdin(Stdio::null()).output().unwrap();
+	assert!(output.status.success(), "{:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+}
+
+// A directory under the system temporary directory, removed on drop
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+	pub fn new(name: &str) -> Self {
+		let path = std::env::temp_dir().join(format!("zip_handler-{}-{}", name, std::process::id()));
+		let _ = fs::remove_dir_all(&path);
+		fs::create_dir_all(&path).unwrap();
+		Self(path)
+	}
+
+	pub fn path(&self) -> &Path { &self.0 }
+
+	// The path of name inside the directory, as a string for command arguments
+	pub fn join(&self, name: &str) -> String {
+		self.0.join(name).to_str().unwrap().to_string()
+	}
+}
+
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		let _ = fs::remove_dir_all(&self.0);
+	}
+}
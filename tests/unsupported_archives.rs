@@ -0,0 +1,24 @@
+// Serves tests/fixtures/archives: a plain zip next to a gzip-wrapped zip, a gzip file named .zip
+// and a split .z01/.zip set, none of which may stop the server from starting
+mod common;
+
+use std::fs;
+
+use common::Server;
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/archives");
+
+#[test]
+fn unsupported_archives_are_skipped_and_served_as_files() {
+	let server = Server::start(FIXTURES, &[]);
+
+	assert_eq!(server.get("/docs/hello.txt"), (200, b"hello from a plain zip\n".to_vec()));
+	for name in ["wrapped.zip.gz", "gzipped.zip", "split.z01", "split.zip"] {
+		assert_eq!(server.get(&format!("/{}", name)), (200, fs::read(format!("{}/{}", FIXTURES, name)).unwrap()), "{}", name);
+	}
+
+	let log = server.stop();
+	assert!(log.contains("Not browsing wrapped.zip.gz: it is a gzip-compressed zip"), "{}", log);
+	assert!(log.contains("gzipped.zip: it is gzip-compressed rather than a zip"), "{}", log);
+	assert!(log.contains("split.zip: it is the last part of a split zip set"), "{}", log);
+}
@@ -0,0 +1,70 @@
+// Small entries written with Zip64 headers, as split does for entries close to 4 GiB, must be served,
+// split and merged like any other
+mod common;
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+
+use zip::{ZipArchive, ZipWriter};
+use zip::write::FileOptions;
+
+use common::{run, Server, TempDir};
+
+const ENTRIES: [(&str, &[u8]); 3] = [
+	("a.txt", b"first entry\n"),
+	("dir/b.txt", b"second entry\n"),
+	("dir/c.txt", b"third entry\n")
+];
+
+// Every entry carries a Zip64 extra field however small it is
+fn write_zip64(path: &str) {
+	let mut zip_writer = ZipWriter::new(File::create(path).unwrap());
+	for (name, data) in ENTRIES {
+		zip_writer.start_file(name, FileOptions::default().large_file(true)).unwrap();
+		zip_writer.write_all(data).unwrap();
+	}
+	zip_writer.finish().unwrap();
+	// Header ID 0x0001 with 16 bytes of sizes
+	assert!(fs::read(path).unwrap().windows(4).any(|x| x == [0x01, 0x00, 0x10, 0x00]));
+}
+
+fn read_entries(path: &str) -> Vec<(String, Vec<u8>)> {
+	let mut zip_archive = ZipArchive::new(File::open(path).unwrap()).unwrap();
+	let mut entries = vec![];
+	for i in 0..zip_archive.len() {
+		let mut entry = zip_archive.by_index(i).unwrap();
+		let mut data = vec![];
+		entry.read_to_end(&mut data).unwrap();
+		entries.push((entry.name().to_string(), data));
+	}
+	entries.sort();
+	entries
+}
+
+#[test]
+fn zip64_entries_are_served() {
+	let dir = TempDir::new("zip64-serve");
+	write_zip64(&dir.join("zip64.zip"));
+	let server = Server::start(dir.path().to_str().unwrap(), &[]);
+	for (name, data) in ENTRIES {
+		assert_eq!(server.get(&format!("/{}", name)), (200, data.to_vec()), "{}", name);
+	}
+}
+
+#[test]
+fn zip64_entries_survive_split_and_merge() {
+	let dir = TempDir::new("zip64-split");
+	let (input, parts, merged) = (dir.join("zip64.zip"), dir.join("parts"), dir.join("merged.zip"));
+	write_zip64(&input);
+	run(&["split", "-i", &input, "-o", &parts, "-j", "2", "-q", "-f"]);
+	run(&["merge", "-i", &parts, "-o", &merged, "-q", "-f"]);
+	let expected: Vec<(String, Vec<u8>)> = ENTRIES.iter().map(|(name, data)| (name.to_string(), data.to_vec())).collect();
+	let mut split: Vec<(String, Vec<u8>)> = fs::read_dir(&parts).unwrap()
+		.map(|x| x.unwrap().path())
+		.filter(|x| x.extension().is_some_and(|x| x == "zip"))
+		.flat_map(|x| read_entries(x.to_str().unwrap()))
+		.collect();
+	split.sort();
+	assert_eq!(split, expected);
+	assert_eq!(read_entries(&merged), expected);
+}
@@ -0,0 +1,26 @@
+// Serves tests/fixtures/bomb, whose bomb.zip holds small.txt and zeros.bin: 8 MiB of zeros
+// deflated to a few KiB, far above what --max-request-bytes allows a request to decompress
+mod common;
+
+use common::Server;
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/bomb");
+const MAX_REQUEST_BYTES: usize = 1024 * 1024;
+
+#[test]
+fn high_ratio_entries_are_refused() {
+	let server = Server::start(FIXTURES, &["--max-request-bytes", &MAX_REQUEST_BYTES.to_string()]);
+
+	assert_eq!(server.get("/small.txt"), (200, b"small enough\n".to_vec()));
+	assert_eq!(server.get("/zeros.bin").0, 413);
+
+	// Stopped at zeros.bin, after small.txt went out but before the end of the archive
+	let (status, body) = server.get("/?archive=zip");
+	assert_eq!(status, 200);
+	assert!(body.len() < MAX_REQUEST_BYTES, "{} bytes sent", body.len());
+	assert!(body.windows(9).any(|x| x == b"small.txt"));
+	assert!(!body.windows(9).any(|x| x == b"zeros.bin"));
+
+	let log = server.stop();
+	assert!(log.contains("stopped: More than 1048576 bytes decompressed by the time of zeros.bin"), "{}", log);
+}